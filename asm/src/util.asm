@@ -12,6 +12,7 @@
     global _bp_handler
     global _gp_handler
     global _pf_handler
+    global _df_handler
     global _do_execute
     global _load_context
     global _sysenter_landing
@@ -27,7 +28,9 @@
     global _bp_early_handler
     global _gp_early_handler
     global _pf_early_handler
-    global _reserve_slab
+    global _exception_handlers
+    global _early_exception_handlers
+    global _interrupt_handlers
 	global _entry_stack_end
 	global _entry_stack
 
@@ -37,21 +40,21 @@
     extern interrupt_breakpoint
     extern interrupt_general_protection_fault
     extern interrupt_page_fault
+    extern interrupt_double_fault
     extern early_interrupt_breakpoint
     extern early_interrupt_general_protection_fault
     extern early_interrupt_page_fault
+    extern interrupt_exception
+    extern early_interrupt_exception
+    extern interrupt_dispatch
     extern sysenter_handler
     extern SYSCALL_STACK
 
     section .bss nobits
     align 16
-_fxsave_int:    resb 0x200
-    align 16
 _fxsave_trap:   resb 0x200
     align 16
 _fxsave_task:   resb 0x200
-    align 8
-_reserve_slab:  resb 0x8000     ;must match constants.rs
 
 	;; early stack used for entry
 _entry_stack_end:
@@ -80,7 +83,7 @@ _error:
     
 ;;; Interrupt handler macro
 
-%macro interrupt_handler 1
+%macro interrupt_handler 1-2 0
     push 0x0                    ;push null error to ensure consistent stack frame
 .with_error:
     ;; push general-purpose registers
@@ -100,27 +103,31 @@ _error:
     push rbx
     push rax
 
-    ;; fxsave
-    fxsave [_fxsave_int]
-
     ;; first argument is the position of the stack, which contains all the context
     ;; needed to unwind
     mov rdi, rsp
+    ;; second argument is the vector, for handlers shared between several
+    mov rsi, %2
     ;; copy stack pointer to rbp, so it's saved after the interrupt handler
     mov rbp, rsp
 
-    ;; align stack
+    ;; make room for the fpu state and align stack
+    ;; the state has to live on this stack, since handlers can nest
+    sub rsp, 0x200
     and rsp, -16
 
+    ;; fxsave
+    fxsave [rsp]
+
     ;; interrupt handler
     call %1
 
+    ;; fxrstor
+    fxrstor [rsp]
+
     ;; de-align stack
     mov rsp, rbp
 
-    ;; fxrstor
-    fxrstor [_fxsave_int]
-
     ;; restore old registers
     pop rax
     pop rbx
@@ -159,7 +166,11 @@ _gp_handler:
 
 _pf_handler:
     jmp .with_error             ;has an error code
-    interrupt_handler early_interrupt_page_fault
+    interrupt_handler interrupt_page_fault
+
+_df_handler:
+    jmp .with_error             ;has an error code
+    interrupt_handler interrupt_double_fault
 
 _bp_early_handler:
     interrupt_handler early_interrupt_breakpoint
@@ -171,3 +182,171 @@ _gp_early_handler:
 _pf_early_handler:
     jmp .with_error             ;has an error code
     interrupt_handler early_interrupt_page_fault
+
+;;; Exception macro, the last argument says whether the CPU pushes an error code
+
+%macro exception_handler 3
+_%1_%2:
+%if %3
+    jmp .with_error             ;has an error code
+%endif
+    interrupt_handler %1, %2
+%endmacro
+
+;;; Every exception, so none of them triple faults for lack of a handler
+
+    exception_handler interrupt_exception, 0, 0
+    exception_handler interrupt_exception, 1, 0
+    exception_handler interrupt_exception, 2, 0
+    exception_handler interrupt_exception, 3, 0
+    exception_handler interrupt_exception, 4, 0
+    exception_handler interrupt_exception, 5, 0
+    exception_handler interrupt_exception, 6, 0
+    exception_handler interrupt_exception, 7, 0
+    exception_handler interrupt_exception, 8, 1
+    exception_handler interrupt_exception, 9, 0
+    exception_handler interrupt_exception, 10, 1
+    exception_handler interrupt_exception, 11, 1
+    exception_handler interrupt_exception, 12, 1
+    exception_handler interrupt_exception, 13, 1
+    exception_handler interrupt_exception, 14, 1
+    exception_handler interrupt_exception, 15, 0
+    exception_handler interrupt_exception, 16, 0
+    exception_handler interrupt_exception, 17, 1
+    exception_handler interrupt_exception, 18, 0
+    exception_handler interrupt_exception, 19, 0
+    exception_handler interrupt_exception, 20, 0
+    exception_handler interrupt_exception, 21, 1
+    exception_handler interrupt_exception, 22, 0
+    exception_handler interrupt_exception, 23, 0
+    exception_handler interrupt_exception, 24, 0
+    exception_handler interrupt_exception, 25, 0
+    exception_handler interrupt_exception, 26, 0
+    exception_handler interrupt_exception, 27, 0
+    exception_handler interrupt_exception, 28, 0
+    exception_handler interrupt_exception, 29, 1
+    exception_handler interrupt_exception, 30, 1
+    exception_handler interrupt_exception, 31, 0
+
+    exception_handler early_interrupt_exception, 0, 0
+    exception_handler early_interrupt_exception, 1, 0
+    exception_handler early_interrupt_exception, 2, 0
+    exception_handler early_interrupt_exception, 3, 0
+    exception_handler early_interrupt_exception, 4, 0
+    exception_handler early_interrupt_exception, 5, 0
+    exception_handler early_interrupt_exception, 6, 0
+    exception_handler early_interrupt_exception, 7, 0
+    exception_handler early_interrupt_exception, 8, 1
+    exception_handler early_interrupt_exception, 9, 0
+    exception_handler early_interrupt_exception, 10, 1
+    exception_handler early_interrupt_exception, 11, 1
+    exception_handler early_interrupt_exception, 12, 1
+    exception_handler early_interrupt_exception, 13, 1
+    exception_handler early_interrupt_exception, 14, 1
+    exception_handler early_interrupt_exception, 15, 0
+    exception_handler early_interrupt_exception, 16, 0
+    exception_handler early_interrupt_exception, 17, 1
+    exception_handler early_interrupt_exception, 18, 0
+    exception_handler early_interrupt_exception, 19, 0
+    exception_handler early_interrupt_exception, 20, 0
+    exception_handler early_interrupt_exception, 21, 1
+    exception_handler early_interrupt_exception, 22, 0
+    exception_handler early_interrupt_exception, 23, 0
+    exception_handler early_interrupt_exception, 24, 0
+    exception_handler early_interrupt_exception, 25, 0
+    exception_handler early_interrupt_exception, 26, 0
+    exception_handler early_interrupt_exception, 27, 0
+    exception_handler early_interrupt_exception, 28, 0
+    exception_handler early_interrupt_exception, 29, 1
+    exception_handler early_interrupt_exception, 30, 1
+    exception_handler early_interrupt_exception, 31, 0
+
+;;; Everything above the exceptions goes to the dispatcher, which calls whatever
+;;; handlers were registered for the vector
+
+%assign vector 32
+%rep 224
+_interrupt_ %+ vector:
+    interrupt_handler interrupt_dispatch, vector
+%assign vector vector + 1
+%endrep
+
+    section .rodata
+
+    ;; stubs indexed by vector
+    align 8
+_exception_handlers:
+    dq _interrupt_exception_0
+    dq _interrupt_exception_1
+    dq _interrupt_exception_2
+    dq _interrupt_exception_3
+    dq _interrupt_exception_4
+    dq _interrupt_exception_5
+    dq _interrupt_exception_6
+    dq _interrupt_exception_7
+    dq _interrupt_exception_8
+    dq _interrupt_exception_9
+    dq _interrupt_exception_10
+    dq _interrupt_exception_11
+    dq _interrupt_exception_12
+    dq _interrupt_exception_13
+    dq _interrupt_exception_14
+    dq _interrupt_exception_15
+    dq _interrupt_exception_16
+    dq _interrupt_exception_17
+    dq _interrupt_exception_18
+    dq _interrupt_exception_19
+    dq _interrupt_exception_20
+    dq _interrupt_exception_21
+    dq _interrupt_exception_22
+    dq _interrupt_exception_23
+    dq _interrupt_exception_24
+    dq _interrupt_exception_25
+    dq _interrupt_exception_26
+    dq _interrupt_exception_27
+    dq _interrupt_exception_28
+    dq _interrupt_exception_29
+    dq _interrupt_exception_30
+    dq _interrupt_exception_31
+
+_early_exception_handlers:
+    dq _early_interrupt_exception_0
+    dq _early_interrupt_exception_1
+    dq _early_interrupt_exception_2
+    dq _early_interrupt_exception_3
+    dq _early_interrupt_exception_4
+    dq _early_interrupt_exception_5
+    dq _early_interrupt_exception_6
+    dq _early_interrupt_exception_7
+    dq _early_interrupt_exception_8
+    dq _early_interrupt_exception_9
+    dq _early_interrupt_exception_10
+    dq _early_interrupt_exception_11
+    dq _early_interrupt_exception_12
+    dq _early_interrupt_exception_13
+    dq _early_interrupt_exception_14
+    dq _early_interrupt_exception_15
+    dq _early_interrupt_exception_16
+    dq _early_interrupt_exception_17
+    dq _early_interrupt_exception_18
+    dq _early_interrupt_exception_19
+    dq _early_interrupt_exception_20
+    dq _early_interrupt_exception_21
+    dq _early_interrupt_exception_22
+    dq _early_interrupt_exception_23
+    dq _early_interrupt_exception_24
+    dq _early_interrupt_exception_25
+    dq _early_interrupt_exception_26
+    dq _early_interrupt_exception_27
+    dq _early_interrupt_exception_28
+    dq _early_interrupt_exception_29
+    dq _early_interrupt_exception_30
+    dq _early_interrupt_exception_31
+
+    ;; dispatcher stubs, starting at vector 32
+_interrupt_handlers:
+%assign vector 32
+%rep 224
+    dq _interrupt_ %+ vector
+%assign vector vector + 1
+%endrep
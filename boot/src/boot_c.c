@@ -19,6 +19,19 @@ struct module {
   const char *cmdline;
 };
 
+struct rsdp {
+  char signature[8];
+  uint8_t checksum;
+  char oem_id[6];
+  uint8_t revision;
+  uint32_t rsdt_address;
+  // only present from revision 2
+  uint32_t length;
+  uint64_t xsdt_address;
+  uint8_t extended_checksum;
+  uint8_t reserved[3];
+} __attribute__((packed));
+
 struct memory_region {
   uint64_t start;
   uint64_t len;
@@ -34,6 +47,8 @@ struct boot_info {
   size_t modules_capacity;
   size_t modules_size;
   struct module *modules;
+  uint64_t acpi_root;
+  uint32_t acpi_extended;
 };
 
 extern void *__rust_allocate(size_t size, size_t align)
@@ -127,6 +142,16 @@ static int32_t parse_module(const struct multiboot_tag_module *tag, struct boot_
   return 0;
 }
 
+static void parse_rsdp(const struct rsdp *rsdp, struct boot_info *kernel_info) {
+  if (rsdp->revision >= 2 && rsdp->xsdt_address != 0) {
+    kernel_info->acpi_root = rsdp->xsdt_address;
+    kernel_info->acpi_extended = 1;
+  } else if (!kernel_info->acpi_extended) {
+    // an XSDT from the other tag wins, whichever comes first
+    kernel_info->acpi_root = rsdp->rsdt_address;
+  }
+}
+
 int32_t parse_multiboot_info(const struct multiboot_tag_fixed *info, struct boot_info *kernel_info) {
   const struct multiboot_header_tag *tag = info->tags;
 
@@ -157,6 +182,16 @@ int32_t parse_multiboot_info(const struct multiboot_tag_fixed *info, struct boot
         return -1;
       }
 
+      break;
+    case MULTIBOOT_TAG_TYPE_ACPI_OLD:
+      // ACPI 1.0 root pointer
+      parse_rsdp((struct rsdp *)((struct multiboot_tag_old_acpi *)tag)->rsdp, kernel_info);
+
+      break;
+    case MULTIBOOT_TAG_TYPE_ACPI_NEW:
+      // ACPI 2.0 root pointer
+      parse_rsdp((struct rsdp *)((struct multiboot_tag_new_acpi *)tag)->rsdp, kernel_info);
+
       break;
     default:
       // do nothing
@@ -36,7 +36,9 @@ mod c {
         pub memory_map: *const memory_region,
         pub modules_capacity: usize,
         pub modules_size: usize,
-        pub modules: *const module
+        pub modules: *const module,
+        pub acpi_root: u64,
+        pub acpi_extended: u32
     }
 
     #[repr(C)]
@@ -60,6 +62,10 @@ mod c {
         // marker variable for the end of our image
         static _boot_end: u8;
 
+        // markers around our code, which is the only part that runs
+        static _boot_text: u8;
+        static _boot_text_end: u8;
+
         fn parse_multiboot_info(info: *const c_void, kernel_info: *mut boot_info_inner) -> i32;
     }
 
@@ -98,7 +104,9 @@ mod c {
                     memory_map: ptr::null(),
                     modules_capacity: 0,
                     modules_size: 0,
-                    modules: ptr::null()
+                    modules: ptr::null(),
+                    acpi_root: 0,
+                    acpi_extended: 0
                 }
             }
         }
@@ -141,12 +149,23 @@ mod c {
     pub fn get_image_end() -> u64 {
         unsafe { &_boot_end as *const u8 as u64 }
     }
+
+    #[inline]
+    pub fn get_text_begin() -> u64 {
+        unsafe { &_boot_text as *const u8 as u64 }
+    }
+
+    #[inline]
+    pub fn get_text_end() -> u64 {
+        unsafe { &_boot_text_end as *const u8 as u64 }
+    }
 }
 
 
 #[derive(Debug, Clone, Copy)]
 enum CommandItem {
-    LogLevel
+    LogLevel,
+    Layout
 }
 
 #[inline]
@@ -154,7 +173,18 @@ pub fn get_image_end() -> u64 {
     c::get_image_end()
 }
 
-fn parse_command_line(cmdline: &[u8]) -> log::LogLevelFilter {
+#[inline]
+pub fn get_text_begin() -> u64 {
+    c::get_text_begin()
+}
+
+#[inline]
+pub fn get_text_end() -> u64 {
+    c::get_text_end()
+}
+
+/// Returns the log level and whether the initial layout should be validated
+fn parse_command_line(cmdline: &[u8]) -> (log::LogLevelFilter, bool) {
     let line = match str::from_utf8(cmdline) {
         Ok(s) => s,
         Err(e) => {
@@ -165,6 +195,7 @@ fn parse_command_line(cmdline: &[u8]) -> log::LogLevelFilter {
     debug!("Boot command line: {}", line);
 
     let mut log_level = log::LogLevelFilter::Trace;
+    let mut check_layout = true;
 
     let mut acc = String::new();
     let mut item = None;
@@ -184,6 +215,13 @@ fn parse_command_line(cmdline: &[u8]) -> log::LogLevelFilter {
                             } else {
                                 error!("Invalid log level: {}", acc);
                             }
+                        },
+                        CommandItem::Layout => {
+                            match acc.as_ref() {
+                                "checked" => check_layout = true,
+                                "unchecked" => check_layout = false,
+                                _ => error!("Invalid layout setting: {}", acc)
+                            }
                         }
                     }
                 }
@@ -200,6 +238,8 @@ fn parse_command_line(cmdline: &[u8]) -> log::LogLevelFilter {
                 trace!("{:?}", acc);
                 if acc == "log" {
                     item = Some(CommandItem::LogLevel);
+                } else if acc == "layout" {
+                    item = Some(CommandItem::Layout);
                 } else {
                     item = None;
                 }
@@ -214,7 +254,7 @@ fn parse_command_line(cmdline: &[u8]) -> log::LogLevelFilter {
         }
     }
 
-    log_level
+    (log_level, check_layout)
 }
 
 fn parse_memory_info(memory: &[c::memory_region]) -> MemoryInfo {
@@ -301,20 +341,27 @@ pub fn parse_multiboot_info(multiboot_info: *const c_void) -> BootInfo {
 
     trace!("parsed module info");
 
-    let log_level = if !info.command_line.is_null() {
+    let (log_level, check_layout) = if !info.command_line.is_null() {
         // parse command line
         parse_command_line(
             unsafe {slice::from_raw_parts(info.command_line, info.command_line_size)})
     } else {
-        log::LogLevelFilter::Off
+        (log::LogLevelFilter::Off, true)
     };
 
     trace!("parsed command line");
 
+    if info.acpi_root == 0 {
+        warn!("Did not get an ACPI root table in boot info");
+    }
+
     BootInfo {
         log_level: log_level,
+        check_layout: check_layout,
         memory: memory_info,
-        modules: module_info
+        modules: module_info,
+        acpi_root: info.acpi_root,
+        acpi_extended: info.acpi_extended != 0
     }
 }
 
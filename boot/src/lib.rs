@@ -58,6 +58,10 @@ impl paging::Base for WatermarkBuilder {
         ptr
     }
 
+    unsafe fn release_table(&mut self, _: Shared<paging::Table>) {
+        // boot only builds tables, it never takes them apart
+    }
+
     fn clear(&mut self) {
         self.end = self.base;
     }
@@ -92,6 +96,12 @@ pub extern "C" fn bootstrap(magic: u32, boot_info: *const c_void) -> ! {
     // set up SSE
     enable_sse();
 
+    // build five-level tables if the cpu can use them
+    paging::set_five_level(test_la57());
+
+    // fall back to 2M pages where 1G pages aren't supported
+    paging::set_huge_pages(test_huge_pages());
+
     // enable memory
     memory::enable();
 
@@ -143,11 +153,23 @@ pub extern "C" fn bootstrap(magic: u32, boot_info: *const c_void) -> ! {
     debug!("Initial heap: {:?}", heap);
     debug!("Page tables: {:?}", pages);
 
-    // map our image in so we can safely enable paging
+    // map our image in so we can safely enable paging, only the code executable
+    let text_begin = boot_c::get_text_begin();
+    let text_end = boot_c::get_text_end();
+
     assert!(layout.insert(paging::Segment::new(
-        0x0, 0x0, boot_c::get_image_end(),
-        true, false, true, false
+        0x0, 0x0, text_begin,
+        true, false, false, false
+    )), "failed to add segment");
 
+    assert!(layout.insert(paging::Segment::new(
+        text_begin, text_begin, text_end - text_begin,
+        false, false, true, false
+    )), "failed to add segment");
+
+    assert!(layout.insert(paging::Segment::new(
+        text_end, text_end, boot_c::get_image_end() - text_end,
+        true, false, false, false
     )), "failed to add segment");
 
     trace!("0x{:x}", HEAP_BEGIN);
@@ -158,17 +180,39 @@ pub extern "C" fn bootstrap(magic: u32, boot_info: *const c_void) -> ! {
         true, false, false, false
     )), "failed to add segment");
 
+    // map the page tables so the kernel can change them later
+    assert!(layout.insert(paging::Segment::new(
+        pages.base(), PAGE_TABLES_BEGIN, pages.size(),
+        true, false, false, false
+    )), "failed to add segment");
+
     /*****************LOAD KERNEL*****************/
 
     // make sure we found an entry point
     let entry = entry.expect("Kernel did not contain an entry point");
 
+    // refuse to run with tables that break W^X or let user code into the kernel
+    if let Err(violations) = layout.validate() {
+        for violation in violations.iter() {
+            error!("Invalid layout: {}", violation);
+        }
+
+        if info.check_layout {
+            panic!("Initial layout had {} violations, boot with layout=unchecked to build it anyway",
+                   violations.len());
+        } else {
+            warn!("Building initial layout with {} violations", violations.len());
+        }
+    }
+
     // create the builder
     let mut base = unsafe { WatermarkBuilder::new(pages.base()) };
-    let builder = unsafe { paging::Builder::new(&mut base) };
 
     // build things out
-    let page_tables = unsafe { builder.build(&mut layout) };
+    let page_tables = unsafe {
+        let builder = paging::Builder::new(&mut base);
+        builder.build(&mut layout)
+    };
 
     debug!("built page tables at 0x{:x}", page_tables);
 
@@ -177,7 +221,8 @@ pub extern "C" fn bootstrap(magic: u32, boot_info: *const c_void) -> ! {
     setup_paging(page_tables as u32);
 
     // create the boot proto
-    let proto = Box::new(BootProto::create(info, heap.base()));
+    let proto = Box::new(BootProto::create(info, heap.base(), available.allocated(),
+                                           pages, base.end - pages.base()));
 
     // create a starting gdt
     let tss = cpu::tss::Segment::new([None, None, None, None, None, None, None],
@@ -243,39 +288,36 @@ fn load_module(module: ElfFile, grub_base: u64, available: &mut Allocator, layou
             continue;
         }
 
-        let vm_base = program_header.virtual_addr();
-
-        let p_base;
-
-        if program_header.file_size() > 0 {
-            // data has been included
-            assert!(program_header.file_size() == program_header.mem_size(),
-                    "Can't handle partially-empty sections yet");
-
-            p_base = grub_base + program_header.offset();
-        } else {
-            let place = available.allocate(program_header.mem_size(), program_header.align())
-                .expect("Could not allocate space for empty region");
+        let placement = place_segment(
+            grub_base + program_header.offset(), program_header.virtual_addr(),
+            program_header.file_size(), program_header.mem_size(),
+            program_header.align(), available
+        ).expect("Could not allocate space for zero-filled region");
 
-            // zero-out the region
+        if let Some(tail) = placement.tail {
             unsafe {
-                ptr::write_bytes(place.base() as usize as *mut u8, 0, place.size() as usize);
-            }
+                // zero-out the region
+                ptr::write_bytes(tail.physical_base as usize as *mut u8, 0, tail.size as usize);
 
-            p_base = place.base();
+                // bring over file data that shares a page with the empty part
+                ptr::copy(placement.source as usize as *const u8,
+                          tail.physical_base as usize as *mut u8, placement.copy as usize);
+            }
         }
 
         assert!(program_header.flags() & program::FLAG_R == program::FLAG_R, "Loadable region was not readable");
         let write = program_header.flags() & program::FLAG_W == program::FLAG_W;
         let execute = program_header.flags() & program::FLAG_X == program::FLAG_X;
 
-        // insert this segment
-        let segment = paging::Segment::new(
-            p_base, vm_base, program_header.mem_size(),
-            write, false, execute, false
-        );
+        // insert the pieces of this segment
+        for piece in placement.file.iter().chain(placement.tail.iter()) {
+            let segment = paging::Segment::new(
+                piece.physical_base, piece.virtual_base, piece.size,
+                write, false, execute, false
+            );
 
-        assert!(layout.insert(segment), "failed to insert segment");
+            assert!(layout.insert(segment), "failed to insert segment");
+        }
     }
 }
 
@@ -310,6 +352,12 @@ fn setup_paging(page_tables: u32) {
 
         asm!("mov $0, cr4" : "=r"(cr4) ::: "intel");
         cr4 |= 0xb << 4;
+
+        // the tables were built with a PML5 root
+        if paging::five_level() {
+            cr4 |= 1 << 12;
+        }
+
         asm!("mov cr4, $0" :: "r"(cr4) :: "intel", "volatile");
 
         // set long mode bit and NX bit in EFER MSR
@@ -319,6 +367,9 @@ fn setup_paging(page_tables: u32) {
         efer_msr |= 0x1;
         util::write_msr(EFER_MSR, efer_msr);
 
+        // program the memory types segments can ask for
+        util::write_msr(PAT_MSR, paging::PAT);
+
         // set the WP bit in the cr0 register
         let mut cr0: u32;
 
@@ -413,6 +464,10 @@ fn test_sse() {
         panic!("No SEP");
     }
 
+    if cpuid_d & 1 << 16 == 0 {
+        panic!("No PAT");
+    }
+
     if cpuid_d & 1 << 24 == 0 {
         panic!("No FXSAVE/FXRSTOR");
     }
@@ -422,6 +477,26 @@ fn test_sse() {
     //}
 }
 
+fn test_la57() -> bool {
+    let cpuid_c: u32;
+
+    unsafe {
+        asm!("cpuid" : "={ecx}"(cpuid_c) : "{eax}"(0x7), "{ecx}"(0x0) : "ebx", "edx" : "intel");
+    }
+
+    cpuid_c & 1 << 16 != 0
+}
+
+fn test_huge_pages() -> bool {
+    let cpuid_d: u32;
+
+    unsafe {
+        asm!("cpuid" : "={edx}"(cpuid_d) : "{eax}"(0x80000001) : "ebx", "ecx" : "intel");
+    }
+
+    cpuid_d & 1 << 26 != 0
+}
+
 fn enable_sse() {
     let mut cr0: u32;
 
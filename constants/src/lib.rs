@@ -13,7 +13,6 @@ use std::slice;
 pub mod util;
 pub mod error;
 
-pub const RESERVE_SLAB_SIZE: usize = 0x1000; // eight pages, this is a count of u64s
 pub const RESERVE_MAGIC: u64 = 15297541685404970074;
 pub const VGA_BUFFER_WIDTH: usize = 80;
 pub const VGA_BUFFER_HEIGHT: usize = 25;
@@ -27,6 +26,9 @@ pub const STAR_MSR: u32 = 0xC0000081;
 pub const LSTAR_MSR: u32 = 0xC0000082;
 pub const FMASK_MSR: u32 = 0xC0000084;
 pub const EFER_MSR: u32 = 0xC0000080;
+pub const PAT_MSR: u32 = 0x277;
+pub const APIC_BASE_MSR: u32 = 0x1b;
+pub const TSC_DEADLINE_MSR: u32 = 0x6e0;
 pub const CORE_CS: u16 = 0x08;
 pub const CORE_DS: u16 = 0x10;
 pub const CORE_SS: u16 = 0x10;
@@ -48,9 +50,18 @@ pub const MULTIBOOT_MEMORY_CODE: u32 = 20;
 pub const U64_BYTES: usize = 0x8;
 pub const FXSAVE_SIZE: usize = 0x200;
 
+pub const TABLE_MAP_BEGIN: u64 = 0xffffff8000000000; // page tables from the frame allocator, at their physical address past here
+pub const TABLE_MAP_SIZE: usize = 0x7f80000000;
 pub const CORE_BEGIN: u64 = 0xffffffff80000000;
 pub const CORE_SIZE: usize = 0x80000000;
 pub const HEAP_BEGIN: u64 = 0xffffffff81000000;
+pub const HEAP_SIZE: usize = 0x3f000000; // heap can grow up to the page tables
+pub const PAGE_TABLES_BEGIN: u64 = 0xffffffffc0000000;
+pub const STACKS_BEGIN: u64 = 0xffffffffd0000000; // task stacks, each above a guard page
+pub const STACKS_SIZE: usize = 0x10000000;
+pub const MMIO_BEGIN: u64 = 0xffffffffe0000000; // device registers, mapped on demand
+pub const MMIO_SIZE: usize = 0x10000000;
+pub const COPY_WINDOW: u64 = 0xfffffffff0000000; // one page for filling frames that aren't mapped yet
 pub const IDENTITY_END: usize = 0x400000;
 pub const OPTIMISTIC_HEAP: usize = 0x200000;
 pub const OPTIMISTIC_HEAP_SIZE: usize = 0x200000;
@@ -60,10 +71,13 @@ pub const TASK_SIZE: usize = 0x7fc00000;
 
 pub const PAGE_TABLES_OFFSET: usize = 0x180000;
 
+pub const FRAME_LIMIT: u64 = 0x100000000; // physical memory tracked by the frame allocator
+
 pub const KERNEL_ELF: &'static str = "target/kernel.elf";
 pub const KERNEL_MOD: &'static str = "target/kernel.mod";
 
 pub const CANONICAL_BITS: usize = 48;
+pub const LA57_CANONICAL_BITS: usize = 57; // with five-level paging
 pub const PAGE_ADDR_MASK: u64 = ((1 << CANONICAL_BITS) - 1) & !((1 << 12) - 1);
 
 #[allow(non_camel_case_types)]
@@ -150,5 +164,11 @@ pub fn on_boundary<T>(base: T, end: T, align_to: T) -> bool
 
 #[inline]
 pub fn canonicalize(addr: u64) -> u64 {
-    addr | (0u64.wrapping_sub((addr >> (CANONICAL_BITS - 1)) & 1) << CANONICAL_BITS)
+    canonicalize_to(addr, CANONICAL_BITS)
+}
+
+/// Sign-extend addr from the top of a bits-wide address space
+#[inline]
+pub fn canonicalize_to(addr: u64, bits: usize) -> u64 {
+    addr | (0u64.wrapping_sub((addr >> (bits - 1)) & 1) << bits)
 }
@@ -0,0 +1,236 @@
+use std::slice;
+
+use collections::Vec;
+
+use paging::Cache;
+
+use mmio;
+
+// signature, length, revision, checksum, OEM fields and creator
+const HEADER_SIZE: u64 = 36;
+
+// entries of the MADT start after the local APIC address and flags
+const MADT_ENTRIES: usize = 44;
+
+const ENTRY_IO_APIC: u8 = 1;
+const ENTRY_OVERRIDE: u8 = 2;
+
+/// An IOAPIC, and the first global system interrupt it takes
+#[derive(Debug, Clone, Copy)]
+pub struct IoApic {
+    pub id: u8,
+    pub address: u64,
+    pub gsi_base: u32
+}
+
+/// An ISA IRQ that isn't wired to the global system interrupt of the same number
+#[derive(Debug, Clone, Copy)]
+pub struct Override {
+    pub source: u8,
+    pub gsi: u32,
+    /// MPS INTI flags, polarity in bits 0-1 and trigger mode in bits 2-3
+    pub flags: u16
+}
+
+/// What the MADT says about interrupt routing
+#[derive(Debug)]
+pub struct Madt {
+    pub io_apics: Vec<IoApic>,
+    pub overrides: Vec<Override>
+}
+
+fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    bytes[offset] as u16 | (bytes[offset + 1] as u16) << 8
+}
+
+fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    read_u16(bytes, offset) as u32 | (read_u16(bytes, offset + 2) as u32) << 16
+}
+
+fn read_u64(bytes: &[u8], offset: usize) -> u64 {
+    read_u32(bytes, offset) as u64 | (read_u32(bytes, offset + 4) as u64) << 32
+}
+
+/// Run f on the table at physical, if it has the right signature and sums to zero
+unsafe fn with_table<T, F: FnOnce(&[u8]) -> T>(physical: u64, signature: &str, f: F) -> Option<T> {
+    // only the header is mapped until we know how long the table is
+    let header = match mmio::map(physical, HEADER_SIZE, Cache::WriteBack) {
+        Some(header) => header,
+        None => return None
+    };
+
+    let (matches, length) = {
+        let bytes = slice::from_raw_parts(header as *const u8, HEADER_SIZE as usize);
+
+        (&bytes[0..4] == signature.as_bytes(), read_u32(bytes, 4) as u64)
+    };
+
+    mmio::unmap(header, HEADER_SIZE);
+
+    if !matches || length < HEADER_SIZE {
+        return None;
+    }
+
+    let table = match mmio::map(physical, length, Cache::WriteBack) {
+        Some(table) => table,
+        None => return None
+    };
+
+    let result = {
+        let bytes = slice::from_raw_parts(table as *const u8, length as usize);
+
+        if bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0 {
+            Some(f(bytes))
+        } else {
+            warn!("ACPI table {} at 0x{:x} has a bad checksum", signature, physical);
+            None
+        }
+    };
+
+    mmio::unmap(table, length);
+
+    result
+}
+
+/// Physical addresses of every table the root table points to
+unsafe fn tables(root: u64, extended: bool) -> Option<Vec<u64>> {
+    let (signature, entry_size) = if extended {
+        ("XSDT", 8)
+    } else {
+        ("RSDT", 4)
+    };
+
+    with_table(root, signature, |bytes| {
+        let mut tables = vec![];
+        let mut offset = HEADER_SIZE as usize;
+
+        while offset + entry_size <= bytes.len() {
+            tables.push(if extended {
+                read_u64(bytes, offset)
+            } else {
+                read_u32(bytes, offset) as u64
+            });
+
+            offset += entry_size;
+        }
+
+        tables
+    })
+}
+
+fn parse_madt(bytes: &[u8]) -> Madt {
+    let mut madt = Madt {
+        io_apics: vec![],
+        overrides: vec![]
+    };
+
+    let mut offset = MADT_ENTRIES;
+
+    while offset + 2 <= bytes.len() {
+        let ty = bytes[offset];
+        let length = bytes[offset + 1] as usize;
+
+        if length < 2 || offset + length > bytes.len() {
+            warn!("MADT entry at offset {} runs off the end of the table", offset);
+            break;
+        }
+
+        match ty {
+            ENTRY_IO_APIC if length >= 12 => {
+                madt.io_apics.push(IoApic {
+                    id: bytes[offset + 2],
+                    address: read_u32(bytes, offset + 4) as u64,
+                    gsi_base: read_u32(bytes, offset + 8)
+                });
+            },
+            // only ISA has overrides, so the bus is always zero
+            ENTRY_OVERRIDE if length >= 10 => {
+                madt.overrides.push(Override {
+                    source: bytes[offset + 3],
+                    gsi: read_u32(bytes, offset + 4),
+                    flags: read_u16(bytes, offset + 8)
+                });
+            },
+            _ => {}
+        }
+
+        offset += length;
+    }
+
+    madt
+}
+
+/// Find and parse the MADT under the root table the boot loader passed on
+pub unsafe fn madt(root: u64, extended: bool) -> Option<Madt> {
+    let tables = match tables(root, extended) {
+        Some(tables) => tables,
+        None => {
+            warn!("ACPI root table at 0x{:x} was not valid", root);
+            return None;
+        }
+    };
+
+    for table in tables {
+        if let Some(madt) = with_table(table, "APIC", parse_madt) {
+            return Some(madt);
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use collections::Vec;
+
+    use super::{parse_madt, MADT_ENTRIES};
+
+    fn table(entries: &[&[u8]]) -> Vec<u8> {
+        let mut bytes = vec![0; MADT_ENTRIES];
+
+        for entry in entries {
+            bytes.extend_from_slice(entry);
+        }
+
+        bytes
+    }
+
+    #[test]
+    fn test_parse_madt() {
+        let bytes = table(&[// a local APIC, which is skipped
+                           &[0, 8, 0, 0, 1, 0, 0, 0],
+                           &[1, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0x18, 0x00, 0x00, 0x00],
+                           &[2, 10, 0, 0, 0x02, 0x00, 0x00, 0x00, 0x00, 0x00],
+                           &[2, 10, 0, 9, 0x09, 0x00, 0x00, 0x00, 0x0f, 0x00]]);
+
+        let madt = parse_madt(&bytes);
+
+        assert_eq!(madt.io_apics.len(), 1);
+        assert_eq!((madt.io_apics[0].id, madt.io_apics[0].address, madt.io_apics[0].gsi_base),
+                   (2, 0xfec00000, 0x18));
+
+        assert_eq!(madt.overrides.len(), 2);
+        assert_eq!((madt.overrides[0].source, madt.overrides[0].gsi, madt.overrides[0].flags), (0, 2, 0));
+        assert_eq!((madt.overrides[1].source, madt.overrides[1].gsi, madt.overrides[1].flags), (9, 9, 0xf));
+    }
+
+    #[test]
+    fn test_parse_madt_short() {
+        let bytes = table(&[// too short to be an override, so skipped
+                           &[2, 8, 0, 0, 0x02, 0x00, 0x00, 0x00],
+                           &[2, 10, 0, 1, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00],
+                           // runs off the end, which stops the parse
+                           &[1, 12, 0, 0]]);
+
+        let madt = parse_madt(&bytes);
+
+        assert_eq!(madt.io_apics.len(), 0);
+        assert_eq!(madt.overrides.len(), 1);
+        assert_eq!(madt.overrides[0].source, 1);
+
+        // a zero length would never move on
+        let madt = parse_madt(&table(&[&[2, 0, 0, 0]]));
+
+        assert_eq!(madt.overrides.len(), 0);
+    }
+}
@@ -19,10 +19,15 @@ extern "C" {
     pub fn _bp_handler();
     pub fn _gp_handler();
     pub fn _pf_handler();
+    pub fn _df_handler();
 
     pub fn _bp_early_handler();
     pub fn _gp_early_handler();
     pub fn _pf_early_handler();
+
+    pub static _exception_handlers: [u64; 32];
+    pub static _early_exception_handlers: [u64; 32];
+    pub static _interrupt_handlers: [u64; 224];
 }
 
 #[cfg(not(test))]
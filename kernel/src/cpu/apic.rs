@@ -0,0 +1,300 @@
+use std::ptr;
+use std::sync::atomic::{Ordering, AtomicUsize, AtomicBool};
+
+use constants::*;
+use constants::util;
+
+use paging::Cache;
+
+use mmio;
+
+use super::vector;
+use super::interrupt::Context;
+
+// register offsets into the MMIO page
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const END_OF_INTERRUPT: usize = 0xb0;
+const SPURIOUS: usize = 0xf0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL: usize = 0x380;
+const TIMER_CURRENT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3e0;
+
+const LVT_MASKED: u32 = 1 << 16;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DEADLINE: u32 = 2 << 17;
+
+// count down once every 16 bus cycles
+const DIVIDE_BY_16: u32 = 0x3;
+
+/// Vector the APIC raises when an interrupt goes away before it's taken
+///
+/// Spurious interrupts are never acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Where the legacy PIC's lines end up, masked, so stray ones don't look like exceptions
+///
+/// Lines 7 and 15 are the PICs' spurious interrupts, which never reach the
+/// local APIC and so are never acknowledged to it.
+pub const PIC_VECTOR: u8 = 0x20;
+
+/// Period of the timer setup starts
+pub const TICK_MS: u32 = 10;
+
+const PIT_FREQUENCY: u64 = 1193182;
+const CALIBRATION_MS: u64 = 10;
+
+// reads of port 0x61 to wait for the PIT, each takes about a microsecond
+const CALIBRATION_TIMEOUT: usize = 1000000;
+
+static BASE: AtomicUsize = AtomicUsize::new(0);
+static TIMER_VECTOR: AtomicUsize = AtomicUsize::new(0);
+
+// measured against the PIT
+static TIMER_PER_MS: AtomicUsize = AtomicUsize::new(0);
+static TSC_PER_MS: AtomicUsize = AtomicUsize::new(0);
+
+static DEADLINE: AtomicBool = AtomicBool::new(false);
+
+static TICKS: AtomicUsize = AtomicUsize::new(0);
+
+#[inline]
+unsafe fn read(register: usize) -> u32 {
+    ptr::read_volatile((BASE.load(Ordering::Relaxed) + register) as *const u32)
+}
+
+#[inline]
+unsafe fn write(register: usize, value: u32) {
+    ptr::write_volatile((BASE.load(Ordering::Relaxed) + register) as *mut u32, value);
+}
+
+#[inline]
+fn timestamp() -> u64 {
+    let low: u32;
+    let high: u32;
+
+    unsafe {
+        asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "intel", "volatile");
+    }
+
+    (high as u64) << 32 | low as u64
+}
+
+/// Whether the timer can fire at a TSC value instead of counting down
+pub fn deadline_supported() -> bool {
+    let cpuid_c: u32;
+
+    unsafe {
+        asm!("cpuid" : "={ecx}"(cpuid_c) : "{eax}"(0x1) : "ebx", "edx" : "intel");
+    }
+
+    cpuid_c & 1 << 24 != 0
+}
+
+/// Move the 8259 PICs above the exceptions and mask every line
+fn disable_pic() {
+    // start initialization, expect a fourth word
+    util::write_port_byte(0x20, 0x11);
+    util::write_port_byte(0xa0, 0x11);
+
+    // vector offsets
+    util::write_port_byte(0x21, PIC_VECTOR);
+    util::write_port_byte(0xa1, PIC_VECTOR + 8);
+
+    // the second PIC hangs off line 2 of the first
+    util::write_port_byte(0x21, 1 << 2);
+    util::write_port_byte(0xa1, 2);
+
+    // 8086 mode
+    util::write_port_byte(0x21, 0x01);
+    util::write_port_byte(0xa1, 0x01);
+
+    util::write_port_byte(0x21, 0xff);
+    util::write_port_byte(0xa1, 0xff);
+}
+
+/// Count timer ticks and TSC cycles over a PIT one-shot
+///
+/// Returns false if the PIT never ran out.
+unsafe fn calibrate() -> bool {
+    let count = PIT_FREQUENCY * CALIBRATION_MS / 1000;
+
+    // gate channel 2 on, with the speaker off
+    let gate = util::read_port_byte(0x61) & !0x02;
+
+    // channel 2, low then high byte, interrupt on terminal count
+    util::write_port_byte(0x43, 0b10110000);
+    util::write_port_byte(0x42, count as u8);
+    util::write_port_byte(0x42, (count >> 8) as u8);
+
+    // a rising edge on the gate starts the count
+    util::write_port_byte(0x61, gate & !0x01);
+    util::write_port_byte(0x61, gate | 0x01);
+
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, LVT_MASKED);
+    write(TIMER_INITIAL, 0xffffffff);
+
+    let start = timestamp();
+
+    // output goes high once the count runs out
+    let mut reads = 0;
+
+    while util::read_port_byte(0x61) & 0x20 == 0 {
+        reads += 1;
+
+        if reads == CALIBRATION_TIMEOUT {
+            write(TIMER_INITIAL, 0);
+            return false;
+        }
+    }
+
+    let elapsed = 0xffffffff - read(TIMER_CURRENT);
+    let cycles = timestamp() - start;
+
+    write(TIMER_INITIAL, 0);
+
+    TIMER_PER_MS.store((elapsed as u64 / CALIBRATION_MS) as usize, Ordering::Relaxed);
+    TSC_PER_MS.store((cycles / CALIBRATION_MS) as usize, Ordering::Relaxed);
+
+    true
+}
+
+fn tick(_: &mut Context) {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+// stray PIC lines and spurious APIC interrupts need no handling
+fn ignore(_: &mut Context) {}
+
+/// Whether vector came through the local APIC, and has to be acknowledged to it
+pub fn needs_eoi(vector: u8) -> bool {
+    vector != SPURIOUS_VECTOR && vector != PIC_VECTOR + 7 && vector != PIC_VECTOR + 15
+}
+
+/// Signal the end of the interrupt being handled
+#[inline]
+pub fn eoi() {
+    if BASE.load(Ordering::Relaxed) != 0 {
+        unsafe {
+            write(END_OF_INTERRUPT, 0);
+        }
+    }
+}
+
+/// This CPU's local APIC id, which IOAPICs send interrupts to
+pub fn id() -> u8 {
+    unsafe { (read(ID) >> 24) as u8 }
+}
+
+/// Fire vector every period milliseconds
+pub fn periodic(vector: u8, period: u32) {
+    let count = TIMER_PER_MS.load(Ordering::Relaxed) as u64 * period as u64;
+
+    assert!(count > 0 && count <= 0xffffffff, "Timer period of {} milliseconds out of range", period);
+
+    unsafe {
+        write(TIMER_DIVIDE, DIVIDE_BY_16);
+        write(LVT_TIMER, vector as u32 | TIMER_PERIODIC);
+        write(TIMER_INITIAL, count as u32);
+    }
+}
+
+/// Fire vector once, delay milliseconds from now
+///
+/// Uses a TSC deadline when the CPU has one, otherwise the timer counts down.
+pub fn one_shot(vector: u8, delay: u32) {
+    unsafe {
+        if DEADLINE.load(Ordering::Relaxed) {
+            let cycles = TSC_PER_MS.load(Ordering::Relaxed) as u64 * delay as u64;
+
+            write(LVT_TIMER, vector as u32 | TIMER_DEADLINE);
+
+            // the mode switch has to land before the deadline is armed
+            asm!("mfence" :::: "intel", "volatile");
+
+            util::write_msr(TSC_DEADLINE_MSR, timestamp() + cycles);
+        } else {
+            let count = TIMER_PER_MS.load(Ordering::Relaxed) as u64 * delay as u64;
+
+            assert!(count > 0 && count <= 0xffffffff, "Timer delay of {} milliseconds out of range", delay);
+
+            write(TIMER_DIVIDE, DIVIDE_BY_16);
+            write(LVT_TIMER, vector as u32);
+            write(TIMER_INITIAL, count as u32);
+        }
+    }
+}
+
+/// Stop the timer, whatever mode it's in
+pub fn stop() {
+    unsafe {
+        write(LVT_TIMER, LVT_MASKED);
+        write(TIMER_INITIAL, 0);
+
+        if DEADLINE.load(Ordering::Relaxed) {
+            util::write_msr(TSC_DEADLINE_MSR, 0);
+        }
+    }
+}
+
+/// Timer interrupts since setup
+pub fn ticks() -> usize {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Vector the tick timer fires on
+pub fn timer_vector() -> u8 {
+    TIMER_VECTOR.load(Ordering::Relaxed) as u8
+}
+
+/// Map and enable the local APIC, and start ticking every TICK_MS
+///
+/// Interrupts stay disabled, the caller enables them once it's ready. Returns
+/// false, with the timer stopped, if it could not be calibrated against the PIT.
+pub unsafe fn setup() -> bool {
+    disable_pic();
+
+    for line in 0..16 {
+        assert!(vector::reserve(PIC_VECTOR + line), "PIC vector was already taken");
+    }
+
+    // only the PICs' spurious lines can fire while masked
+    vector::register(PIC_VECTOR + 7, ignore);
+    vector::register(PIC_VECTOR + 15, ignore);
+
+    let apic_base = util::read_msr(APIC_BASE_MSR);
+    let physical = apic_base & 0x000ffffffffff000;
+
+    let base = mmio::map(physical, 0x1000, Cache::Uncached).expect("Failed to map local APIC");
+
+    BASE.store(base as usize, Ordering::Relaxed);
+
+    // make sure it's enabled in case firmware turned it off
+    util::write_msr(APIC_BASE_MSR, apic_base | 1 << 11);
+
+    assert!(vector::reserve(SPURIOUS_VECTOR), "Spurious vector was already taken");
+    vector::register(SPURIOUS_VECTOR, ignore);
+
+    write(SPURIOUS, 1 << 8 | SPURIOUS_VECTOR as u32);
+    write(TASK_PRIORITY, 0);
+
+    if !calibrate() {
+        return false;
+    }
+
+    debug!("Local APIC at 0x{:x}, timer runs at {} ticks/ms, TSC at {} cycles/ms",
+           physical, TIMER_PER_MS.load(Ordering::Relaxed), TSC_PER_MS.load(Ordering::Relaxed));
+
+    DEADLINE.store(deadline_supported(), Ordering::Relaxed);
+
+    let timer = vector::allocate().expect("No vector left for the timer");
+
+    vector::register(timer, tick);
+    TIMER_VECTOR.store(timer as usize, Ordering::Relaxed);
+
+    periodic(timer, TICK_MS);
+
+    true
+}
@@ -3,13 +3,21 @@ use std::sync::atomic::{Ordering, AtomicBool};
 use constants::*;
 
 use kernel_std::cpu::{gdt, tss, idt};
+use kernel_std::cpu::stack::Stack;
 
 use c;
 
-static mut EARLY_IDT_BUFFER: [u64; 2 * 15 * U64_BYTES] = [0; 2 * 15 * U64_BYTES];
+use super::vector;
+
+static mut EARLY_IDT_BUFFER: [u64; 2 * 32 * U64_BYTES] = [0; 2 * 32 * U64_BYTES];
 
 static SETUP_DONE: AtomicBool = AtomicBool::new(false);
 
+// interrupt stack table slots, numbered from one like descriptors do
+const DOUBLE_FAULT_STACK: u8 = 1;
+const NMI_STACK: u8 = 2;
+const MACHINE_CHECK_STACK: u8 = 3;
+
 #[cfg(test)]
 unsafe extern "C" fn _bp_handler() {
     unreachable!("Breakpoint handler reached");
@@ -43,22 +51,40 @@ unsafe extern "C" fn _pf_early_handler() {
 pub unsafe fn early_setup() {
     // no logging or memory at this point
 
+    // exceptions without a handler of their own panic with a register dump
     let early_idt = [
-        idt::Descriptor::placeholder(), // 0
-        idt::Descriptor::placeholder(), // 1
-        idt::Descriptor::placeholder(), // 2
+        idt::Descriptor::new(c::_early_exception_handlers[0], 0), // 0
+        idt::Descriptor::new(c::_early_exception_handlers[1], 0), // 1
+        idt::Descriptor::new(c::_early_exception_handlers[2], 0), // 2
         idt::Descriptor::new(c::_bp_early_handler as u64, 0), // 3
-        idt::Descriptor::placeholder(), // 4
-        idt::Descriptor::placeholder(), // 5
-        idt::Descriptor::placeholder(), // 6
-        idt::Descriptor::placeholder(), // 7
-        idt::Descriptor::placeholder(), // 8
-        idt::Descriptor::placeholder(), // 9
-        idt::Descriptor::placeholder(), // 10
-        idt::Descriptor::placeholder(), // 11
-        idt::Descriptor::placeholder(), // 12
+        idt::Descriptor::new(c::_early_exception_handlers[4], 0), // 4
+        idt::Descriptor::new(c::_early_exception_handlers[5], 0), // 5
+        idt::Descriptor::new(c::_early_exception_handlers[6], 0), // 6
+        idt::Descriptor::new(c::_early_exception_handlers[7], 0), // 7
+        idt::Descriptor::new(c::_early_exception_handlers[8], 0), // 8
+        idt::Descriptor::new(c::_early_exception_handlers[9], 0), // 9
+        idt::Descriptor::new(c::_early_exception_handlers[10], 0), // 10
+        idt::Descriptor::new(c::_early_exception_handlers[11], 0), // 11
+        idt::Descriptor::new(c::_early_exception_handlers[12], 0), // 12
         idt::Descriptor::new(c::_gp_early_handler as u64, 0), // 13
         idt::Descriptor::new(c::_pf_early_handler as u64, 0), // 14
+        idt::Descriptor::new(c::_early_exception_handlers[15], 0), // 15
+        idt::Descriptor::new(c::_early_exception_handlers[16], 0), // 16
+        idt::Descriptor::new(c::_early_exception_handlers[17], 0), // 17
+        idt::Descriptor::new(c::_early_exception_handlers[18], 0), // 18
+        idt::Descriptor::new(c::_early_exception_handlers[19], 0), // 19
+        idt::Descriptor::new(c::_early_exception_handlers[20], 0), // 20
+        idt::Descriptor::new(c::_early_exception_handlers[21], 0), // 21
+        idt::Descriptor::new(c::_early_exception_handlers[22], 0), // 22
+        idt::Descriptor::new(c::_early_exception_handlers[23], 0), // 23
+        idt::Descriptor::new(c::_early_exception_handlers[24], 0), // 24
+        idt::Descriptor::new(c::_early_exception_handlers[25], 0), // 25
+        idt::Descriptor::new(c::_early_exception_handlers[26], 0), // 26
+        idt::Descriptor::new(c::_early_exception_handlers[27], 0), // 27
+        idt::Descriptor::new(c::_early_exception_handlers[28], 0), // 28
+        idt::Descriptor::new(c::_early_exception_handlers[29], 0), // 29
+        idt::Descriptor::new(c::_early_exception_handlers[30], 0), // 30
+        idt::Descriptor::new(c::_early_exception_handlers[31], 0), // 31
     ];
 
     idt::early_install(&early_idt, EARLY_IDT_BUFFER.as_mut_ptr());
@@ -73,7 +99,11 @@ pub unsafe fn setup() -> (gdt::Table, idt::Table) {
     trace!("Setting up cpu");
 
     // create a new GDT with a TSS
-    let tss = tss::Segment::new([None, None, None, None, None, None, None],
+    // double faults get a stack of their own, so a task running into the guard
+    // page under its stack, where the page fault can't push its frame, is still reported
+    // NMIs and machine checks can arrive with any stack at all
+    let tss = tss::Segment::new([Some(Stack::new(STACK_SIZE)), Some(Stack::new(STACK_SIZE)),
+                                 Some(Stack::new(STACK_SIZE)), None, None, None, None],
                                 [None, None, None], 0);
 
     let mut gdt = gdt::Table::new(vec![tss]);
@@ -92,9 +122,20 @@ pub unsafe fn setup() -> (gdt::Table, idt::Table) {
 
     let mut idt = idt::Table::new();
 
+    // every exception gets a handler that dumps the context
+    for vector in 0..32 {
+        idt.insert(vector, idt::Descriptor::new(c::_exception_handlers[vector as usize], 0));
+    }
+
+    idt.insert(0x2, idt::Descriptor::new(c::_exception_handlers[0x2], NMI_STACK));
     idt.insert(0x3, idt::Descriptor::new(c::_bp_handler as u64, 0));
+    idt.insert(0x8, idt::Descriptor::new(c::_df_handler as u64, DOUBLE_FAULT_STACK));
     idt.insert(0xd, idt::Descriptor::new(c::_gp_handler as u64, 0));
     idt.insert(0xe, idt::Descriptor::new(c::_pf_handler as u64, 0));
+    idt.insert(0x12, idt::Descriptor::new(c::_exception_handlers[0x12], MACHINE_CHECK_STACK));
+
+    // and the rest go to whatever handlers drivers register
+    vector::setup(&mut idt);
 
     idt.install();
 
@@ -1,48 +1,179 @@
 use std::ptr;
+use std::fmt;
 
-#[allow(dead_code)]
-// may be used more later
+/// Registers saved by the interrupt stubs, which they restore on return
 #[repr(C, packed)]
 pub struct Context {
-    rax: u64,
-    rbx: u64,
-    rcx: u64,
-    rdx: u64,
-    rbp: u64,
-    rsi: u64,
-    rdi: u64,
-    r8: u64,
-    r9: u64,
-    r10: u64,
-    r11: u64,
-    r12: u64,
-    r13: u64,
-    r14: u64,
-    r15: u64,
+    pub rax: u64,
+    pub rbx: u64,
+    pub rcx: u64,
+    pub rdx: u64,
+    pub rbp: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub r8: u64,
+    pub r9: u64,
+    pub r10: u64,
+    pub r11: u64,
+    pub r12: u64,
+    pub r13: u64,
+    pub r14: u64,
+    pub r15: u64,
 
     // begin interrupt info
-    error_code: u64,
-    rip: u64,
-    cs: u64,
-    rflags: u64,
-    rsp: u64,
-    ss: u64,
+    pub error_code: u64,
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+/// Exception names, indexed by vector
+const EXCEPTIONS: [&'static str; 32] = [
+    "Divide error",
+    "Debug exception",
+    "Non-maskable interrupt",
+    "Breakpoint",
+    "Overflow",
+    "Bound range exceeded",
+    "Invalid opcode",
+    "Device not available",
+    "Double fault",
+    "Coprocessor segment overrun",
+    "Invalid TSS",
+    "Segment not present",
+    "Stack-segment fault",
+    "General protection fault",
+    "Page fault",
+    "Reserved exception 15",
+    "x87 floating-point exception",
+    "Alignment check",
+    "Machine check",
+    "SIMD floating-point exception",
+    "Virtualization exception",
+    "Control protection exception",
+    "Reserved exception 22",
+    "Reserved exception 23",
+    "Reserved exception 24",
+    "Reserved exception 25",
+    "Reserved exception 26",
+    "Reserved exception 27",
+    "Hypervisor injection exception",
+    "VMM communication exception",
+    "Security exception",
+    "Reserved exception 31"
+];
+
+/// Everything we know about the state an exception interrupted
+struct Dump<'a> {
+    context: &'a Context,
+    cr2: u64,
+    cr3: u64
+}
+
+impl<'a> fmt::Display for Dump<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let context = self.context;
+
+        try!(writeln!(fmt, "rax 0x{:016x} rbx 0x{:016x} rcx 0x{:016x} rdx 0x{:016x}",
+                      context.rax, context.rbx, context.rcx, context.rdx));
+        try!(writeln!(fmt, "rsi 0x{:016x} rdi 0x{:016x} rbp 0x{:016x} rsp 0x{:016x}",
+                      context.rsi, context.rdi, context.rbp, context.rsp));
+        try!(writeln!(fmt, "r8  0x{:016x} r9  0x{:016x} r10 0x{:016x} r11 0x{:016x}",
+                      context.r8, context.r9, context.r10, context.r11));
+        try!(writeln!(fmt, "r12 0x{:016x} r13 0x{:016x} r14 0x{:016x} r15 0x{:016x}",
+                      context.r12, context.r13, context.r14, context.r15));
+        try!(writeln!(fmt, "rip 0x{:016x} cs  0x{:04x} rflags 0x{:08x} ss 0x{:04x} error 0x{:x}",
+                      context.rip, context.cs, context.rflags, context.ss, context.error_code));
+
+        write!(fmt, "cr2 0x{:016x} cr3 0x{:016x}", self.cr2, self.cr3)
+    }
+}
+
+#[inline]
+unsafe fn fault_address() -> u64 {
+    let cr2: u64;
+
+    asm!("mov $0, cr2" : "=r"(cr2) ::: "intel");
+
+    cr2
+}
+
+#[inline]
+unsafe fn page_tables() -> u64 {
+    let cr3: u64;
+
+    asm!("mov $0, cr3" : "=r"(cr3) ::: "intel");
+
+    cr3
+}
+
+// read the control registers before anything else can change them
+unsafe fn dump(context: &Context) -> Dump {
+    Dump {
+        context: context,
+        cr2: fault_address(),
+        cr3: page_tables()
+    }
+}
+
+unsafe fn unhandled(context: *const Context, vector: u64) -> ! {
+    let context = ptr::read(context);
+
+    panic!("{} at 0x{:x}\n{}", EXCEPTIONS[vector as usize], context.rip, dump(&context));
+}
+
+/// Run f with interrupts disabled, restoring them afterwards if they were enabled
+pub fn without_interrupts<T, F: FnOnce() -> T>(f: F) -> T {
+    let rflags: u64;
+
+    unsafe {
+        asm!("pushfq; pop $0; cli" : "=r"(rflags) ::: "intel", "volatile");
+    }
+
+    let result = f();
+
+    if rflags & 1 << 9 != 0 {
+        unsafe {
+            asm!("sti" :::: "intel", "volatile");
+        }
+    }
+
+    result
+}
+
+/// Let interrupts in, once there are handlers for them
+pub unsafe fn enable() {
+    asm!("sti" :::: "intel", "volatile");
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn interrupt_dispatch(context: *mut Context, vector: u64) {
+    // changes handlers make to the context are restored on return
+    super::vector::dispatch(vector as u8, &mut *context);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn interrupt_exception(context: *const Context, vector: u64) {
+    unhandled(context, vector);
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn interrupt_breakpoint(context: *const Context) {
     let context = ptr::read(context);
 
-    debug!("Breakpoint at 0x{:x}", context.rip);
+    debug!("Breakpoint at 0x{:x}\n{}", context.rip, dump(&context));
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn interrupt_general_protection_fault(context: *const Context) {
     let context = ptr::read(context);
 
-    panic!("General protection fault at 0x{:x}, error 0x{:x}",
+    panic!("General protection fault at 0x{:x}, error 0x{:x}\n{}",
            context.rip,
-           context.error_code);
+           context.error_code,
+           dump(&context));
 }
 
 #[no_mangle]
@@ -79,23 +210,66 @@ pub unsafe extern "C" fn interrupt_page_fault(context: *const Context) {
         }
     };
 
-    panic!("Page fault at 0x{:x}: {} on {}-level {}", context.rip, error, access_level, access_type);
+    let address = fault_address();
+
+    if (context.error_code & 1) == 0 && ::space::fault(address) {
+        // touched a lazy segment, which is backed now
+        return;
+    }
+
+    if (context.error_code & 0b11) == 0b11 && ::space::write_fault(address) {
+        // wrote to a copy-on-write page, which has its own frame now
+        return;
+    }
+
+    if let Some(overflow) = ::stacks::overflow(address) {
+        panic!("Stack overflow in task {} at 0x{:x}, accessing 0x{:x} below stack top 0x{:x}\n{}",
+               overflow.owner.unwrap_or("<unknown>"), context.rip, address, overflow.top, dump(&context));
+    }
+
+    ::heap::print_path(address);
+
+    if (context.error_code & (1 << 5)) != 0 {
+        if let Some(key) = ::heap::protection_key(address) {
+            panic!("Page fault at 0x{:x} accessing 0x{:x}: protection key {} denied {}-level {}\n{}",
+                   context.rip, address, key, access_level, access_type, dump(&context));
+        }
+    }
+
+    panic!("Page fault at 0x{:x} accessing 0x{:x}: {} on {}-level {}\n{}",
+           context.rip, address, error, access_level, access_type, dump(&context));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn interrupt_double_fault(context: *const Context) {
+    let context = ptr::read(context);
+
+    // usually a fault that couldn't push its frame because the stack ran into its guard page
+    let guard = ::stacks::overflow(fault_address()).or_else(|| ::stacks::overflow(context.rsp.wrapping_sub(1)));
+
+    if let Some(overflow) = guard {
+        panic!("Double fault at 0x{:x}: stack overflow in task {} below stack top 0x{:x}\n{}",
+               context.rip, overflow.owner.unwrap_or("<unknown>"), overflow.top, dump(&context));
+    }
+
+    panic!("Double fault at 0x{:x}\n{}", context.rip, dump(&context));
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn early_interrupt_breakpoint(context: *const Context) {
     let context = ptr::read(context);
 
-    debug!("Breakpoint at 0x{:x}", context.rip);
+    debug!("Breakpoint at 0x{:x}\n{}", context.rip, dump(&context));
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn early_interrupt_general_protection_fault(context: *const Context) {
     let context = ptr::read(context);
 
-    panic!("General protection fault at 0x{:x}, error 0x{:x}",
+    panic!("General protection fault at 0x{:x}, error 0x{:x}\n{}",
            context.rip,
-           context.error_code);
+           context.error_code,
+           dump(&context));
 }
 
 #[no_mangle]
@@ -132,5 +306,11 @@ pub unsafe extern "C" fn early_interrupt_page_fault(context: *const Context) {
         }
     };
 
-    panic!("Page fault at 0x{:x}: {} on {}-level {}", context.rip, error, access_level, access_type);
+    panic!("Page fault at 0x{:x} accessing 0x{:x}: {} on {}-level {}\n{}",
+           context.rip, fault_address(), error, access_level, access_type, dump(&context));
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn early_interrupt_exception(context: *const Context, vector: u64) {
+    unhandled(context, vector);
 }
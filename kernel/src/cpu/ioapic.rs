@@ -0,0 +1,154 @@
+use std::ptr;
+
+use collections::Vec;
+
+use spin::Mutex;
+
+use paging::Cache;
+
+use mmio;
+use acpi;
+
+use super::interrupt::without_interrupts;
+
+// the only two registers in the MMIO window, everything else is behind them
+const REGISTER_SELECT: u64 = 0x00;
+const REGISTER_WINDOW: u64 = 0x10;
+
+const VERSION: u32 = 0x01;
+const REDIRECTION: u32 = 0x10;
+
+const MASKED: u64 = 1 << 16;
+const ACTIVE_LOW: u64 = 1 << 13;
+const LEVEL_TRIGGERED: u64 = 1 << 15;
+
+/// How a global system interrupt signals
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Trigger {
+    pub active_low: bool,
+    pub level: bool
+}
+
+struct IoApic {
+    base: u64,
+    gsi_base: u32,
+    count: u32
+}
+
+// interrupts are disabled whenever this is held
+static IO_APICS: Mutex<Option<Vec<IoApic>>> = Mutex::new(None);
+
+impl IoApic {
+    unsafe fn new(entry: &acpi::IoApic) -> Option<IoApic> {
+        let base = match mmio::map(entry.address, 0x20, Cache::Uncached) {
+            Some(base) => base,
+            None => return None
+        };
+
+        let mut io_apic = IoApic {
+            base: base,
+            gsi_base: entry.gsi_base,
+            count: 0
+        };
+
+        // bits 16-23 hold the index of the last redirection entry
+        io_apic.count = ((io_apic.read(VERSION) >> 16) & 0xff) + 1;
+
+        // firmware may have left lines unmasked
+        for idx in 0..io_apic.count {
+            io_apic.write_entry(idx, MASKED);
+        }
+
+        Some(io_apic)
+    }
+
+    unsafe fn read(&self, register: u32) -> u32 {
+        ptr::write_volatile((self.base + REGISTER_SELECT) as *mut u32, register);
+        ptr::read_volatile((self.base + REGISTER_WINDOW) as *const u32)
+    }
+
+    unsafe fn write(&self, register: u32, value: u32) {
+        ptr::write_volatile((self.base + REGISTER_SELECT) as *mut u32, register);
+        ptr::write_volatile((self.base + REGISTER_WINDOW) as *mut u32, value);
+    }
+
+    unsafe fn write_entry(&self, idx: u32, entry: u64) {
+        // mask first so the line never fires half written
+        self.write(REDIRECTION + idx * 2, MASKED as u32);
+        self.write(REDIRECTION + idx * 2 + 1, (entry >> 32) as u32);
+        self.write(REDIRECTION + idx * 2, entry as u32);
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi - self.gsi_base < self.count
+    }
+}
+
+fn with_entry<T, F: FnOnce(&IoApic, u32) -> T>(gsi: u32, f: F) -> Option<T> {
+    without_interrupts(|| {
+        let io_apics = IO_APICS.lock();
+
+        let result = io_apics.as_ref()
+            .and_then(|io_apics| io_apics.iter().find(|io_apic| io_apic.handles(gsi)))
+            .map(|io_apic| f(io_apic, gsi - io_apic.gsi_base));
+
+        result
+    })
+}
+
+/// Send gsi to vector on the local APIC with id destination, leaving it masked
+///
+/// Returns false if no IOAPIC takes gsi.
+pub fn route(gsi: u32, vector: u8, destination: u8, trigger: Trigger) -> bool {
+    let mut entry = MASKED | vector as u64 | (destination as u64) << 56;
+
+    if trigger.active_low {
+        entry |= ACTIVE_LOW;
+    }
+
+    if trigger.level {
+        entry |= LEVEL_TRIGGERED;
+    }
+
+    with_entry(gsi, |io_apic, idx| unsafe { io_apic.write_entry(idx, entry) }).is_some()
+}
+
+/// Stop gsi from interrupting, returning false if no IOAPIC takes it
+pub fn mask(gsi: u32) -> bool {
+    with_entry(gsi, |io_apic, idx| unsafe {
+        // the mask bit is in the low half
+        let low = io_apic.read(REDIRECTION + idx * 2);
+
+        io_apic.write(REDIRECTION + idx * 2, low | MASKED as u32);
+    }).is_some()
+}
+
+/// Let gsi interrupt again, returning false if no IOAPIC takes it
+pub fn unmask(gsi: u32) -> bool {
+    with_entry(gsi, |io_apic, idx| unsafe {
+        let low = io_apic.read(REDIRECTION + idx * 2);
+
+        io_apic.write(REDIRECTION + idx * 2, low & !(MASKED as u32));
+    }).is_some()
+}
+
+/// Map every IOAPIC in the MADT with all of its lines masked
+pub unsafe fn setup(madt: &acpi::Madt) {
+    let mut io_apics = vec![];
+
+    for entry in madt.io_apics.iter() {
+        match IoApic::new(entry) {
+            Some(io_apic) => {
+                debug!("IOAPIC {} at 0x{:x} takes global interrupts {} to {}",
+                       entry.id, entry.address, io_apic.gsi_base, io_apic.gsi_base + io_apic.count - 1);
+
+                io_apics.push(io_apic);
+            },
+            None => {
+                warn!("Failed to map IOAPIC {} at 0x{:x}", entry.id, entry.address);
+            }
+        }
+    }
+
+    *IO_APICS.lock() = Some(io_apics);
+}
@@ -0,0 +1,247 @@
+use collections::Vec;
+
+use spin::Mutex;
+
+use kernel_std::BootProto;
+
+use acpi;
+
+use super::vector::{self, Handler};
+use super::interrupt::without_interrupts;
+use super::{apic, ioapic};
+
+/// Lines on the legacy ISA bus, which the MADT's overrides cover
+pub const ISA_LINES: u8 = 16;
+
+// fields of an override's flags, zero means whatever the bus does
+const POLARITY_MASK: u16 = 0x3;
+const POLARITY_LOW: u16 = 0x3;
+const TRIGGER_MASK: u16 = 0xc;
+const TRIGGER_LEVEL: u16 = 0xc;
+
+struct Line {
+    // none if an override took this line's global interrupt for another line
+    gsi: Option<u32>,
+    trigger: ioapic::Trigger,
+    // only allocated while the line has handlers
+    vector: Option<u8>
+}
+
+// interrupts are disabled whenever this is held
+static LINES: Mutex<Option<Vec<Line>>> = Mutex::new(None);
+
+fn with_line<T, F: FnOnce(&mut Line) -> T>(line: u8, f: F) -> Option<T> {
+    assert!(line < ISA_LINES, "IRQ {} is not an ISA line", line);
+
+    without_interrupts(|| {
+        let mut lines = LINES.lock();
+
+        let result = lines.as_mut().map(|lines| f(&mut lines[line as usize]));
+
+        result
+    })
+}
+
+/// Run handler whenever line interrupts, and unmask it
+///
+/// Every handler on a shared line runs. Returns false if the line can't be
+/// routed, because there's no IOAPIC for it, its global interrupt was taken
+/// by an override, or there's no vector left.
+pub fn enable(line: u8, handler: Handler) -> bool {
+    with_line(line, |entry| {
+        let gsi = match entry.gsi {
+            Some(gsi) => gsi,
+            None => return false
+        };
+
+        let vector = match entry.vector {
+            Some(vector) => vector,
+            None => {
+                let vector = match vector::allocate() {
+                    Some(vector) => vector,
+                    None => return false
+                };
+
+                if !ioapic::route(gsi, vector, apic::id(), entry.trigger) {
+                    vector::free(vector);
+                    return false;
+                }
+
+                entry.vector = Some(vector);
+                vector
+            }
+        };
+
+        vector::register(vector, handler);
+
+        ioapic::unmask(gsi)
+    }).unwrap_or(false)
+}
+
+/// Stop running handler for line, returning whether it was enabled
+///
+/// Once no handlers are left the line is masked and its vector given back.
+pub fn disable(line: u8, handler: Handler) -> bool {
+    with_line(line, |entry| {
+        let vector = match entry.vector {
+            Some(vector) => vector,
+            None => return false
+        };
+
+        if !vector::unregister(vector, handler) {
+            return false;
+        }
+
+        if !vector::is_handled(vector) {
+            // only lines with a global interrupt get a vector
+            if let Some(gsi) = entry.gsi {
+                ioapic::mask(gsi);
+            }
+
+            vector::free(vector);
+            entry.vector = None;
+        }
+
+        true
+    }).unwrap_or(false)
+}
+
+/// Hold off line's interrupts without dropping its handlers
+pub fn mask(line: u8) -> bool {
+    with_line(line, |entry| match (entry.vector, entry.gsi) {
+        (Some(_), Some(gsi)) => ioapic::mask(gsi),
+        _ => false
+    }).unwrap_or(false)
+}
+
+/// Let an enabled line interrupt again after mask
+pub fn unmask(line: u8) -> bool {
+    with_line(line, |entry| match (entry.vector, entry.gsi) {
+        (Some(_), Some(gsi)) => ioapic::unmask(gsi),
+        _ => false
+    }).unwrap_or(false)
+}
+
+// where each ISA line ends up once the MADT's overrides are applied
+fn route_lines(overrides: &[acpi::Override]) -> Vec<Line> {
+    // ISA lines are active high and edge triggered unless overridden
+    let mut lines: Vec<Line> = (0..ISA_LINES).map(|line| Line {
+        gsi: Some(line as u32),
+        trigger: ioapic::Trigger {
+            active_low: false,
+            level: false
+        },
+        vector: None
+    }).collect();
+
+    let mut overridden = [false; ISA_LINES as usize];
+
+    for entry in overrides.iter() {
+        if entry.source >= ISA_LINES {
+            warn!("Ignoring override for IRQ {}, which is not an ISA line", entry.source);
+            continue;
+        }
+
+        debug!("IRQ {} goes to global interrupt {} with flags 0x{:x}", entry.source, entry.gsi, entry.flags);
+
+        lines[entry.source as usize] = Line {
+            gsi: Some(entry.gsi),
+            trigger: ioapic::Trigger {
+                active_low: entry.flags & POLARITY_MASK == POLARITY_LOW,
+                level: entry.flags & TRIGGER_MASK == TRIGGER_LEVEL
+            },
+            vector: None
+        };
+
+        overridden[entry.source as usize] = true;
+    }
+
+    // an override onto another line's global interrupt leaves that line without one,
+    // like IRQ 0 going to global interrupt 2 does to IRQ 2
+    for line in 0..ISA_LINES {
+        let claimed = overrides.iter()
+            .any(|entry| entry.source < ISA_LINES && entry.source != line && entry.gsi == line as u32);
+
+        if claimed && !overridden[line as usize] {
+            debug!("IRQ {} lost its global interrupt to an override", line);
+
+            lines[line as usize].gsi = None;
+        }
+    }
+
+    lines
+}
+
+/// Find the IOAPICs and where each ISA line ends up from the MADT
+///
+/// Lines stay masked until something enables them. Without a MADT, enabling
+/// any line fails.
+pub unsafe fn setup(proto: &BootProto) {
+    let madt = match proto.acpi_root().and_then(|(root, extended)| acpi::madt(root, extended)) {
+        Some(madt) => madt,
+        None => {
+            warn!("No MADT found, device interrupts are unavailable");
+            return;
+        }
+    };
+
+    ioapic::setup(&madt);
+
+    *LINES.lock() = Some(route_lines(&madt.overrides));
+}
+
+#[cfg(test)]
+mod tests {
+    use acpi::Override;
+    use super::route_lines;
+
+    #[test]
+    fn test_identity() {
+        let lines = route_lines(&[]);
+
+        assert_eq!(lines.len(), 16);
+
+        for (idx, line) in lines.iter().enumerate() {
+            assert_eq!(line.gsi, Some(idx as u32));
+            assert!(!line.trigger.active_low && !line.trigger.level);
+        }
+    }
+
+    #[test]
+    fn test_overrides() {
+        let lines = route_lines(&[// the PIT, on the usual line
+                                  Override { source: 0, gsi: 2, flags: 0 },
+                                  // ACPI's SCI, active low and level triggered
+                                  Override { source: 9, gsi: 9, flags: 0xf },
+                                  // active high and level triggered
+                                  Override { source: 10, gsi: 20, flags: 0xd },
+                                  // not an ISA line
+                                  Override { source: 16, gsi: 3, flags: 0xf }]);
+
+        assert_eq!(lines[0].gsi, Some(2));
+        assert!(!lines[0].trigger.active_low && !lines[0].trigger.level);
+
+        // IRQ 0 took its global interrupt
+        assert_eq!(lines[2].gsi, None);
+
+        // the override for IRQ 16 changed nothing
+        assert_eq!(lines[3].gsi, Some(3));
+        assert!(!lines[3].trigger.active_low && !lines[3].trigger.level);
+
+        assert_eq!(lines[9].gsi, Some(9));
+        assert!(lines[9].trigger.active_low && lines[9].trigger.level);
+
+        assert_eq!(lines[10].gsi, Some(20));
+        assert!(!lines[10].trigger.active_low && lines[10].trigger.level);
+    }
+
+    #[test]
+    fn test_swapped() {
+        // two lines trading global interrupts both keep theirs
+        let lines = route_lines(&[Override { source: 4, gsi: 5, flags: 0 },
+                                  Override { source: 5, gsi: 4, flags: 0 }]);
+
+        assert_eq!(lines[4].gsi, Some(5));
+        assert_eq!(lines[5].gsi, Some(4));
+    }
+}
@@ -1,4 +1,9 @@
 pub mod init;
 pub mod task;
 pub mod interrupt;
+pub mod pkey;
+pub mod vector;
+pub mod apic;
+pub mod ioapic;
+pub mod irq;
 //pub mod syscall;
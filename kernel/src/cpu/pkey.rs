@@ -0,0 +1,90 @@
+use std::sync::atomic::{Ordering, AtomicBool};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// What the running task may do with user pages tagged with a key
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    ReadWrite,
+    ReadOnly,
+    None
+}
+
+/// PKRU value that allows every access
+pub const ALLOW_ALL: u32 = 0;
+
+/// Whether the CPU has protection keys for user pages
+pub fn supported() -> bool {
+    let cpuid_c: u32;
+
+    unsafe {
+        asm!("cpuid" : "={ecx}"(cpuid_c) : "{eax}"(0x7), "{ecx}"(0x0) : "ebx", "edx" : "intel");
+    }
+
+    cpuid_c & 1 << 3 != 0
+}
+
+/// Set CR4.PKE if the CPU supports it, returning whether keys are enforced now
+pub unsafe fn enable() -> bool {
+    if !supported() {
+        return false;
+    }
+
+    let mut cr4: u64;
+
+    asm!("mov $0, cr4" : "=r"(cr4) ::: "intel");
+    cr4 |= 1 << 22;
+    asm!("mov cr4, $0" :: "r"(cr4) :: "intel", "volatile");
+
+    write(ALLOW_ALL);
+
+    ENABLED.store(true, Ordering::Relaxed);
+
+    true
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Read PKRU, which only exists once keys are enabled
+#[inline]
+pub unsafe fn read() -> u32 {
+    let pkru: u32;
+
+    // rdpkru, which the assembler may not know yet
+    asm!(".byte 0x0f, 0x01, 0xee" : "={eax}"(pkru) : "{ecx}"(0) : "edx" : "intel", "volatile");
+
+    pkru
+}
+
+#[inline]
+pub unsafe fn write(pkru: u32) {
+    // wrpkru
+    asm!(".byte 0x0f, 0x01, 0xef" :: "{eax}"(pkru), "{ecx}"(0), "{edx}"(0) : "memory" : "intel", "volatile");
+}
+
+/// Change the access to key in a PKRU value
+pub fn with_access(pkru: u32, key: u8, access: Access) -> u32 {
+    assert!(key < 16, "Protection key {} out of range", key);
+
+    let bits = match access {
+        Access::ReadWrite => 0b00,
+        Access::ReadOnly => 0b10,
+        Access::None => 0b01
+    };
+
+    pkru & !(0b11 << (key * 2)) | bits << (key * 2)
+}
+
+/// The access a PKRU value gives to key
+pub fn access(pkru: u32, key: u8) -> Access {
+    assert!(key < 16, "Protection key {} out of range", key);
+
+    match pkru >> (key * 2) & 0b11 {
+        0b00 => Access::ReadWrite,
+        0b10 => Access::ReadOnly,
+        _ => Access::None
+    }
+}
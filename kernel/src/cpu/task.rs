@@ -8,6 +8,11 @@ use alloc::rc::Rc;
 
 use kernel_std::cpu::stack::Stack;
 
+use space::AddressSpace;
+
+use super::pkey;
+use super::pkey::Access;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Context {
     Empty,
@@ -57,15 +62,19 @@ pub struct Handle {
 }
 
 struct TaskInner {
+    name: &'static str,
     context: Context,
     entry: extern fn(current: Task) -> !,
-    stack: Stack
+    stack: Stack,
+    space: Rc<AddressSpace>,
+    // protection key rights, live in the register while the task runs
+    pkru: u32
 }
 
 impl fmt::Debug for TaskInner {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "TaskInner {{ context: {:?}, entry: 0x{:x}, stack: {:?} }}",
-               self.context, self.entry as u64, self.stack)
+        write!(fmt, "TaskInner {{ name: {:?}, context: {:?}, entry: 0x{:x}, stack: {:?}, space: {:?}, pkru: 0x{:x} }}",
+               self.name, self.context, self.entry as u64, self.stack, self.space, self.pkru)
     }
 }
 
@@ -145,6 +154,16 @@ fn switch(mut hook: LoadHook) {
     }
 
     unsafe {
+        // tasks sharing an address space skip the reload and keep the TLB
+        if hook.outer.space.root() != hook.inner.space.root() {
+            hook.inner.space.activate();
+        }
+
+        if pkey::enabled() {
+            hook.outer.pkru = pkey::read();
+            pkey::write(hook.inner.pkru);
+        }
+
         // What we're doing doesn't violate borrowing rules because we only use the
         // mutable references to the context fields before calling load_context.
         let hook_ptr = &mut hook as *mut _;
@@ -189,14 +208,28 @@ impl Task {
         }
     }
 
-    pub fn spawn(&self, entry: extern fn(task: Task) -> !, stack: Stack) -> Handle {
+    /// Start a task in the same address space as this one
+    pub fn spawn(&self, name: &'static str, entry: extern fn(task: Task) -> !, stack: Stack) -> Handle {
+        let space = self.inner.borrow().space.clone();
+
+        self.spawn_in(name, entry, stack, space)
+    }
+
+    pub fn spawn_in(&self, name: &'static str, entry: extern fn(task: Task) -> !, stack: Stack,
+                    space: Rc<AddressSpace>) -> Handle {
         let stack_ptr = stack.get_ptr();
 
+        // so an overflow can be blamed on the right task
+        ::stacks::set_owner(stack_ptr, name);
+
         let task = Task {
             inner: Rc::new(RefCell::new(TaskInner {
+                name: name,
                 context: Context::Empty,
                 stack: stack,
-                entry: entry
+                entry: entry,
+                space: space,
+                pkru: pkey::ALLOW_ALL
             })),
             previous: Handle {
                 inner: self.inner.clone()
@@ -223,6 +256,25 @@ impl Task {
         }
     }
 
+    /// Change what this task may do with user pages tagged with key
+    ///
+    /// Takes effect right away, since the task is the one running.
+    pub fn set_access(&mut self, key: u8, access: Access) {
+        if pkey::enabled() {
+            unsafe {
+                pkey::write(pkey::with_access(pkey::read(), key, access));
+            }
+        }
+    }
+
+    pub fn access(&self, key: u8) -> Access {
+        if pkey::enabled() {
+            pkey::access(unsafe { pkey::read() }, key)
+        } else {
+            Access::ReadWrite
+        }
+    }
+
     pub fn yield_back(&mut self) {
         // these locks need to be unlocked after the context switch
         let hook = LoadHook {
@@ -247,9 +299,21 @@ impl Task {
 impl TaskInner {
     unsafe fn empty() -> TaskInner {
         TaskInner {
+            name: "kernel",
             context: Context::Empty,
             entry: empty_entry,
-            stack: Stack::empty()
+            stack: Stack::empty(),
+            space: Rc::new(AddressSpace::kernel()),
+            pkru: pkey::ALLOW_ALL
         }
     }
 }
+
+impl Handle {
+    /// Change what the task may do with user pages tagged with key, once it runs again
+    pub fn set_access(&mut self, key: u8, access: Access) {
+        let mut inner = self.inner.borrow_mut();
+
+        inner.pkru = pkey::with_access(inner.pkru, key, access);
+    }
+}
@@ -0,0 +1,151 @@
+use collections::Vec;
+
+use spin::Mutex;
+
+use kernel_std::cpu::idt;
+
+use c;
+
+use super::interrupt::{Context, without_interrupts};
+use super::apic;
+
+/// Called with the saved registers whenever its vector fires
+pub type Handler = fn(&mut Context);
+
+/// Vectors below this are exceptions, which have handlers of their own
+pub const FIRST_VECTOR: u8 = 32;
+
+const VECTOR_COUNT: usize = 256 - FIRST_VECTOR as usize;
+
+struct Vector {
+    allocated: bool,
+    // every handler on a shared line runs, each checks its own device
+    handlers: Vec<Handler>
+}
+
+// interrupts are disabled whenever this is held outside the dispatcher,
+// so the dispatcher never spins on it
+static VECTORS: Mutex<Option<Vec<Vector>>> = Mutex::new(None);
+
+fn index(vector: u8) -> usize {
+    assert!(vector >= FIRST_VECTOR, "Vector {} is an exception", vector);
+
+    (vector - FIRST_VECTOR) as usize
+}
+
+fn with_vectors<T, F: FnOnce(&mut Vec<Vector>) -> T>(f: F) -> T {
+    without_interrupts(|| {
+        let mut vectors = VECTORS.lock();
+
+        f(vectors.as_mut().expect("Interrupt vectors were not set up"))
+    })
+}
+
+/// Claim a vector nothing else uses
+pub fn allocate() -> Option<u8> {
+    with_vectors(|vectors| {
+        for (idx, vector) in vectors.iter_mut().enumerate() {
+            if !vector.allocated {
+                vector.allocated = true;
+
+                return Some(FIRST_VECTOR + idx as u8);
+            }
+        }
+
+        None
+    })
+}
+
+/// Claim a specific vector, returning false if it was already taken
+pub fn reserve(vector: u8) -> bool {
+    with_vectors(|vectors| {
+        let entry = &mut vectors[index(vector)];
+
+        if entry.allocated {
+            false
+        } else {
+            entry.allocated = true;
+            true
+        }
+    })
+}
+
+/// Add handler to the handlers run for vector
+///
+/// Handlers run in the order they were registered. A handler must not
+/// register or unregister handlers itself.
+pub fn register(vector: u8, handler: Handler) {
+    with_vectors(|vectors| {
+        let entry = &mut vectors[index(vector)];
+
+        entry.allocated = true;
+        entry.handlers.push(handler);
+    })
+}
+
+/// Remove handler from vector, returning whether it was registered
+pub fn unregister(vector: u8, handler: Handler) -> bool {
+    with_vectors(|vectors| {
+        let entry = &mut vectors[index(vector)];
+        let count = entry.handlers.len();
+
+        entry.handlers.retain(|other| *other as usize != handler as usize);
+
+        entry.handlers.len() != count
+    })
+}
+
+/// Whether vector has any handlers registered
+pub fn is_handled(vector: u8) -> bool {
+    with_vectors(|vectors| !vectors[index(vector)].handlers.is_empty())
+}
+
+/// Give vector back, dropping any handlers still registered for it
+pub fn free(vector: u8) {
+    with_vectors(|vectors| {
+        let entry = &mut vectors[index(vector)];
+
+        entry.allocated = false;
+        entry.handlers.clear();
+    })
+}
+
+/// Run the handlers for vector and acknowledge it, called by the interrupt stubs
+pub fn dispatch(vector: u8, context: &mut Context) {
+    let vectors = VECTORS.lock();
+
+    let handlers = match vectors.as_ref() {
+        Some(vectors) => &vectors[index(vector)].handlers,
+        None => return
+    };
+
+    if handlers.is_empty() {
+        warn!("Unhandled interrupt on vector {}", vector);
+    }
+
+    for &handler in handlers.iter() {
+        handler(context);
+    }
+
+    if apic::needs_eoi(vector) {
+        apic::eoi();
+    }
+}
+
+/// Point every vector above the exceptions at the dispatcher
+pub fn setup(idt: &mut idt::Table) {
+    let mut vectors = Vec::with_capacity(VECTOR_COUNT);
+
+    for idx in 0..VECTOR_COUNT {
+        vectors.push(Vector {
+            allocated: false,
+            handlers: vec![]
+        });
+
+        let target = unsafe { c::_interrupt_handlers[idx] };
+
+        idt.insert(FIRST_VECTOR + idx as u8, idt::Descriptor::new(target, 0));
+    }
+
+    *VECTORS.lock() = Some(vectors);
+}
@@ -0,0 +1,263 @@
+use std::ptr::Shared;
+
+use std::fmt::Write;
+
+use std::mem;
+use std::ptr;
+
+use spin::Mutex;
+
+use constants::*;
+
+use kernel_std::{BootProto, Region};
+
+use paging;
+use memory;
+use memory::FrameSize;
+use serial;
+
+static TABLES: Mutex<Option<TableWindow>> = Mutex::new(None);
+
+/// Page tables the kernel can reach
+///
+/// Boot's tables sit in the region it mapped at PAGE_TABLES_BEGIN. New tables
+/// are frames mapped at TABLE_MAP_BEGIN plus their physical address, and go
+/// back to the frame allocator when released. The tables of that mapping
+/// come out of the boot region, which has to be big enough for them.
+pub struct TableWindow {
+    physical: Region,
+    used: u64,
+    // released tables in the boot region, linked through their first entry
+    free: u64,
+    // set while a new table gets mapped
+    mapping: bool
+}
+
+impl paging::Base for TableWindow {
+    fn to_physical(&self, address: u64) -> Option<u64> {
+        if address >= PAGE_TABLES_BEGIN && address - PAGE_TABLES_BEGIN < self.physical.size() {
+            Some(address - PAGE_TABLES_BEGIN + self.physical.base())
+        } else if address >= TABLE_MAP_BEGIN && address - TABLE_MAP_BEGIN < TABLE_MAP_SIZE as u64 {
+            Some(address - TABLE_MAP_BEGIN)
+        } else {
+            None
+        }
+    }
+
+    fn to_virtual(&self, address: u64) -> Option<u64> {
+        if address >= self.physical.base() && address < self.physical.end() {
+            Some(address - self.physical.base() + PAGE_TABLES_BEGIN)
+        } else if address < TABLE_MAP_SIZE as u64 {
+            Some(address + TABLE_MAP_BEGIN)
+        } else {
+            None
+        }
+    }
+
+    unsafe fn new_table(&mut self) -> Shared<paging::Table> {
+        // tables for the table map can't wait for the table map
+        let ptr = if self.mapping {
+            self.boot_table()
+        } else {
+            match self.mapped_table() {
+                Some(ptr) => ptr,
+                None => self.boot_table()
+            }
+        };
+
+        ptr::write(*ptr, paging::Table::new());
+        ptr
+    }
+
+    unsafe fn release_table(&mut self, table: Shared<paging::Table>) {
+        let address = *table as u64;
+
+        if address >= TABLE_MAP_BEGIN && address - TABLE_MAP_BEGIN < TABLE_MAP_SIZE as u64 {
+            paging::Mapper::active(self).unmap(address, 0x1000).expect("Failed to unmap released page table");
+
+            memory::release_frame(address - TABLE_MAP_BEGIN, FrameSize::Page)
+                .expect("Failed to release page table frame");
+        } else {
+            assert!(address >= PAGE_TABLES_BEGIN && address - PAGE_TABLES_BEGIN < self.used,
+                    "Released page table 0x{:x} was never handed out", address);
+
+            ptr::write(address as *mut u64, self.free);
+            self.free = address;
+        }
+    }
+
+    fn clear(&mut self) {
+        panic!("Tried to clear the live page tables");
+    }
+}
+
+impl TableWindow {
+    fn new(physical: Region, used: u64) -> TableWindow {
+        TableWindow {
+            physical: physical,
+            used: used,
+            free: 0,
+            mapping: false
+        }
+    }
+
+    unsafe fn boot_table(&mut self) -> Shared<paging::Table> {
+        if self.free != 0 {
+            let ptr = Shared::new(self.free as *mut paging::Table);
+            self.free = ptr::read(self.free as *const u64);
+            return ptr;
+        }
+
+        let size = mem::size_of::<paging::Table>() as u64;
+
+        assert!(self.used + size <= self.physical.size(), "Ran out of space for page tables");
+
+        let ptr = Shared::new((PAGE_TABLES_BEGIN + self.used) as *mut paging::Table);
+        self.used += size;
+        ptr
+    }
+
+    unsafe fn mapped_table(&mut self) -> Option<Shared<paging::Table>> {
+        let frame = match memory::allocate_frame(FrameSize::Page) {
+            Ok(frame) => frame,
+            Err(_) => return None
+        };
+
+        let mapped = frame < TABLE_MAP_SIZE as u64 && {
+            self.mapping = true;
+
+            let mapped = paging::Mapper::active(self)
+                .map(frame + TABLE_MAP_BEGIN, frame, 0x1000, paging::Flags::kernel_data()).is_ok();
+
+            self.mapping = false;
+            mapped
+        };
+
+        if !mapped {
+            memory::release_frame(frame, FrameSize::Page).expect("Failed to release page table frame");
+            return None;
+        }
+
+        Some(Shared::new((frame + TABLE_MAP_BEGIN) as *mut paging::Table))
+    }
+}
+
+unsafe fn map_heap(virtual_address: usize, physical_address: u64, size: usize) -> bool {
+    let mut tables = TABLES.lock();
+
+    let tables = match tables.as_mut() {
+        Some(tables) => tables,
+        None => return false
+    };
+
+    paging::Mapper::active(tables).map(virtual_address as u64, physical_address, size as u64, paging::Flags::kernel_data()).is_ok()
+}
+
+/// Run f on the live page table region
+pub fn with_tables<F, R>(f: F) -> R where F: FnOnce(&mut TableWindow) -> R {
+    let mut tables = TABLES.lock();
+
+    f(tables.as_mut().expect("Page tables were not set up"))
+}
+
+/// Run f on the live page table region, unless someone else is using it
+pub fn try_with_tables<F, R>(f: F) -> Option<R> where F: FnOnce(&mut TableWindow) -> R {
+    if let Some(mut tables) = TABLES.try_lock() {
+        if let Some(tables) = tables.as_mut() {
+            return Some(f(tables));
+        }
+    }
+
+    None
+}
+
+/// Let the heap grow into the rest of its virtual region
+pub unsafe fn setup(proto: &BootProto) {
+    *TABLES.lock() = Some(TableWindow::new(proto.page_tables(), proto.page_tables_used()));
+
+    memory::set_grow((HEAP_BEGIN as usize + OPTIMISTIC_HEAP_SIZE) as *mut u8,
+                     (HEAP_BEGIN as usize + HEAP_SIZE) as *mut u8,
+                     map_heap);
+}
+
+/// Print heap usage to serial
+pub fn print_stats() {
+    // callers may be interrupting the allocator
+    match memory::try_stats() {
+        Some(stats) => {
+            let _ = writeln!(serial::Writer, "Memory usage:\n{}", stats);
+        },
+        None => {
+            let _ = writeln!(serial::Writer, "Memory usage: heap busy");
+        }
+    }
+}
+
+/// Print every live mapping to serial
+pub fn print_mappings() {
+    // may be called from a fault while the tables are being edited
+    if let Some(tables) = TABLES.try_lock() {
+        if let Some(tables) = tables.as_ref() {
+            let _ = writeln!(serial::Writer, "Mappings:");
+
+            unsafe {
+                match paging::Walker::active(tables) {
+                    Some(walker) => walker.mappings(|mapping| {
+                        let _ = writeln!(serial::Writer, "{}", mapping);
+                    }),
+                    None => {
+                        let _ = writeln!(serial::Writer, "Root table is outside the table window");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Print the page table entries used to translate an address to serial
+pub fn print_path(address: u64) {
+    if let Some(tables) = TABLES.try_lock() {
+        if let Some(tables) = tables.as_ref() {
+            let _ = writeln!(serial::Writer, "Page walk for 0x{:x}:", address);
+
+            unsafe {
+                match paging::Walker::active(tables) {
+                    Some(walker) => {
+                        let result = walker.path(address, |level, idx, entry| {
+                            let _ = writeln!(serial::Writer, "{:?}[0x{:x}]: {:?}", level, idx, entry);
+                        });
+
+                        if let Err(table) = result {
+                            let _ = writeln!(serial::Writer, "Table at 0x{:x} is outside the table window", table);
+                        }
+                    },
+                    None => {
+                        let _ = writeln!(serial::Writer, "Root table is outside the table window");
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// The protection key of the page holding address, if it's mapped
+pub fn protection_key(address: u64) -> Option<u8> {
+    let mut key = None;
+
+    if let Some(tables) = TABLES.try_lock() {
+        if let Some(tables) = tables.as_ref() {
+            unsafe {
+                if let Some(walker) = paging::Walker::active(tables) {
+                    // an unreachable table just means no key
+                    let _ = walker.path(address, |level, _, entry| {
+                        if entry.present() && entry.is_page(level) {
+                            key = Some(entry.protection_key(level));
+                        }
+                    });
+                }
+            }
+        }
+    }
+
+    key
+}
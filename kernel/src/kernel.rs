@@ -30,21 +30,32 @@ extern crate memory;
 
 use std::mem;
 
-use kernel_std::BootProto;
+use alloc::rc::Rc;
+
+use kernel_std::{BootProto, Region, Allocator};
 use constants::*;
 
 mod c;
 mod cpu;
+mod heap;
 mod logging;
+mod space;
+mod stacks;
+mod mmio;
+mod acpi;
 
 // pub use since we want to export
 #[cfg(not(test))]
 pub use cpu::interrupt::{interrupt_breakpoint,
                          interrupt_general_protection_fault,
                          interrupt_page_fault,
+                         interrupt_double_fault,
                          early_interrupt_breakpoint,
                          early_interrupt_general_protection_fault,
-                         early_interrupt_page_fault};
+                         early_interrupt_page_fault,
+                         interrupt_exception,
+                         early_interrupt_exception,
+                         interrupt_dispatch};
 
 pub use cpu::task::load_context;
 
@@ -52,11 +63,38 @@ pub use cpu::task::load_context;
 extern "C" fn test_task(mut task: cpu::task::Task) -> ! {
     info!("Hello from a task!");
 
+    // the first touch faults in a zeroed page
+    let scratch = TASK_BEGIN as *mut u64;
+
+    unsafe {
+        assert!(*scratch == 0, "Lazy page was not zeroed");
+        *scratch = 0x1;
+    }
+
     task.yield_back();
 
     unreachable!("task exited");
 }
 
+#[cfg(all(not(test), feature = "overflow_test"))]
+#[allow(unconditional_recursion)]
+fn recurse(depth: u64) -> u64 {
+    let frame = [depth; 64];
+
+    recurse(depth + 1) + frame[(depth % 64) as usize]
+}
+
+#[cfg(all(not(test), feature = "overflow_test"))]
+extern "C" fn overflow_task(_: cpu::task::Task) -> ! {
+    info!("Overflowing the stack on purpose");
+
+    // the page fault on the guard page can't push its frame, so this should
+    // end in a double fault report naming this task
+    recurse(0);
+
+    unreachable!("Stack overflow went unnoticed");
+}
+
 #[no_mangle]
 #[naked]
 pub unsafe extern "C" fn _start() -> ! {
@@ -111,6 +149,21 @@ pub extern "C" fn kernel_main(boot_proto: u64) -> ! {
     // exit reserve memory
     memory::exit_reserved();
 
+    // hand the rest of physical memory to the frame allocator
+    setup_frames(&proto);
+
+    // boot may have used five-level tables
+    unsafe { space::setup() };
+
+    // let the heap grow past the optimistic heap
+    unsafe { heap::setup(&proto) };
+
+    // put stacks above guard pages
+    unsafe { stacks::setup() };
+
+    // device registers get mapped on demand
+    unsafe { mmio::setup() };
+
     // set up cpu data structures and other settings
     // keep references around so we don't break things
     let (gdt, idt) = unsafe {cpu::init::setup()};
@@ -119,18 +172,82 @@ pub extern "C" fn kernel_main(boot_proto: u64) -> ! {
     mem::forget(gdt);
     mem::forget(idt);
 
+    // let tasks revoke access to key-tagged user pages
+    if unsafe { cpu::pkey::enable() } {
+        info!("Protection keys enabled");
+    }
+
+    // start the timer, which is the first thing that can interrupt us,
+    // and find where device interrupts come in
+    unsafe {
+        if !cpu::apic::setup() {
+            warn!("The PIT never fired, running without a timer");
+        }
+
+        cpu::irq::setup(&proto);
+        cpu::interrupt::enable();
+    }
+
     // we're done with setup
     cpu::init::setup_done();
 
+    // report what setup used
+    heap::print_stats();
+
     info!("Starting tasks");
 
     // start some tasks
     let mut kernel_task = unsafe { cpu::task::Task::empty() };
 
-    let mut new_task = kernel_task.spawn(test_task, kernel_std::cpu::stack::Stack::new(0xf000));
+    #[cfg(feature = "overflow_test")]
+    {
+        let mut overflow = kernel_task.spawn("overflow", overflow_task, kernel_std::cpu::stack::Stack::new(0xf000));
+
+        kernel_task.switch(&mut overflow);
+    }
+
+    // the test task gets tables of its own, with a lazy scratch segment in the lower half
+    let mut task_layout = paging::Layout::new();
+
+    assert!(task_layout.insert(paging::Segment::lazy(TASK_BEGIN as u64, 0x100000,
+                                                      true, false, false, false)));
+
+    let task_space = Rc::new(unsafe { space::AddressSpace::new(task_layout) });
+
+    let mut new_task = kernel_task.spawn_in("test", test_task, kernel_std::cpu::stack::Stack::new(0xf000), task_space);
 
     kernel_task.switch(&mut new_task);
 
     unreachable!("kernel_main tried to return");
 }
 
+
+#[cfg(not(test))]
+fn setup_frames(proto: &BootProto) {
+    let mut free = Allocator::new();
+
+    for region in proto.memory().available() {
+        free.register(*region);
+    }
+
+    for module in proto.modules() {
+        // the kernel image is backed by its module
+        free.forget(module.memory());
+    }
+
+    for region in proto.memory().allocated() {
+        // optimistic heap, boot page tables and zero-filled segments
+        free.forget(*region);
+    }
+
+    // boot doesn't allocate anything below 0x200000 either
+    free.forget(Region::new(0x0, 0x200000));
+
+    let mut total = 0;
+
+    for region in free.available() {
+        total += unsafe { memory::register_frames(region.base(), region.size()) };
+    }
+
+    info!("Registered 0x{:x} bytes of physical memory", total);
+}
@@ -0,0 +1,61 @@
+use spin::Mutex;
+
+use constants::*;
+
+use kernel_std::{Allocator, Region};
+
+use paging;
+
+use heap;
+
+static FREE: Mutex<Option<Allocator>> = Mutex::new(None);
+
+/// Map size bytes of device memory at physical into the kernel half
+///
+/// Returns the virtual address of physical, which keeps its offset into the page.
+pub unsafe fn map(physical: u64, size: u64, cache: paging::Cache) -> Option<u64> {
+    let base = align_back(physical, 0x1000);
+    let size = align(physical + size, 0x1000) - base;
+
+    let region = match FREE.lock().as_mut().and_then(|free| free.allocate(size, 0x1000)) {
+        Some(region) => region,
+        None => return None
+    };
+
+    let flags = paging::Flags {
+        cache: cache,
+        ..paging::Flags::kernel_data()
+    };
+
+    if heap::with_tables(|tables| paging::Mapper::active(tables).map(region.base(), base, size, flags)).is_err() {
+        if let Some(free) = FREE.lock().as_mut() {
+            free.release(region);
+        }
+
+        return None;
+    }
+
+    Some(region.base() + physical - base)
+}
+
+/// Unmap a mapping of size bytes that map returned address for
+pub unsafe fn unmap(address: u64, size: u64) {
+    let base = align_back(address, 0x1000);
+    let size = align(address + size, 0x1000) - base;
+
+    heap::with_tables(|tables| paging::Mapper::active(tables).unmap(base, size))
+        .expect("Failed to unmap device memory");
+
+    if let Some(free) = FREE.lock().as_mut() {
+        assert!(free.release(Region::new(base, size)), "Unmapped device memory twice");
+    }
+}
+
+/// Place device mappings in their own region from now on
+pub unsafe fn setup() {
+    let mut free = Allocator::new();
+
+    assert!(free.register(Region::new(MMIO_BEGIN, MMIO_SIZE as u64)), "Failed to register MMIO region");
+
+    *FREE.lock() = Some(free);
+}
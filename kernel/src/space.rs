@@ -0,0 +1,314 @@
+use std::sync::atomic::{Ordering, AtomicUsize};
+
+use std::fmt;
+use std::ptr;
+use std::ptr::Shared;
+
+use spin::Mutex;
+
+use constants::*;
+
+use paging;
+use paging::{Base, Builder, Layout, Table, Level, Shares};
+
+use memory;
+use memory::FrameSize;
+
+use heap;
+
+// the address space last loaded into CR3, kept alive by the task running in it
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+// mappings of copy-on-write frames across every address space
+static SHARES: Mutex<Option<Shares>> = Mutex::new(None);
+
+// root table entries from here on map the kernel half
+const KERNEL_HALF: usize = 0x100;
+
+/// Page tables a task runs on
+///
+/// The layout describes the lower half only. The kernel half is shared with
+/// every other address space by pointing at the same tables below the root,
+/// so kernel mappings made later show up everywhere, as long as they don't
+/// need a new root entry.
+///
+/// Lazy segments in the layout get a zeroed frame on first touch. Dropping
+/// the address space gives those frames back.
+///
+/// Copy-on-write segments map their frames read-only, so several address
+/// spaces can share them until one writes. Dropping the address space gives
+/// back its share of every frame it never wrote to, and the frames of pages
+/// it did write to. Frames nobody maps anymore go back to the frame
+/// allocator, so copy-on-write segments have to be backed by its frames.
+pub struct AddressSpace {
+    layout: Layout,
+    root: u64,
+    // boot's tables are never taken apart
+    kernel: bool
+}
+
+impl fmt::Debug for AddressSpace {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "AddressSpace {{ root: 0x{:x}, segments: {} }}",
+               self.root, self.layout.segments().count())
+    }
+}
+
+#[inline]
+unsafe fn current_root() -> u64 {
+    let cr3: u64;
+
+    asm!("mov $0, cr3" : "=r"(cr3) ::: "intel");
+
+    cr3 & PAGE_ADDR_MASK
+}
+
+/// Walk tables as deep as the ones boot set up
+pub unsafe fn setup() {
+    let cr4: u64;
+
+    asm!("mov $0, cr4" : "=r"(cr4) ::: "intel");
+
+    paging::set_five_level(cr4 & 1 << 12 != 0);
+
+    let cpuid_d: u32;
+
+    asm!("cpuid" : "={edx}"(cpuid_d) : "{eax}"(0x80000001) : "ebx", "ecx" : "intel");
+
+    paging::set_huge_pages(cpuid_d & 1 << 26 != 0);
+}
+
+impl AddressSpace {
+    /// The tables boot set up, which the kernel runs on
+    pub unsafe fn kernel() -> AddressSpace {
+        AddressSpace {
+            layout: Layout::new(),
+            root: current_root(),
+            kernel: true
+        }
+    }
+
+    /// Build a layout of lower-half segments on top of the kernel half
+    ///
+    /// The root and the lower-half tables are released on drop.
+    pub unsafe fn new(mut layout: Layout) -> AddressSpace {
+        for segment in layout.segments() {
+            assert!(segment.virtual_base() + segment.size() <= 1 << (Level::root().canonical_bits() - 1),
+                    "Segment reached into the kernel half: {:?}", segment);
+        }
+
+        let root = heap::with_tables(|tables| {
+            let kernel = tables.to_virtual(current_root())
+                .expect("Root page table was outside the page table region")
+                as *mut Table;
+
+            let root = tables.new_table();
+
+            for idx in KERNEL_HALF..0x200 {
+                let entry = kernel.as_mut().unwrap().read(idx);
+                root.as_mut().unwrap().write(entry, idx);
+            }
+
+            Builder::at(tables, root).build(&mut layout)
+        });
+
+        let mut shares = SHARES.lock();
+
+        if shares.is_none() {
+            *shares = Some(Shares::new());
+        }
+
+        for segment in layout.segments() {
+            shares.as_mut().unwrap().share_segment(segment);
+        }
+
+        AddressSpace {
+            layout: layout,
+            root: root,
+            kernel: false
+        }
+    }
+
+    /// Physical address of the root table
+    #[inline]
+    pub fn root(&self) -> u64 {
+        self.root
+    }
+
+    /// Load into CR3, which flushes every non-global TLB entry
+    pub unsafe fn activate(&self) {
+        ACTIVE.store(self as *const AddressSpace as usize, Ordering::Relaxed);
+
+        asm!("mov cr3, $0" :: "r"(self.root) : "memory" : "intel", "volatile");
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        ACTIVE.compare_and_swap(self as *const AddressSpace as usize, 0, Ordering::Relaxed);
+
+        if self.kernel {
+            return;
+        }
+
+        // pages written to already gave their share back in write_fault
+        let mut guard = SHARES.lock();
+        let shares = guard.as_mut().expect("Address space was built without shares");
+
+        let root = self.root;
+        let layout = &self.layout;
+
+        heap::with_tables(|tables| unsafe {
+            let root = Shared::new(tables.to_virtual(root)
+                .expect("Root page table was outside the page table region")
+                as *mut Table);
+
+            {
+                let mut mapper = paging::Mapper::at(tables, root);
+
+                for segment in layout.segments().filter(|segment| segment.copy_on_write() && segment.allocate()) {
+                    let mut offset = 0;
+
+                    while offset < segment.size() {
+                        let page = segment.virtual_base() + offset;
+
+                        if let Some(frame) = mapper.shared_frame(page) {
+                            if shares.release(frame) == 0 {
+                                memory::release_frame(frame, FrameSize::Page).expect("Failed to release shared frame");
+                            }
+                        } else if let Some(frame) = mapper.translate(page) {
+                            // written to, so the frame is ours alone
+                            memory::release_frame(frame, FrameSize::Page).expect("Failed to release written frame");
+                        }
+
+                        offset += 0x1000;
+                    }
+                }
+
+                // whatever fault backed lazy pages with
+                for segment in layout.segments().filter(|segment| !segment.allocate()) {
+                    let mut offset = 0;
+
+                    while offset < segment.size() {
+                        if let Some(frame) = mapper.translate(segment.virtual_base() + offset) {
+                            memory::release_frame(frame, FrameSize::Page).expect("Failed to release lazy frame");
+                        }
+
+                        offset += 0x1000;
+                    }
+                }
+
+                // the kernel half is everyone's
+                mapper.release_tables(0..KERNEL_HALF);
+            }
+
+            tables.release_table(root);
+        });
+    }
+}
+
+/// Back the page holding address if a lazy segment of the active address space covers it
+///
+/// Returns false if nothing covers it, or if the page could not be mapped.
+pub unsafe fn fault(address: u64) -> bool {
+    let space = match (ACTIVE.load(Ordering::Relaxed) as *const AddressSpace).as_ref() {
+        Some(space) => space,
+        None => return false
+    };
+
+    let segment = match space.layout.segment(address) {
+        Some(segment) if !segment.allocate() => segment.clone(),
+        _ => return false
+    };
+
+    let page = align_back(address, 0x1000);
+
+    let frame = match memory::allocate_frame(FrameSize::Page) {
+        Ok(frame) => frame,
+        Err(_) => return false
+    };
+
+    // the fault may have interrupted someone editing the tables
+    let mapped = heap::try_with_tables(|tables| {
+        let mut mapper = paging::Mapper::active(tables);
+
+        // map writable first so the frame can be cleared through its new address
+        if mapper.map(page, frame, 0x1000, paging::Flags::kernel_data()).is_err() {
+            return false;
+        }
+
+        ptr::write_bytes(page as *mut u8, 0, 0x1000);
+
+        mapper.protect(page, 0x1000, paging::Flags::from(&segment)).is_ok()
+    });
+
+    if mapped != Some(true) {
+        memory::release_frame(frame, FrameSize::Page).expect("Failed to release lazy frame");
+        return false;
+    }
+
+    true
+}
+
+/// Give the copy-on-write page holding address a frame it can write to
+///
+/// The page keeps its frame if nothing else maps it anymore, otherwise it gets
+/// a copy. Returns false if the page is not copy-on-write, or if it could not
+/// be remapped.
+pub unsafe fn write_fault(address: u64) -> bool {
+    let page = align_back(address, 0x1000);
+
+    let mut shares = match SHARES.try_lock() {
+        Some(shares) => shares,
+        None => return false
+    };
+
+    let shares = match shares.as_mut() {
+        Some(shares) => shares,
+        None => return false
+    };
+
+    let unshared = heap::try_with_tables(|tables| {
+        let mut mapper = paging::Mapper::active(tables);
+
+        let frame = match mapper.shared_frame(page) {
+            Some(frame) => frame,
+            None => return false
+        };
+
+        if shares.release(frame) == 0 {
+            // the last one to write keeps the frame
+            return mapper.unshare(page, frame).is_ok();
+        }
+
+        let copy = match memory::allocate_frame(FrameSize::Page) {
+            Ok(copy) => copy,
+            Err(_) => {
+                shares.share(frame);
+                return false;
+            }
+        };
+
+        // the copy has no mapping yet, so fill it through the copy window,
+        // which holding SHARES keeps to ourselves
+        if mapper.map(COPY_WINDOW, copy, 0x1000, paging::Flags::kernel_data()).is_err() {
+            memory::release_frame(copy, FrameSize::Page).expect("Failed to release copied frame");
+            shares.share(frame);
+            return false;
+        }
+
+        ptr::copy_nonoverlapping(page as *const u8, COPY_WINDOW as *mut u8, 0x1000);
+
+        mapper.unmap(COPY_WINDOW, 0x1000).expect("Failed to unmap the copy window");
+
+        if mapper.unshare(page, copy).is_err() {
+            memory::release_frame(copy, FrameSize::Page).expect("Failed to release copied frame");
+            shares.share(frame);
+            return false;
+        }
+
+        true
+    });
+
+    unshared == Some(true)
+}
@@ -0,0 +1,164 @@
+use collections::Vec;
+
+use spin::Mutex;
+
+use constants::*;
+
+use kernel_std::{Allocator, Region};
+use kernel_std::cpu::stack;
+
+use memory;
+use memory::FrameSize;
+use paging;
+
+use heap;
+
+static STACKS: Mutex<Option<Stacks>> = Mutex::new(None);
+
+// left unmapped below every stack
+const GUARD_SIZE: u64 = 0x1000;
+
+const STACK_FLAGS: paging::Flags = paging::Flags::kernel_data();
+
+// A placed stack and the guard page under it
+struct Slot {
+    guard: u64,
+    top: u64,
+    owner: Option<&'static str>
+}
+
+struct Stacks {
+    free: Allocator,
+    slots: Vec<Slot>
+}
+
+/// A fault in the guard page of a stack
+#[derive(Debug, Clone, Copy)]
+pub struct Overflow {
+    pub top: u64,
+    pub owner: Option<&'static str>
+}
+
+unsafe fn unmap(bottom: u64, size: u64) {
+    heap::with_tables(|tables| {
+        let mut mapper = paging::Mapper::active(tables);
+        let mut offset = 0;
+
+        while offset < size {
+            let frame = mapper.translate(bottom + offset).expect("Stack page was not mapped");
+
+            mapper.unmap(bottom + offset, 0x1000).expect("Failed to unmap stack page");
+            memory::release_frame(frame, FrameSize::Page).expect("Failed to release stack frame");
+
+            offset += 0x1000;
+        }
+    });
+}
+
+unsafe fn place_stack(size: usize) -> Option<*mut u8> {
+    let size = align(size as u64, 0x1000);
+
+    let mut stacks = STACKS.lock();
+
+    let stacks = match stacks.as_mut() {
+        Some(stacks) => stacks,
+        None => return None
+    };
+
+    let region = match stacks.free.allocate(size + GUARD_SIZE, 0x1000) {
+        Some(region) => region,
+        None => return None
+    };
+
+    let bottom = region.base() + GUARD_SIZE;
+    let mut mapped = 0;
+
+    while mapped < size {
+        let frame = match memory::allocate_frame(FrameSize::Page) {
+            Ok(frame) => frame,
+            Err(_) => break
+        };
+
+        if heap::with_tables(|tables| {
+            paging::Mapper::active(tables).map(bottom + mapped, frame, 0x1000, STACK_FLAGS)
+        }).is_err() {
+            memory::release_frame(frame, FrameSize::Page).expect("Failed to release stack frame");
+            break;
+        }
+
+        mapped += 0x1000;
+    }
+
+    if mapped < size {
+        unmap(bottom, mapped);
+        stacks.free.release(region);
+
+        return None;
+    }
+
+    stacks.slots.push(Slot {
+        guard: region.base(),
+        top: bottom + size,
+        owner: None
+    });
+
+    Some(bottom as *mut u8)
+}
+
+unsafe fn free_stack(bottom: *mut u8, size: usize) {
+    let size = align(size as u64, 0x1000);
+    let bottom = bottom as u64;
+
+    unmap(bottom, size);
+
+    if let Some(stacks) = STACKS.lock().as_mut() {
+        stacks.slots.retain(|slot| slot.top != bottom + size);
+
+        assert!(stacks.free.release(Region::new(bottom - GUARD_SIZE, size + GUARD_SIZE)),
+                "Released a stack that was never placed");
+    }
+}
+
+/// Record which task runs on the stack ending at top
+pub fn set_owner(top: *mut u8, owner: &'static str) {
+    if let Some(stacks) = STACKS.lock().as_mut() {
+        for slot in stacks.slots.iter_mut() {
+            if slot.top == top as u64 {
+                slot.owner = Some(owner);
+            }
+        }
+    }
+}
+
+/// The stack whose guard page holds address, if any
+pub fn overflow(address: u64) -> Option<Overflow> {
+    // called from the page fault handler, which may have interrupted a stack being placed
+    if let Some(stacks) = STACKS.try_lock() {
+        if let Some(stacks) = stacks.as_ref() {
+            for slot in stacks.slots.iter() {
+                if address >= slot.guard && address < slot.guard + GUARD_SIZE {
+                    return Some(Overflow {
+                        top: slot.top,
+                        owner: slot.owner
+                    });
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// Place stacks in their own region from now on
+pub unsafe fn setup() {
+    let mut free = Allocator::new();
+
+    assert!(free.register(Region::new(STACKS_BEGIN, STACKS_SIZE as u64)), "Failed to register stack region");
+
+    *STACKS.lock() = Some(Stacks {
+        free: free,
+        slots: vec![]
+    });
+
+    stack::set_hooks(place_stack, free_stack);
+}
@@ -4,7 +4,7 @@ use std::iter::Iterator;
 use std::fmt;
 use std::str;
 
-use collections::BTreeMap;
+use collections::{Vec, BTreeMap};
 
 use constants;
 
@@ -296,6 +296,14 @@ impl Allocator {
         }
     }
 
+    pub fn available(&self) -> Vec<Region> {
+        self.free.iter().cloned().collect()
+    }
+
+    pub fn allocated(&self) -> Vec<Region> {
+        self.used.iter().cloned().collect()
+    }
+
     pub fn release(&mut self, region: Region) -> bool {
         if !self.used.contains(region) {
             return false;
@@ -341,4 +349,22 @@ mod tests {
 
         assert!(allocator.allocate(0x1, 0x1).is_none());
     }
+
+    #[test]
+    fn test_allocated() {
+        let mut allocator = Allocator::new();
+
+        assert!(allocator.register(Region::new(0x200000, 0x200000)));
+
+        let first = allocator.allocate(0x1000, 0x1000).unwrap();
+        let second = allocator.allocate(0x2000, 0x1000).unwrap();
+
+        assert_eq!(allocator.allocated(), vec![first, second]);
+
+        assert!(allocator.release(first));
+
+        assert_eq!(allocator.allocated(), vec![second]);
+        assert_eq!(allocator.available(), vec![Region::new(0x200000, 0x1000),
+                                               Region::new(0x203000, 0x1fd000)]);
+    }
 }
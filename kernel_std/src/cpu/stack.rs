@@ -7,8 +7,28 @@ use alloc::raw_vec::RawVec;
 
 use alloc::heap;
 
+use spin::Mutex;
+
+use constants::align;
+
+/// Maps size bytes of stack above an unmapped guard page, returning the bottom
+pub type PlaceHook = unsafe fn(size: usize) -> Option<*mut u8>;
+
+/// Takes back a stack that PlaceHook placed
+pub type FreeHook = unsafe fn(bottom: *mut u8, size: usize);
+
+static HOOKS: Mutex<Option<(PlaceHook, FreeHook)>> = Mutex::new(None);
+
 pub struct Stack {
-    buffer: Option<RawVec<u8>>
+    buffer: Option<RawVec<u8>>,
+    guarded: Option<Guarded>
+}
+
+// a stack placed by the hooks, which has to go back through them
+struct Guarded {
+    bottom: usize,
+    size: usize,
+    free: FreeHook
 }
 
 impl Debug for Stack {
@@ -16,22 +36,62 @@ impl Debug for Stack {
         if let Some(ref buffer) = self.buffer {
             write!(fmt, "Stack {{ buffer: Some {{ end: 0x{:x} size: 0x{:x} }} }}",
                    self.get_ptr() as usize, buffer.cap())
+        } else if let Some(ref guarded) = self.guarded {
+            write!(fmt, "Stack {{ guarded: Some {{ end: 0x{:x} size: 0x{:x} }} }}",
+                   self.get_ptr() as usize, guarded.size)
         } else {
             write!(fmt, "Stack {{ buffer: None }}")
         }
     }
 }
 
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if let Some(ref guarded) = self.guarded {
+            unsafe { (guarded.free)(guarded.bottom as *mut u8, guarded.size) };
+        }
+    }
+}
+
+/// Place new stacks with place, and give them back with free
+///
+/// Until this is called, or whenever place fails, stacks come from the heap.
+pub unsafe fn set_hooks(place: PlaceHook, free: FreeHook) {
+    *HOOKS.lock() = Some((place, free));
+}
+
 impl Stack {
     pub fn new(size: usize) -> Stack {
+        let hooks = *HOOKS.lock();
+
+        if let Some((place, free)) = hooks {
+            // guarded stacks are mapped in whole pages, and the top has to match
+            let rounded = align(size, 0x1000);
+
+            if let Some(bottom) = unsafe { place(rounded) } {
+                return Stack {
+                    buffer: None,
+                    guarded: Some(Guarded {
+                        bottom: bottom as usize,
+                        size: rounded,
+                        free: free
+                    })
+                };
+            }
+
+            warn!("Could not place a guarded stack of size 0x{:x}, using the heap", size);
+        }
+
         Stack {
             buffer: Some(unsafe { RawVec::from_raw_parts(heap::allocate(size, 16), size) }),
+            guarded: None
         }
     }
 
     pub unsafe fn empty() -> Stack {
         Stack {
-            buffer: None
+            buffer: None,
+            guarded: None
         }
     }
 
@@ -39,6 +99,8 @@ impl Stack {
         if let Some(ref buffer) = self.buffer {
             let size = buffer.cap();
             unsafe { buffer.ptr().offset(size as isize) }
+        } else if let Some(ref guarded) = self.guarded {
+            (guarded.bottom + guarded.size) as *mut u8
         } else {
             ptr::null_mut()
         }
@@ -26,6 +26,7 @@ extern crate collections;
 extern crate spin;
 
 pub use allocator::{Region, Allocator};
+pub use loader::{Piece, Placement, place_segment};
 pub use logging::{ReserveLogger};
 
 use std::marker::PhantomData;
@@ -52,6 +53,7 @@ use constants::*;
 pub mod cpu;
 
 mod allocator;
+mod loader;
 mod logging;
 
 pub trait Error: Debug + Display {
@@ -80,8 +82,13 @@ pub struct ModuleInfo {
 #[derive(Debug)]
 pub struct BootInfo {
     pub log_level: log::LogLevelFilter,
+    pub check_layout: bool,
     pub memory: MemoryInfo,
-    pub modules: Vec<ModuleInfo>
+    pub modules: Vec<ModuleInfo>,
+    /// Physical address of the RSDT or XSDT, zero if the loader gave none
+    pub acpi_root: u64,
+    /// Whether acpi_root is an XSDT, with 64-bit entries
+    pub acpi_extended: bool
 }
 
 #[repr(packed)]
@@ -96,7 +103,8 @@ pub struct MemoryProto {
     reserved: BootSlice<Region>,
     acpi: BootSlice<Region>,
     nvs: BootSlice<Region>,
-    bad: BootSlice<Region>
+    bad: BootSlice<Region>,
+    allocated: BootSlice<Region>
 }
 
 #[repr(packed)]
@@ -104,8 +112,12 @@ pub struct BootProto {
     magic: u64,
     log_level: u64,
     optimistic_heap: u64,
+    page_tables: Region,
+    page_tables_used: u64,
     memory: MemoryProto,
-    modules: BootSlice<ModuleProto>
+    modules: BootSlice<ModuleProto>,
+    acpi_root: u64,
+    acpi_extended: u64
 }
 
 #[repr(packed)]
@@ -222,6 +234,11 @@ fn panic_fmt(msg: fmt::Arguments, file: &'static str, line: u32) -> ! {
 
     error!("PANIC at {}({}): {}", file, line, msg);
 
+    // skip this if the panic happened inside the allocator
+    if let Some(stats) = memory::try_stats() {
+        let _ = writeln!(serial::Writer, "Memory usage:\n{}", stats);
+    }
+
     panic_halt();
 }
 
@@ -331,16 +348,23 @@ impl MemoryProto {
     pub fn bad(&self) -> &'static [Region] {
         self.bad.as_slice()
     }
+
+    /// Memory taken out of the available regions during boot
+    pub fn allocated(&self) -> &'static [Region] {
+        self.allocated.as_slice()
+    }
 }
 
 impl BootProto {
-    pub fn create(info: BootInfo, optimistic_heap: u64) -> BootProto {
+    pub fn create(info: BootInfo, optimistic_heap: u64, allocated: Vec<Region>,
+                  page_tables: Region, page_tables_used: u64) -> BootProto {
         let memory = MemoryProto {
             available: BootSlice::new(info.memory.available),
             reserved: BootSlice::new(info.memory.reserved),
             acpi: BootSlice::new(info.memory.acpi),
             nvs: BootSlice::new(info.memory.nvs),
-            bad: BootSlice::new(info.memory.bad)
+            bad: BootSlice::new(info.memory.bad),
+            allocated: BootSlice::new(allocated)
         };
 
         let mut modules_list = vec![];
@@ -357,8 +381,12 @@ impl BootProto {
             magic: BOOT_INFO_MAGIC,
             log_level: info.log_level as u64,
             optimistic_heap: optimistic_heap,
+            page_tables: page_tables,
+            page_tables_used: page_tables_used,
             memory: memory,
-            modules: modules
+            modules: modules,
+            acpi_root: info.acpi_root,
+            acpi_extended: info.acpi_extended as u64
         }
     }
 
@@ -388,6 +416,16 @@ impl BootProto {
         self.optimistic_heap
     }
 
+    /// Physical region holding the page tables, mapped at PAGE_TABLES_BEGIN
+    pub fn page_tables(&self) -> Region {
+        self.page_tables
+    }
+
+    /// Bytes at the start of page_tables already taken by tables
+    pub fn page_tables_used(&self) -> u64 {
+        self.page_tables_used
+    }
+
     pub fn memory(&self) -> &MemoryProto {
         &self.memory
     }
@@ -395,4 +433,13 @@ impl BootProto {
     pub fn modules(&self) -> &'static [ModuleProto] {
         self.modules.as_slice()
     }
+
+    /// Physical address of the ACPI root table, and whether it's an XSDT
+    pub fn acpi_root(&self) -> Option<(u64, bool)> {
+        if self.acpi_root == 0 {
+            None
+        } else {
+            Some((self.acpi_root, self.acpi_extended != 0))
+        }
+    }
 }
@@ -0,0 +1,234 @@
+use std::cmp;
+
+use constants;
+
+use allocator::{Region, Allocator};
+
+/// Physically contiguous piece of a loadable segment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Piece {
+    pub physical_base: u64,
+    pub virtual_base: u64,
+    pub size: u64
+}
+
+/// Where the pages of one loadable program header end up
+///
+/// `file` is backed directly by the module image, `tail` is fresh memory that
+/// has to be zeroed before use. The first `copy` bytes of `tail` are still
+/// file data, found at `source`, since the page they live on also holds the
+/// start of the zero-filled part.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub file: Option<Piece>,
+    pub tail: Option<Piece>,
+    pub source: u64,
+    pub copy: u64
+}
+
+const PAGE: u64 = 0x1000;
+
+/// Plan the pages for a program header with the given file image and memory size
+///
+/// Returns None if the tail could not be allocated from available.
+pub fn place_segment(file_base: u64, virtual_base: u64, file_size: u64, mem_size: u64,
+                     align: u64, available: &mut Allocator) -> Option<Placement> {
+    assert!(file_size <= mem_size, "Segment had more file data than memory");
+    assert!((file_base ^ virtual_base) & (PAGE - 1) == 0,
+            "Segment file offset and virtual address were not congruent");
+
+    let virtual_top = constants::align_back(virtual_base, PAGE);
+    let physical_top = constants::align_back(file_base, PAGE);
+    let virtual_end = constants::align(virtual_base + mem_size, PAGE);
+
+    if file_size == mem_size {
+        // everything is in the image, map it in place
+        return Some(Placement {
+            file: Some(Piece {
+                physical_base: physical_top,
+                virtual_base: virtual_top,
+                size: virtual_end - virtual_top
+            }),
+            tail: None,
+            source: 0,
+            copy: 0
+        });
+    }
+
+    // whole pages of file data can be used in place, the rest is allocated
+    let split = constants::align_back(virtual_base + file_size, PAGE);
+    let split = cmp::max(split, virtual_top);
+
+    let file = if split > virtual_top {
+        Some(Piece {
+            physical_base: physical_top,
+            virtual_base: virtual_top,
+            size: split - virtual_top
+        })
+    } else {
+        None
+    };
+
+    // keep the requested alignment if the whole segment ends up allocated
+    let tail_align = if file.is_none() {
+        cmp::max(align, PAGE)
+    } else {
+        PAGE
+    };
+
+    let tail_size = virtual_end - split;
+    let region: Region = match available.allocate(tail_size, tail_align) {
+        Some(region) => region,
+        None => return None
+    };
+
+    Some(Placement {
+        file: file,
+        tail: Some(Piece {
+            physical_base: region.base(),
+            virtual_base: split,
+            size: tail_size
+        }),
+        source: physical_top + (split - virtual_top),
+        copy: cmp::max(virtual_base + file_size, split) - split
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use allocator::{Region, Allocator};
+
+    fn available() -> Allocator {
+        let mut allocator = Allocator::new();
+
+        assert!(allocator.register(Region::new(0x800000, 0x200000)));
+
+        allocator
+    }
+
+    #[test]
+    fn test_place_full() {
+        let mut allocator = available();
+
+        let placement = place_segment(0x201000, 0xffffffff80200000, 0x34ddf, 0x34ddf,
+                                      0x1000, &mut allocator).unwrap();
+
+        assert_eq!(placement.file, Some(Piece {
+            physical_base: 0x201000,
+            virtual_base: 0xffffffff80200000,
+            size: 0x35000
+        }));
+        assert_eq!(placement.tail, None);
+        assert_eq!(placement.copy, 0);
+    }
+
+    #[test]
+    fn test_place_empty() {
+        let mut allocator = available();
+
+        let placement = place_segment(0x23f000, 0xffffffff80800000, 0, 0x17648,
+                                      0x200000, &mut allocator).unwrap();
+
+        assert_eq!(placement.file, None);
+        assert_eq!(placement.tail, Some(Piece {
+            physical_base: 0x800000,
+            virtual_base: 0xffffffff80800000,
+            size: 0x18000
+        }));
+        assert_eq!(placement.copy, 0);
+    }
+
+    #[test]
+    fn test_place_partial_page() {
+        let mut allocator = available();
+
+        // data ends in the middle of the third page, bss runs two pages further
+        let placement = place_segment(0x23b000, 0xffffffff80600000, 0x2a10, 0x4a00,
+                                      0x1000, &mut allocator).unwrap();
+
+        assert_eq!(placement.file, Some(Piece {
+            physical_base: 0x23b000,
+            virtual_base: 0xffffffff80600000,
+            size: 0x2000
+        }));
+        assert_eq!(placement.tail, Some(Piece {
+            physical_base: 0x800000,
+            virtual_base: 0xffffffff80602000,
+            size: 0x3000
+        }));
+        assert_eq!(placement.source, 0x23d000);
+        assert_eq!(placement.copy, 0xa10);
+    }
+
+    #[test]
+    fn test_place_page_boundary() {
+        let mut allocator = available();
+
+        // data fills exactly two pages, nothing has to be copied
+        let placement = place_segment(0x23b000, 0x600000, 0x2000, 0x2001,
+                                      0x1000, &mut allocator).unwrap();
+
+        assert_eq!(placement.file.unwrap().size, 0x2000);
+        assert_eq!(placement.tail.unwrap().virtual_base, 0x602000);
+        assert_eq!(placement.tail.unwrap().size, 0x1000);
+        assert_eq!(placement.copy, 0);
+    }
+
+    #[test]
+    fn test_place_unaligned_start() {
+        let mut allocator = available();
+
+        // segment starts and ends in the same page
+        let placement = place_segment(0x23b123, 0x600123, 0x10, 0x33,
+                                      0x10, &mut allocator).unwrap();
+
+        assert_eq!(placement.file, None);
+        assert_eq!(placement.tail, Some(Piece {
+            physical_base: 0x800000,
+            virtual_base: 0x600000,
+            size: 0x1000
+        }));
+        assert_eq!(placement.source, 0x23b000);
+        assert_eq!(placement.copy, 0x133);
+    }
+
+    #[test]
+    fn test_place_unaligned_span() {
+        let mut allocator = available();
+
+        // odd start, data crosses a page, tail crosses another
+        let placement = place_segment(0x23bff8, 0x600ff8, 0x1009, 0x2011,
+                                      0x8, &mut allocator).unwrap();
+
+        assert_eq!(placement.file, Some(Piece {
+            physical_base: 0x23b000,
+            virtual_base: 0x600000,
+            size: 0x2000
+        }));
+        assert_eq!(placement.tail, Some(Piece {
+            physical_base: 0x800000,
+            virtual_base: 0x602000,
+            size: 0x2000
+        }));
+        assert_eq!(placement.source, 0x23d000);
+        assert_eq!(placement.copy, 0x1);
+    }
+
+    #[test]
+    fn test_place_no_memory() {
+        let mut allocator = Allocator::new();
+
+        assert!(place_segment(0x23b000, 0x600000, 0x10, 0x2000,
+                              0x1000, &mut allocator).is_none());
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_place_incongruent() {
+        let mut allocator = available();
+
+        place_segment(0x23b010, 0x600000, 0x10, 0x10, 0x1000, &mut allocator);
+    }
+}
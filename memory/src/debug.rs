@@ -0,0 +1,384 @@
+use std::fmt::Display;
+
+use std::fmt;
+use std::mem;
+use std::ptr;
+use std::cmp;
+
+use constants;
+
+use super::{MEMORY, MemoryError, reserve, slab};
+
+// left untouched in front of the header, free lists keep their links here
+const SLACK: usize = 32;
+
+// guard bytes on either side of an allocation
+const GUARD: usize = 16;
+
+const GUARD_BYTE: u8 = 0xfd;
+const POISON_BYTE: u8 = 0xdd;
+
+const ALIVE: usize = 0xa110ca7e;
+const DEAD: usize = 0xdeadf7ee;
+
+// Kept right below the front guard of every allocation
+//
+// slack | header | front guard | data | tail guard
+#[repr(C)]
+struct Header {
+    magic: usize,
+    size: usize,
+    align: usize
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Corruption {
+    DoubleFree,
+    BadHeader,
+    SizeMismatch { allocated: usize, released: usize },
+    AlignMismatch { allocated: usize, released: usize },
+    Underrun { offset: usize },
+    Overrun { offset: usize },
+    UseAfterFree { offset: usize }
+}
+
+impl Display for Corruption {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Corruption::DoubleFree =>
+                write!(f, "pointer was already released"),
+            &Corruption::BadHeader =>
+                write!(f, "header was overwritten, or pointer was never allocated"),
+            &Corruption::SizeMismatch { allocated, released } =>
+                write!(f, "allocated with size 0x{:x} but released with size 0x{:x}", allocated, released),
+            &Corruption::AlignMismatch { allocated, released } =>
+                write!(f, "allocated with align 0x{:x} but released with align 0x{:x}", allocated, released),
+            &Corruption::Underrun { offset } =>
+                write!(f, "guard overwritten 0x{:x} bytes before the allocation", offset),
+            &Corruption::Overrun { offset } =>
+                write!(f, "guard overwritten 0x{:x} bytes past the end of the allocation", offset),
+            &Corruption::UseAfterFree { offset } =>
+                write!(f, "released memory was written 0x{:x} bytes into the allocation", offset)
+        }
+    }
+}
+
+#[inline]
+fn outer_align(align: usize) -> usize {
+    cmp::max(align, mem::align_of::<Header>())
+}
+
+#[inline]
+fn front_size(align: usize) -> usize {
+    constants::align(SLACK + mem::size_of::<Header>() + GUARD, outer_align(align))
+}
+
+#[inline]
+fn outer_size(size: usize, align: usize) -> usize {
+    front_size(align) + size + GUARD
+}
+
+#[inline]
+unsafe fn header(ptr: *mut u8) -> *mut Header {
+    ptr.offset(-((GUARD + mem::size_of::<Header>()) as isize)) as *mut Header
+}
+
+#[inline]
+unsafe fn outer(ptr: *mut u8, align: usize) -> *mut u8 {
+    ptr.offset(-(front_size(align) as isize))
+}
+
+unsafe fn arm(raw: *mut u8, size: usize, align: usize) -> *mut u8 {
+    let ptr = raw.offset(front_size(align) as isize);
+    let padding = front_size(align) - SLACK - mem::size_of::<Header>() - GUARD;
+
+    // no stale header of an earlier layout survives in the padding
+    ptr::write_bytes(raw.offset(SLACK as isize), POISON_BYTE, padding);
+
+    ptr::write(header(ptr), Header {
+        magic: ALIVE,
+        size: size,
+        align: align
+    });
+
+    ptr::write_bytes(ptr.offset(-(GUARD as isize)), GUARD_BYTE, GUARD);
+    ptr::write_bytes(ptr.offset(size as isize), GUARD_BYTE, GUARD);
+
+    ptr
+}
+
+unsafe fn set_size(ptr: *mut u8, size: usize) {
+    header(ptr).as_mut().unwrap().size = size;
+
+    ptr::write_bytes(ptr.offset(size as isize), GUARD_BYTE, GUARD);
+}
+
+unsafe fn check_guard(guard: *mut u8) -> Option<usize> {
+    for offset in 0..GUARD {
+        if *guard.offset(offset as isize) != GUARD_BYTE {
+            return Some(offset);
+        }
+    }
+
+    None
+}
+
+// Slab objects are only ever reused whole, so a released header found where
+// this one goes belongs to the same object and its data is still poisoned
+unsafe fn check_poison(raw: *mut u8, ptr: *mut u8, size: usize, align: usize) -> Result<(), MemoryError> {
+    let class = slab::class(outer_size(size, align), outer_align(align));
+
+    if class.is_none() || reserve::belongs(raw) {
+        return Ok(());
+    }
+
+    let header = header(ptr).as_ref().unwrap();
+
+    if header.magic != DEAD || slab::class(outer_size(header.size, header.align), outer_align(header.align)) != class {
+        return Ok(());
+    }
+
+    for offset in 0..cmp::min(header.size, size) {
+        if *ptr.offset(offset as isize) != POISON_BYTE {
+            return Err(MemoryError::Corrupt(Corruption::UseAfterFree { offset: offset }));
+        }
+    }
+
+    Ok(())
+}
+
+unsafe fn check(ptr: *mut u8, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+    let header = header(ptr).as_ref().unwrap();
+
+    match header.magic {
+        ALIVE => {},
+        DEAD => return Err(MemoryError::Corrupt(Corruption::DoubleFree)),
+        _ => return Err(MemoryError::Corrupt(Corruption::BadHeader))
+    }
+
+    if header.size != size {
+        return Err(MemoryError::Corrupt(Corruption::SizeMismatch {
+            allocated: header.size,
+            released: size
+        }));
+    }
+
+    if header.align != align {
+        return Err(MemoryError::Corrupt(Corruption::AlignMismatch {
+            allocated: header.align,
+            released: align
+        }));
+    }
+
+    if let Some(offset) = check_guard(ptr.offset(-(GUARD as isize))) {
+        return Err(MemoryError::Corrupt(Corruption::Underrun { offset: GUARD - offset }));
+    }
+
+    if let Some(offset) = check_guard(ptr.offset(size as isize)) {
+        return Err(MemoryError::Corrupt(Corruption::Overrun { offset: offset }));
+    }
+
+    Ok(outer(ptr, align))
+}
+
+pub unsafe fn allocate(size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+    if size == 0 {
+        // let the allocator complain
+        return MEMORY.allocate(size, align);
+    }
+
+    let raw = try!(MEMORY.allocate(outer_size(size, align), outer_align(align)));
+
+    // a corrupted block is left out of the heap
+    try!(check_poison(raw, raw.offset(front_size(align) as isize), size, align));
+
+    Ok(arm(raw, size, align))
+}
+
+pub unsafe fn release(ptr: *mut u8, size: usize, align: usize) -> Result<usize, MemoryError> {
+    if ptr.is_null() {
+        return MEMORY.release(ptr, size, align);
+    }
+
+    let raw = try!(check(ptr, size, align));
+
+    header(ptr).as_mut().unwrap().magic = DEAD;
+    ptr::write_bytes(ptr, POISON_BYTE, size);
+
+    try!(MEMORY.release(raw, outer_size(size, align), outer_align(align)));
+
+    Ok(size)
+}
+
+pub unsafe fn grow(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+    let raw = try!(check(ptr, old_size, align));
+
+    try!(MEMORY.grow(raw, outer_size(old_size, align), outer_size(size, align), outer_align(align)));
+
+    set_size(ptr, size);
+
+    Ok(())
+}
+
+pub unsafe fn shrink(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+    let raw = try!(check(ptr, old_size, align));
+
+    try!(MEMORY.shrink(raw, outer_size(old_size, align), outer_size(size, align), outer_align(align)));
+
+    set_size(ptr, size);
+
+    Ok(())
+}
+
+pub unsafe fn resize(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+    let raw = try!(check(ptr, old_size, align));
+
+    // the header and front guard move along with the data
+    let new_raw = try!(MEMORY.resize(raw, outer_size(old_size, align), outer_size(size, align), outer_align(align)));
+    let new_ptr = new_raw.offset(front_size(align) as isize);
+
+    set_size(new_ptr, size);
+
+    Ok(new_ptr)
+}
+
+#[cfg(test)]
+mod tests {
+    use harness::{Model, Global, with_global};
+
+    use super::{Corruption, GUARD, GUARD_BYTE, POISON_BYTE, DEAD, header, allocate, release, grow, resize};
+    use super::super::MemoryError;
+
+    fn corrupt(corruption: Corruption) -> Result<usize, MemoryError> {
+        Err(MemoryError::Corrupt(corruption))
+    }
+
+    #[test]
+    fn test_double_free() {
+        with_global(|_, _| unsafe {
+            let ptr = allocate(0x20, 8).unwrap();
+
+            assert_eq!(release(ptr, 0x20, 8), Ok(0x20));
+            assert_eq!(release(ptr, 0x20, 8), corrupt(Corruption::DoubleFree));
+        });
+    }
+
+    #[test]
+    fn test_bad_header() {
+        with_global(|_, _| unsafe {
+            let ptr = allocate(0x20, 8).unwrap();
+            let magic = (*header(ptr)).magic;
+
+            (*header(ptr)).magic = 0;
+
+            assert_eq!(release(ptr, 0x20, 8), corrupt(Corruption::BadHeader));
+
+            (*header(ptr)).magic = magic;
+
+            assert_eq!(release(ptr, 0x20, 8), Ok(0x20));
+        });
+    }
+
+    #[test]
+    fn test_mismatch() {
+        with_global(|_, _| unsafe {
+            let ptr = allocate(0x20, 8).unwrap();
+
+            assert_eq!(release(ptr, 0x21, 8), corrupt(Corruption::SizeMismatch {
+                allocated: 0x20,
+                released: 0x21
+            }));
+
+            assert_eq!(release(ptr, 0x20, 0x10), corrupt(Corruption::AlignMismatch {
+                allocated: 8,
+                released: 0x10
+            }));
+
+            // nothing was released by the failed attempts
+            assert_eq!(release(ptr, 0x20, 8), Ok(0x20));
+        });
+    }
+
+    #[test]
+    fn test_guards() {
+        with_global(|_, _| unsafe {
+            let ptr = allocate(0x20, 8).unwrap();
+
+            *ptr.offset(-3) = 0;
+
+            assert_eq!(release(ptr, 0x20, 8), corrupt(Corruption::Underrun { offset: 3 }));
+
+            *ptr.offset(-3) = GUARD_BYTE;
+            *ptr.offset(0x22) = 0;
+
+            assert_eq!(release(ptr, 0x20, 8), corrupt(Corruption::Overrun { offset: 2 }));
+            assert_eq!(grow(ptr, 0x20, 0x30, 8), Err(MemoryError::Corrupt(Corruption::Overrun { offset: 2 })));
+
+            *ptr.offset(0x22) = GUARD_BYTE;
+
+            assert_eq!(release(ptr, 0x20, 8), Ok(0x20));
+        });
+    }
+
+    #[test]
+    fn test_poison() {
+        with_global(|_, _| unsafe {
+            let ptr = allocate(0x20, 8).unwrap();
+
+            assert_eq!(release(ptr, 0x20, 8), Ok(0x20));
+
+            for offset in 0..0x20 {
+                assert_eq!(*ptr.offset(offset), POISON_BYTE);
+            }
+
+            // the slab hands the same object straight back
+            let again = allocate(0x18, 8).unwrap();
+
+            assert_eq!(again, ptr);
+            assert_eq!(release(again, 0x18, 8), Ok(0x18));
+
+            *ptr.offset(5) = 0;
+
+            assert_eq!(allocate(0x20, 8), Err(MemoryError::Corrupt(Corruption::UseAfterFree { offset: 5 })));
+        });
+    }
+
+    #[test]
+    fn test_resize() {
+        with_global(|_, _| unsafe {
+            let ptr = allocate(0x20, 8).unwrap();
+
+            for offset in 0..0x20 {
+                *ptr.offset(offset) = offset as u8;
+            }
+
+            // big enough to leave the slabs, with the header and guards moving along
+            let moved = resize(ptr, 0x20, 0x1000, 8).unwrap();
+
+            for offset in 0..0x20 {
+                assert_eq!(*moved.offset(offset), offset as u8);
+            }
+
+            for offset in 0..GUARD {
+                assert_eq!(*moved.offset((0x1000 + offset) as isize), GUARD_BYTE);
+            }
+
+            assert_eq!(release(moved, 0x20, 8), corrupt(Corruption::SizeMismatch {
+                allocated: 0x1000,
+                released: 0x20
+            }));
+
+            assert_eq!(release(moved, 0x1000, 8), Ok(0x1000));
+            assert_eq!((*header(moved)).magic, DEAD);
+        });
+    }
+
+    #[test]
+    fn test_random() {
+        for seed in 0..8 {
+            with_global(|base, size| unsafe {
+                Model::new(&mut Global, base, size, seed).limits(0x1000, 0x100).run(0x1000);
+            });
+        }
+    }
+}
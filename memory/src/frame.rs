@@ -0,0 +1,393 @@
+use spin::Mutex;
+
+use constants::*;
+
+use super::MemoryError;
+
+static FRAMES: Mutex<Manager> = Mutex::new(Manager::new());
+
+const FRAME_SHIFT: usize = 12;
+const FRAME_COUNT: usize = (FRAME_LIMIT >> FRAME_SHIFT) as usize;
+
+// largest block is one 2MB frame
+const MAX_ORDER: usize = 9;
+
+// one bit per block, per order, halving with each order
+const MAP_WORDS: usize = FRAME_COUNT / 64;
+const TOTAL_WORDS: usize = 2 * MAP_WORDS - (MAP_WORDS >> MAX_ORDER);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameSize {
+    Page, // 4 kilobytes
+    Big   // 2 megabytes
+}
+
+impl FrameSize {
+    #[inline]
+    pub fn get_size(self) -> u64 {
+        1 << (self.get_order() + FRAME_SHIFT)
+    }
+
+    #[inline]
+    fn get_order(self) -> usize {
+        match self {
+            FrameSize::Page => 0,
+            FrameSize::Big => MAX_ORDER
+        }
+    }
+}
+
+// Buddy allocator over physical memory
+//
+// A set bit means that block is free, and that its buddy is not, since two
+// free buddies are always merged into their parent.
+struct Manager {
+    free: usize,
+    map: [u64; TOTAL_WORDS]
+}
+
+impl Manager {
+    #[inline]
+    const fn new() -> Manager {
+        Manager {
+            free: 0,
+            map: [0; TOTAL_WORDS]
+        }
+    }
+
+    #[inline]
+    fn offset(order: usize) -> usize {
+        // words used by all lower orders
+        2 * MAP_WORDS - (2 * MAP_WORDS >> order)
+    }
+
+    #[inline]
+    fn is_free(&self, order: usize, idx: usize) -> bool {
+        (self.map[Manager::offset(order) + (idx >> 6)] >> (idx & 0x3f)) & 1 == 1
+    }
+
+    #[inline]
+    fn set_free(&mut self, order: usize, idx: usize) {
+        self.map[Manager::offset(order) + (idx >> 6)] |= 1 << (idx & 0x3f);
+    }
+
+    #[inline]
+    fn set_used(&mut self, order: usize, idx: usize) {
+        self.map[Manager::offset(order) + (idx >> 6)] &= !(1 << (idx & 0x3f));
+    }
+
+    // whether any of count blocks from idx is free, count being a power of two
+    fn any_free(&self, order: usize, idx: usize, count: usize) -> bool {
+        let base = Manager::offset(order);
+
+        if count >= 64 {
+            (idx >> 6..(idx + count) >> 6).any(|word| self.map[base + word] != 0)
+        } else {
+            let mask = ((1 << count) - 1) << (idx & 0x3f);
+
+            self.map[base + (idx >> 6)] & mask != 0
+        }
+    }
+
+    // whether any part of the block is already free
+    fn overlaps(&self, order: usize, idx: usize) -> bool {
+        // this block or one containing it
+        for parent in order..MAX_ORDER + 1 {
+            if self.is_free(parent, idx >> (parent - order)) {
+                return true;
+            }
+        }
+
+        // or some smaller block inside it
+        for child in 0..order {
+            if self.any_free(child, idx << (order - child), 1 << (order - child)) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    fn find(&self, order: usize) -> Option<usize> {
+        let base = Manager::offset(order);
+
+        for word in 0..MAP_WORDS >> order {
+            let entry = self.map[base + word];
+
+            if entry != 0 {
+                return Some((word << 6) + entry.trailing_zeros() as usize);
+            }
+        }
+
+        None
+    }
+
+    fn allocate(&mut self, order: usize) -> Result<u64, MemoryError> {
+        for found in order..MAX_ORDER + 1 {
+            if let Some(mut idx) = self.find(found) {
+                self.set_used(found, idx);
+
+                // split down to the order we want, freeing the upper halves
+                for split in (order..found).rev() {
+                    idx <<= 1;
+                    self.set_free(split, idx + 1);
+                }
+
+                self.free -= 1 << order;
+
+                return Ok((idx << (order + FRAME_SHIFT)) as u64);
+            }
+        }
+
+        Err(MemoryError::OutOfMemory)
+    }
+
+    fn insert(&mut self, mut order: usize, mut idx: usize) {
+        self.free += 1 << order;
+
+        // merge with free buddies as far as we can
+        while order < MAX_ORDER && self.is_free(order, idx ^ 1) {
+            self.set_used(order, idx ^ 1);
+            idx >>= 1;
+            order += 1;
+        }
+
+        self.set_free(order, idx);
+    }
+
+    fn release(&mut self, address: u64, order: usize) -> Result<(), MemoryError> {
+        if !is_aligned(address, 1 << (order + FRAME_SHIFT)) || address >= FRAME_LIMIT {
+            return Err(MemoryError::InvalidFrame);
+        }
+
+        let idx = (address >> (order + FRAME_SHIFT)) as usize;
+
+        if self.overlaps(order, idx) {
+            return Err(MemoryError::Overlap);
+        }
+
+        self.insert(order, idx);
+
+        Ok(())
+    }
+
+    fn register(&mut self, base: u64, size: u64) -> u64 {
+        let mut end = align_back(base + size, 1 << FRAME_SHIFT);
+        let mut base = align(base, 1 << FRAME_SHIFT);
+
+        if end > FRAME_LIMIT {
+            warn!("Ignoring physical memory above 0x{:x}", FRAME_LIMIT);
+            end = FRAME_LIMIT;
+        }
+
+        let mut registered = 0;
+        let mut skipped = 0;
+
+        while base < end {
+            // find the largest block that starts here, fits, and isn't free already
+            let mut order = MAX_ORDER;
+
+            while !is_aligned(base, 1 << (order + FRAME_SHIFT))
+                || base + (1 << (order + FRAME_SHIFT)) > end
+                || (order > 0 && self.overlaps(order, (base >> (order + FRAME_SHIFT)) as usize)) {
+                    order -= 1;
+                }
+
+            let idx = (base >> (order + FRAME_SHIFT)) as usize;
+
+            if self.overlaps(order, idx) {
+                skipped += 1 << FRAME_SHIFT;
+            } else {
+                self.insert(order, idx);
+                registered += 1 << (order + FRAME_SHIFT);
+            }
+
+            base += 1 << (order + FRAME_SHIFT);
+        }
+
+        if skipped > 0 {
+            warn!("Skipped 0x{:x} bytes of physical memory that were already registered", skipped);
+        }
+
+        registered
+    }
+
+    #[inline]
+    fn free(&self) -> u64 {
+        (self.free as u64) << FRAME_SHIFT
+    }
+}
+
+#[inline]
+pub unsafe fn register(base: u64, size: u64) -> u64 {
+    FRAMES.lock().register(base, size)
+}
+
+#[inline]
+pub fn allocate(size: FrameSize) -> Result<u64, MemoryError> {
+    FRAMES.lock().allocate(size.get_order())
+}
+
+#[inline]
+pub unsafe fn release(address: u64, size: FrameSize) -> Result<(), MemoryError> {
+    FRAMES.lock().release(address, size.get_order())
+}
+
+#[inline]
+pub fn free() -> u64 {
+    FRAMES.lock().free()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::boxed::Box;
+
+    use super::{Manager, FrameSize, MAX_ORDER};
+    use super::super::MemoryError;
+
+    const PAGE: u64 = 0x1000;
+    const BIG: u64 = 0x200000;
+
+    fn manager() -> Box<Manager> {
+        Box::new(Manager::new())
+    }
+
+    #[test]
+    fn test_split_merge() {
+        let mut manager = manager();
+
+        assert_eq!(manager.register(BIG, BIG), BIG);
+
+        let a = manager.allocate(0).unwrap();
+        let b = manager.allocate(0).unwrap();
+
+        assert_eq!(a, BIG);
+        assert_eq!(b, BIG + PAGE);
+        assert_eq!(manager.free(), BIG - 2 * PAGE);
+
+        // the big frame is split, so it can't be handed out whole
+        assert_eq!(manager.allocate(MAX_ORDER), Err(MemoryError::OutOfMemory));
+
+        assert!(manager.release(b, 0).is_ok());
+        assert!(manager.release(a, 0).is_ok());
+
+        // and merges back once both halves are free again
+        assert_eq!(manager.free(), BIG);
+        assert_eq!(manager.allocate(MAX_ORDER), Ok(BIG));
+        assert_eq!(manager.free(), 0);
+    }
+
+    #[test]
+    fn test_register_edges() {
+        let mut manager = manager();
+
+        // neither edge is page aligned, and the region straddles a big frame boundary
+        let base = BIG - 0x1800;
+        let size = BIG + 0x2800;
+
+        assert_eq!(manager.register(base, size), BIG + 2 * PAGE);
+        assert_eq!(manager.free(), BIG + 2 * PAGE);
+
+        // the only whole big frame is the aligned one in the middle
+        assert_eq!(manager.allocate(MAX_ORDER), Ok(BIG));
+        assert_eq!(manager.allocate(MAX_ORDER), Err(MemoryError::OutOfMemory));
+
+        let mut pages = vec![];
+
+        while let Ok(page) = manager.allocate(0) {
+            pages.push(page);
+        }
+
+        pages.sort();
+
+        assert_eq!(pages, vec![BIG - PAGE, 2 * BIG]);
+    }
+
+    #[test]
+    fn test_register_twice() {
+        let mut manager = manager();
+
+        assert_eq!(manager.register(BIG, BIG), BIG);
+
+        // the same region again adds nothing
+        assert_eq!(manager.register(BIG, BIG), 0);
+        assert_eq!(manager.free(), BIG);
+
+        let page = manager.allocate(0).unwrap();
+
+        // an overlapping region only adds what's new
+        assert_eq!(manager.register(BIG + BIG / 2, BIG), BIG / 2);
+        assert_eq!(manager.free(), 3 * BIG / 2 - PAGE);
+
+        // every frame comes out once
+        let mut pages = vec![page];
+
+        while let Ok(page) = manager.allocate(0) {
+            pages.push(page);
+        }
+
+        let count = pages.len();
+
+        pages.sort();
+        pages.dedup();
+
+        assert_eq!(pages.len(), count);
+        assert_eq!(count as u64, 3 * BIG / 2 / PAGE);
+    }
+
+    #[test]
+    fn test_double_release_page() {
+        let mut manager = manager();
+
+        assert_eq!(manager.register(0, BIG), BIG);
+
+        let a = manager.allocate(0).unwrap();
+        let _ = manager.allocate(0).unwrap();
+
+        assert!(manager.release(a, 0).is_ok());
+        assert_eq!(manager.release(a, 0), Err(MemoryError::Overlap));
+        assert_eq!(manager.free(), BIG - PAGE);
+    }
+
+    #[test]
+    fn test_double_release_big() {
+        let mut manager = manager();
+
+        assert_eq!(manager.register(0, BIG), BIG);
+
+        let a = manager.allocate(MAX_ORDER).unwrap();
+
+        assert!(manager.release(a, MAX_ORDER).is_ok());
+        assert_eq!(manager.release(a, MAX_ORDER), Err(MemoryError::Overlap));
+
+        // a page inside a free big frame is free too
+        assert_eq!(manager.release(a + PAGE, 0), Err(MemoryError::Overlap));
+        assert_eq!(manager.free(), BIG);
+    }
+
+    #[test]
+    fn test_release_over_free_page() {
+        let mut manager = manager();
+
+        assert_eq!(manager.register(0, BIG), BIG);
+
+        let a = manager.allocate(MAX_ORDER).unwrap();
+
+        // give back a single page of the big frame, then the whole frame
+        assert!(manager.release(a + 0x10 * PAGE, 0).is_ok());
+        assert_eq!(manager.release(a, MAX_ORDER), Err(MemoryError::Overlap));
+        assert_eq!(manager.free(), PAGE);
+
+        assert_eq!(manager.allocate(0), Ok(a + 0x10 * PAGE));
+        assert_eq!(manager.allocate(0), Err(MemoryError::OutOfMemory));
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut manager = manager();
+
+        assert_eq!(manager.release(PAGE, MAX_ORDER), Err(MemoryError::InvalidFrame));
+        assert_eq!(manager.release(0x100000000, 0), Err(MemoryError::InvalidFrame));
+        assert_eq!(FrameSize::Big.get_size(), BIG);
+    }
+}
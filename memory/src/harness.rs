@@ -0,0 +1,349 @@
+use std::ptr;
+use std::mem;
+
+use spin::Mutex;
+
+use constants;
+
+use super::{MemoryError, slab};
+
+// bytes of host memory handed to the global heap, once
+const GLOBAL_SIZE: usize = 0x40000;
+
+// the global heap is shared, so tests using it take turns
+static GLOBAL: Mutex<Option<usize>> = Mutex::new(None);
+
+/// Implement Heap for a backend's own allocate, release, grow, shrink,
+/// resize and hint
+macro_rules! heap_adapter {
+    ($backend:ident) => {
+        impl ::harness::Heap for $backend {
+            unsafe fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, ::MemoryError> {
+                $backend::allocate(self, size, align)
+            }
+
+            unsafe fn release(&mut self, ptr: *mut u8, size: usize, align: usize) -> Result<usize, ::MemoryError> {
+                $backend::release(self, ptr, size, align)
+            }
+
+            unsafe fn grow(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), ::MemoryError> {
+                $backend::grow(self, ptr, old_size, size, align)
+            }
+
+            unsafe fn shrink(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), ::MemoryError> {
+                $backend::shrink(self, ptr, old_size, size, align)
+            }
+
+            unsafe fn resize(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, ::MemoryError> {
+                $backend::resize(self, ptr, old_size, size, align)
+            }
+
+            fn free(&self) -> usize {
+                self.hint()
+            }
+        }
+    }
+}
+
+/// The operations every backend supports
+pub trait Heap {
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, MemoryError>;
+    unsafe fn release(&mut self, ptr: *mut u8, size: usize, align: usize) -> Result<usize, MemoryError>;
+    unsafe fn grow(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError>;
+    unsafe fn shrink(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError>;
+    unsafe fn resize(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, MemoryError>;
+    fn free(&self) -> usize;
+}
+
+/// Allocations through the crate's entry points, which pick the backend
+pub struct Global;
+
+impl Global {
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+        ::allocate(size, align)
+    }
+
+    unsafe fn release(&mut self, ptr: *mut u8, size: usize, align: usize) -> Result<usize, MemoryError> {
+        ::release(ptr, size, align)
+    }
+
+    unsafe fn grow(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+        ::grow(ptr, old_size, size, align)
+    }
+
+    unsafe fn shrink(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+        ::shrink(ptr, old_size, size, align)
+    }
+
+    unsafe fn resize(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+        ::resize(ptr, old_size, size, align)
+    }
+
+    fn hint(&self) -> usize {
+        ::hint()
+    }
+}
+
+heap_adapter!(Global);
+
+/// Run a test against the global heap, set up over host memory on first use
+pub fn with_global<F: FnOnce(*mut u8, usize)>(test: F) {
+    let mut global = GLOBAL.lock();
+
+    if global.is_none() {
+        // slab pages outlive any one test, so the buffer is never given back
+        let mut buffer: Vec<u8> = vec![0; GLOBAL_SIZE + slab::PAGE_SIZE];
+        let base = constants::align(buffer.as_mut_ptr() as usize, slab::PAGE_SIZE);
+
+        mem::forget(buffer);
+
+        ::enable();
+        ::exit_reserved();
+
+        unsafe {
+            assert_eq!(::register(base as *mut u8, GLOBAL_SIZE), Ok(GLOBAL_SIZE));
+        }
+
+        *global = Some(base);
+    }
+
+    test(global.unwrap() as *mut u8, GLOBAL_SIZE);
+}
+
+/// Xorshift, so a failing seed can be replayed
+pub struct Rng {
+    state: u64
+}
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng {
+        Rng {
+            // state must never be zero
+            state: seed ^ 0x2545f4914f6cdd1d
+        }
+    }
+
+    pub fn next(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+
+    pub fn below(&mut self, limit: usize) -> usize {
+        (self.next() % limit as u64) as usize
+    }
+}
+
+// An allocation the heap handed out, and what should be in it
+#[derive(Debug, Clone, Copy)]
+struct Live {
+    ptr: *mut u8,
+    size: usize,
+    align: usize,
+    fill: u8
+}
+
+/// Shadow model of one heap over a host buffer
+pub struct Model<'a, H: 'a + Heap> {
+    heap: &'a mut H,
+    begin: usize,
+    end: usize,
+    max_size: usize,
+    max_align: usize,
+    live: Vec<Live>,
+    rng: Rng,
+    fill: u8
+}
+
+impl<'a, H: Heap> Model<'a, H> {
+    pub fn new(heap: &'a mut H, begin: *mut u8, size: usize, seed: u64) -> Model<'a, H> {
+        Model {
+            heap: heap,
+            begin: begin as usize,
+            end: begin as usize + size,
+            max_size: 0x200,
+            max_align: 0x40,
+            live: vec![],
+            rng: Rng::new(seed),
+            fill: 0
+        }
+    }
+
+    /// Draw sizes up to max_size and alignments up to max_align instead
+    pub fn limits(mut self, max_size: usize, max_align: usize) -> Model<'a, H> {
+        self.max_size = max_size;
+        self.max_align = max_align;
+        self
+    }
+
+    /// Run steps random operations, then release everything
+    pub unsafe fn run(&mut self, steps: usize) {
+        let free = self.heap.free();
+
+        for step in 0..steps {
+            self.fill = self.fill.wrapping_add(1);
+
+            if self.live.is_empty() {
+                self.allocate();
+                continue;
+            }
+
+            match self.rng.below(6) {
+                0 | 1 => self.allocate(),
+                2 => self.release(),
+                3 => self.grow(),
+                4 => self.shrink(),
+                _ => self.resize()
+            }
+
+            if step % 64 == 0 {
+                self.check_all();
+            }
+        }
+
+        while !self.live.is_empty() {
+            self.release();
+        }
+
+        assert_eq!(self.heap.free(), free);
+    }
+
+    fn random_size(&mut self) -> usize {
+        1 + self.rng.below(self.max_size)
+    }
+
+    fn random_align(&mut self) -> usize {
+        1 << self.rng.below(self.max_align.trailing_zeros() as usize + 1)
+    }
+
+    fn pick(&mut self) -> usize {
+        let len = self.live.len();
+
+        self.rng.below(len)
+    }
+
+    fn check_placed(&self, ptr: *mut u8, size: usize, align: usize, skip: Option<usize>) {
+        let base = ptr as usize;
+
+        assert!(base & (align - 1) == 0, "{:?} was not aligned to 0x{:x}", ptr, align);
+        assert!(base >= self.begin && base + size <= self.end,
+                "{:?}+0x{:x} was outside of the heap", ptr, size);
+
+        for (idx, other) in self.live.iter().enumerate() {
+            if Some(idx) != skip {
+                let other_base = other.ptr as usize;
+
+                assert!(base + size <= other_base || other_base + other.size <= base,
+                        "{:?}+0x{:x} overlapped {:?}+0x{:x}", ptr, size, other.ptr, other.size);
+            }
+        }
+    }
+
+    unsafe fn check_fill(&self, ptr: *mut u8, size: usize, fill: u8) {
+        for offset in 0..size {
+            assert!(*ptr.offset(offset as isize) == fill,
+                    "{:?} lost its contents at offset 0x{:x}", ptr, offset);
+        }
+    }
+
+    unsafe fn check_all(&self) {
+        for live in self.live.iter() {
+            self.check_fill(live.ptr, live.size, live.fill);
+        }
+    }
+
+    unsafe fn allocate(&mut self) {
+        let size = self.random_size();
+        let align = self.random_align();
+
+        match self.heap.allocate(size, align) {
+            Ok(ptr) => {
+                self.check_placed(ptr, size, align, None);
+
+                ptr::write_bytes(ptr, self.fill, size);
+
+                self.live.push(Live {
+                    ptr: ptr,
+                    size: size,
+                    align: align,
+                    fill: self.fill
+                });
+            },
+            Err(MemoryError::OutOfMemory) => {},
+            Err(e) => panic!("Allocate failed: {}", e)
+        }
+    }
+
+    unsafe fn release(&mut self) {
+        let idx = self.pick();
+        let live = self.live.swap_remove(idx);
+
+        self.check_fill(live.ptr, live.size, live.fill);
+
+        assert!(self.heap.release(live.ptr, live.size, live.align).is_ok());
+    }
+
+    unsafe fn grow(&mut self) {
+        let idx = self.pick();
+        let live = self.live[idx];
+        let size = live.size + self.random_size();
+
+        match self.heap.grow(live.ptr, live.size, size, live.align) {
+            Ok(()) => {
+                self.check_placed(live.ptr, size, live.align, Some(idx));
+                self.check_fill(live.ptr, live.size, live.fill);
+
+                ptr::write_bytes(live.ptr, self.fill, size);
+
+                self.live[idx].size = size;
+                self.live[idx].fill = self.fill;
+            },
+            Err(MemoryError::OutOfSpace) => self.check_fill(live.ptr, live.size, live.fill),
+            Err(e) => panic!("Grow failed: {}", e)
+        }
+    }
+
+    unsafe fn shrink(&mut self) {
+        let idx = self.pick();
+        let live = self.live[idx];
+        let size = 1 + self.rng.below(live.size);
+
+        match self.heap.shrink(live.ptr, live.size, size, live.align) {
+            Ok(()) => {
+                self.check_fill(live.ptr, size, live.fill);
+
+                self.live[idx].size = size;
+            },
+            // slab objects can't drop to a smaller class in place
+            Err(MemoryError::OutOfSpace) => self.check_fill(live.ptr, live.size, live.fill),
+            Err(e) => panic!("Shrink failed: {}", e)
+        }
+    }
+
+    unsafe fn resize(&mut self) {
+        let idx = self.pick();
+        let live = self.live[idx];
+        let size = self.random_size();
+
+        match self.heap.resize(live.ptr, live.size, size, live.align) {
+            Ok(ptr) => {
+                self.check_placed(ptr, size, live.align, Some(idx));
+
+                // whatever fits has to come along
+                self.check_fill(ptr, if size < live.size { size } else { live.size }, live.fill);
+
+                ptr::write_bytes(ptr, self.fill, size);
+
+                self.live[idx] = Live {
+                    ptr: ptr,
+                    size: size,
+                    align: live.align,
+                    fill: self.fill
+                };
+            },
+            Err(MemoryError::OutOfMemory) => self.check_fill(live.ptr, live.size, live.fill),
+            Err(e) => panic!("Resize failed: {}", e)
+        }
+    }
+}
@@ -1,8 +1,12 @@
 #![feature(const_fn)]
-#![feature(allocator)]
-#![allocator]
-#![no_std]
+#![cfg_attr(not(test), feature(allocator))]
+#![cfg_attr(not(test), allocator)]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(test, feature(test))]
+#[cfg(not(test))]
 extern crate core as std;
+#[cfg(test)]
+extern crate test;
 #[macro_use]
 extern crate log;
 extern crate constants;
@@ -11,12 +15,22 @@ extern crate spin;
 use std::fmt::Display;
 use std::sync::atomic::{Ordering, AtomicBool};
 
+use spin::Mutex;
+
 use std::fmt;
 use std::str;
 use std::ptr;
+use std::cmp;
 
 use constants::error::Error;
 
+pub use frame::FrameSize;
+
+// Randomized checks against host memory
+#[cfg(test)]
+#[macro_use]
+mod harness;
+
 // Reserve memory
 mod reserve;
 
@@ -24,15 +38,42 @@ mod reserve;
 // Identity page-table only
 mod simple;
 
+// Physical frames
+mod frame;
+
+// Small objects
+mod slab;
+
+// Guards and poisoning
+#[cfg(feature = "debug_heap")]
+mod debug;
+
+#[cfg(feature = "debug_heap")]
+pub use debug::Corruption;
+
 static MEMORY: Manager = Manager {
     enabled: AtomicBool::new(false),
-    use_reserve: AtomicBool::new(true)
+    use_reserve: AtomicBool::new(true),
+    growing: AtomicBool::new(false),
+    grow: Mutex::new(None)
 };
 
+/// Maps size bytes of physical memory at the given virtual address
+pub type MapHook = unsafe fn(virtual_address: usize, physical_address: u64, size: usize) -> bool;
+
 // Memory Manager
 struct Manager {
     enabled: AtomicBool,
-    use_reserve: AtomicBool
+    use_reserve: AtomicBool,
+    growing: AtomicBool,
+    grow: Mutex<Option<Grow>>
+}
+
+// Where and how the simple heap can grow
+struct Grow {
+    end: usize,
+    limit: usize,
+    hook: MapHook
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -43,12 +84,20 @@ pub enum MemoryError {
     EmptyAllocation,
     TinyBlock,
     NoPlace,
-    Overlap
+    Overlap,
+    InvalidFrame,
+    #[cfg(feature = "debug_heap")]
+    Corrupt(Corruption)
 }
 
 impl Display for MemoryError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "MemoryError: {}", self.description())
+        match self {
+            #[cfg(feature = "debug_heap")]
+            &MemoryError::Corrupt(ref corruption) =>
+                write!(f, "MemoryError: {}: {}", self.description(), corruption),
+            _ => write!(f, "MemoryError: {}", self.description())
+        }
     }
 }
 
@@ -62,9 +111,95 @@ impl Error for MemoryError {
             &EmptyAllocation => "Empty allocation",
             &TinyBlock => "Block was too small",
             &NoPlace => "Could not place memory region",
-            &Overlap => "Overlap in memory region"
+            &Overlap => "Overlap in memory region",
+            &InvalidFrame => "Frame address was not valid",
+            #[cfg(feature = "debug_heap")]
+            &Corrupt(_) => "Heap corruption detected"
+        }
+    }
+}
+
+/// Usage of one allocator backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Bytes currently handed out
+    pub used: usize,
+    /// Bytes available to allocate
+    pub free: usize,
+    /// Number of separate free blocks
+    pub free_blocks: usize,
+    /// Size of the largest free block
+    pub largest_free: usize,
+    /// Allocations made so far
+    pub allocations: usize,
+    /// Allocations released so far
+    pub releases: usize,
+    /// Most bytes ever in use at once
+    pub peak: usize
+}
+
+/// Usage of every allocator backend
+///
+/// Slab objects are cut from pages allocated out of simple, so those pages
+/// count as used in simple.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub reserve: Stats,
+    pub simple: Stats,
+    pub slab: Stats
+}
+
+impl Stats {
+    const fn new() -> Stats {
+        Stats {
+            used: 0,
+            free: 0,
+            free_blocks: 0,
+            largest_free: 0,
+            allocations: 0,
+            releases: 0,
+            peak: 0
         }
     }
+
+    #[inline]
+    fn record_allocate(&mut self, size: usize) {
+        self.allocations += 1;
+        self.record_grow(size);
+    }
+
+    #[inline]
+    fn record_release(&mut self, size: usize) {
+        self.releases += 1;
+        self.record_shrink(size);
+    }
+
+    #[inline]
+    fn record_grow(&mut self, size: usize) {
+        self.used += size;
+        self.peak = cmp::max(self.peak, self.used);
+    }
+
+    #[inline]
+    fn record_shrink(&mut self, size: usize) {
+        self.used -= size;
+    }
+}
+
+impl Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "0x{:x} used (peak 0x{:x}), 0x{:x} free in {} blocks (largest 0x{:x}), {} allocations, {} releases",
+               self.used, self.peak, self.free, self.free_blocks, self.largest_free,
+               self.allocations, self.releases)
+    }
+}
+
+impl Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        try!(writeln!(f, "reserve: {}", self.reserve));
+        try!(writeln!(f, "simple: {}", self.simple));
+        write!(f, "slab: {}", self.slab)
+    }
 }
 
 impl Manager {
@@ -114,18 +249,33 @@ impl Manager {
         } else {
             if self.use_reserve.load(Ordering::Relaxed) {
                 reserve::allocate(size, align)
-            } else {
-                // try to make sure we have enough of a heap
-                // lazily avoid growing the simple allocator recursively
-                static TRY_GROW: AtomicBool = AtomicBool::new(true);
+            } else if let Some(class) = slab::class(size, align) {
+                match slab::allocate(class) {
+                    Err(MemoryError::OutOfMemory) => {
+                        // cut a new page for this class and try again
+                        let page = try!(self.allocate_simple(slab::PAGE_SIZE, slab::PAGE_SIZE));
 
-                if simple::hint() < size && TRY_GROW.swap(false, Ordering::Acquire) {
-                    // allocate another 2MB page to the simple heap
-                    unimplemented!();
+                        slab::refill(page, class);
+
+                        slab::allocate(class)
+                    },
+                    result => result
                 }
+            } else {
+                self.allocate_simple(size, align)
+            }
+        }
+    }
+
+    unsafe fn allocate_simple(&self, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+        match simple::allocate(size, align) {
+            Err(MemoryError::OutOfMemory) => {
+                // make the heap big enough and try again
+                try!(self.grow_heap(size + align));
 
                 simple::allocate(size, align)
-            }
+            },
+            result => result
         }
     }
 
@@ -138,6 +288,10 @@ impl Manager {
             Err(MemoryError::EmptyAllocation)
         } else if reserve::belongs(ptr) {
             reserve::release(ptr, size, align)
+        } else if let Some(class) = slab::class(size, align) {
+            slab::release(ptr, class);
+
+            Ok(slab::class_size(class))
         } else {
             simple::release(ptr, size, align)
         }
@@ -148,6 +302,8 @@ impl Manager {
 
         if reserve::belongs(ptr) {
             reserve::grow(ptr, old_size, size, align)
+        } else if is_slab(old_size, size, align) {
+            slab_inplace(old_size, size, align)
         } else {
             simple::grow(ptr, old_size, size, align)
         }
@@ -158,6 +314,8 @@ impl Manager {
 
         if reserve::belongs(ptr) {
             reserve::shrink(ptr, old_size, size, align)
+        } else if is_slab(old_size, size, align) {
+            slab_inplace(old_size, size, align)
         } else {
             simple::shrink(ptr, old_size, size, align)
         }
@@ -168,15 +326,103 @@ impl Manager {
 
         if reserve::belongs(ptr) {
             reserve::resize(ptr, old_size, size, align)
-        } else {
+        } else if !is_slab(old_size, size, align) {
             simple::resize(ptr, old_size, size, align)
+        } else if slab_inplace(old_size, size, align).is_ok() {
+            Ok(ptr)
+        } else {
+            // moving between size classes, or between a slab and the heap
+            let new_ptr = try!(self.allocate(size, align));
+
+            ptr::copy_nonoverlapping(ptr, new_ptr, cmp::min(old_size, size));
+
+            try!(self.release(ptr, old_size, align));
+
+            Ok(new_ptr)
         }
     }
 
+    unsafe fn set_grow(&self, end: *mut u8, limit: *mut u8, hook: MapHook) {
+        *self.grow.lock() = Some(Grow {
+            end: end as usize,
+            limit: limit as usize,
+            hook: hook
+        });
+    }
+
+    unsafe fn grow_heap(&self, size: usize) -> Result<(), MemoryError> {
+        if self.growing.swap(true, Ordering::Acquire) {
+            // the heap is already being grown further up the stack
+            return Err(MemoryError::OutOfMemory);
+        }
+
+        // anything allocated while mapping comes out of the reserve slab
+        let reserved = self.enter_reserved();
+
+        let result = self.grow_simple(size);
+
+        if !reserved {
+            self.exit_reserved();
+        }
+
+        self.growing.store(false, Ordering::Release);
+
+        result
+    }
+
+    unsafe fn grow_simple(&self, size: usize) -> Result<(), MemoryError> {
+        let mut grow = self.grow.lock();
+
+        let grow = match grow.as_mut() {
+            Some(grow) => grow,
+            None => return Err(MemoryError::OutOfMemory)
+        };
+
+        let frame_size = FrameSize::Big.get_size() as usize;
+        let size = constants::align(size, frame_size);
+
+        if grow.limit - grow.end < size {
+            return Err(MemoryError::NoPlace);
+        }
+
+        let mut mapped = 0;
+        let mut result = Ok(());
+
+        while mapped < size {
+            let frame = match frame::allocate(FrameSize::Big) {
+                Ok(frame) => frame,
+                Err(e) => {
+                    result = Err(e);
+                    break;
+                }
+            };
+
+            if !(grow.hook)(grow.end + mapped, frame, frame_size) {
+                assert!(frame::release(frame, FrameSize::Big).is_ok());
+                result = Err(MemoryError::NoPlace);
+                break;
+            }
+
+            mapped += frame_size;
+        }
+
+        if mapped > 0 {
+            trace!("Grew heap by 0x{:x} at 0x{:x}", mapped, grow.end);
+
+            try!(simple::register(grow.end as *mut u8, mapped));
+
+            grow.end += mapped;
+        }
+
+        result
+    }
+
     fn granularity(&self, size: usize, align: usize) -> usize {
         // TODO: this is actually not correct
         if self.use_reserve.load(Ordering::Relaxed) {
             reserve::granularity(size, align)
+        } else if let Some(class) = slab::class(size, align) {
+            slab::class_size(class)
         } else {
             simple::granularity(size, align)
         }
@@ -186,7 +432,7 @@ impl Manager {
         if self.use_reserve.load(Ordering::Relaxed) {
             reserve::hint()
         } else {
-            simple::hint()
+            simple::hint() + slab::hint()
         }
     }
 
@@ -201,6 +447,21 @@ impl Manager {
     }
 }
 
+#[inline]
+fn is_slab(old_size: usize, size: usize, align: usize) -> bool {
+    slab::class(old_size, align).is_some() || slab::class(size, align).is_some()
+}
+
+#[inline]
+fn slab_inplace(old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+    // slab objects can only change size within their class
+    if slab::class(old_size, align) == slab::class(size, align) {
+        Ok(())
+    } else {
+        Err(MemoryError::OutOfSpace)
+    }
+}
+
 #[inline]
 pub unsafe fn register(ptr: *mut u8, size: usize) -> Result<usize, MemoryError> {
     MEMORY.register(ptr, size)
@@ -212,31 +473,66 @@ pub unsafe fn forget(ptr: *mut u8, size: usize) -> Result<usize, MemoryError> {
     MEMORY.forget(ptr, size)
 }
 
+#[cfg(not(feature = "debug_heap"))]
 #[inline]
 pub unsafe fn allocate(size: usize, align: usize) -> Result<*mut u8, MemoryError> {
     MEMORY.allocate(size, align)
 }
 
+#[cfg(feature = "debug_heap")]
+#[inline]
+pub unsafe fn allocate(size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+    debug::allocate(size, align)
+}
+
+#[cfg(not(feature = "debug_heap"))]
 #[inline]
 pub unsafe fn release(ptr: *mut u8, size: usize, align: usize) -> Result<usize, MemoryError> {
     MEMORY.release(ptr, size, align)
 }
 
+#[cfg(feature = "debug_heap")]
+#[inline]
+pub unsafe fn release(ptr: *mut u8, size: usize, align: usize) -> Result<usize, MemoryError> {
+    debug::release(ptr, size, align)
+}
+
+#[cfg(not(feature = "debug_heap"))]
 #[inline]
 pub unsafe fn grow(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
     MEMORY.grow(ptr, old_size, size, align)
 }
 
+#[cfg(feature = "debug_heap")]
+#[inline]
+pub unsafe fn grow(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+    debug::grow(ptr, old_size, size, align)
+}
+
+#[cfg(not(feature = "debug_heap"))]
 #[inline]
 pub unsafe fn shrink(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
     MEMORY.shrink(ptr, old_size, size, align)
 }
 
+#[cfg(feature = "debug_heap")]
+#[inline]
+pub unsafe fn shrink(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
+    debug::shrink(ptr, old_size, size, align)
+}
+
+#[cfg(not(feature = "debug_heap"))]
 #[inline]
 pub unsafe fn resize(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
     MEMORY.resize(ptr, old_size, size, align)
 }
 
+#[cfg(feature = "debug_heap")]
+#[inline]
+pub unsafe fn resize(ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+    debug::resize(ptr, old_size, size, align)
+}
+
 #[inline]
 pub fn is_enabled() -> bool {
     MEMORY.is_enabled()
@@ -262,16 +558,75 @@ pub fn exit_reserved() -> bool {
     MEMORY.exit_reserved()
 }
 
+#[cfg(not(feature = "debug_heap"))]
 #[inline]
 pub fn granularity(size: usize, align: usize) -> usize {
     MEMORY.granularity(size, align)
 }
 
+#[cfg(feature = "debug_heap")]
+#[inline]
+pub fn granularity(size: usize, _: usize) -> usize {
+    // the tail guard starts right after the requested size
+    size
+}
+
 #[inline]
 pub fn hint() -> usize {
     MEMORY.hint()
 }
 
+/// Usage of each backend
+#[inline]
+pub fn stats() -> MemoryStats {
+    MemoryStats {
+        reserve: reserve::stats(),
+        simple: simple::stats(),
+        slab: slab::stats()
+    }
+}
+
+/// Usage of each backend, or None if one is in use
+///
+/// Safe to call while panicking in the middle of an allocation.
+#[inline]
+pub fn try_stats() -> Option<MemoryStats> {
+    match (simple::try_stats(), slab::try_stats()) {
+        (Some(simple), Some(slab)) => Some(MemoryStats {
+            reserve: reserve::stats(),
+            simple: simple,
+            slab: slab
+        }),
+        _ => None
+    }
+}
+
+/// Let the heap grow from end up to limit, mapping new memory with hook
+#[inline]
+pub unsafe fn set_grow(end: *mut u8, limit: *mut u8, hook: MapHook) {
+    MEMORY.set_grow(end, limit, hook)
+}
+
+#[inline]
+pub unsafe fn register_frames(base: u64, size: u64) -> u64 {
+    frame::register(base, size)
+}
+
+#[inline]
+pub fn allocate_frame(size: FrameSize) -> Result<u64, MemoryError> {
+    frame::allocate(size)
+}
+
+#[inline]
+pub unsafe fn release_frame(address: u64, size: FrameSize) -> Result<(), MemoryError> {
+    frame::release(address, size)
+}
+
+#[inline]
+pub fn free_frames() -> u64 {
+    frame::free()
+}
+
 #[cfg(not(test))]
 #[no_mangle]
 pub extern "C" fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
@@ -316,7 +671,7 @@ pub extern "C" fn __rust_reallocate(ptr: *mut u8, old_size: usize, size: usize,
             new_ptr as *mut _
         },
         Err(e) => {
-            error!("Failed to reallocate: {}", e);
+            error!("Failed to reallocate pointer {:?}: {}", ptr, e);
             ptr::null_mut()
         }
     }
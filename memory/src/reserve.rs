@@ -7,18 +7,27 @@ use std::cmp;
 
 use constants::*;
 
-use super::MemoryError;
+use super::{MemoryError, Stats};
 
+#[cfg(not(test))]
 extern "C" {
-    // extern because this needs to be 8 bytes aligned
+    // placed and sized by the linker script, see RESERVE_SLAB_BYTES
     static _reserve_slab: u8;
+    static _reserve_slab_end: u8;
 }
 
+// tests run their own slabs over host memory, so the global one is left empty
+#[cfg(test)]
+static _reserve_slab: [u64; 0] = [];
+#[cfg(test)]
+use self::_reserve_slab as _reserve_slab_end;
+
 static RESERVE: Memory = Memory {
     inner: UnsafeCell::new(MemoryInner {
-        hint: U64_BYTES * RESERVE_SLAB_SIZE,
-        slab: unsafe { &_reserve_slab as *const u8 as *mut u64 },
-        map: [0; (RESERVE_SLAB_SIZE + 7) / 8]
+        stats: Stats::new(),
+        map: 0 as *mut u64,
+        slab: 0 as *mut u64,
+        words: 0
     }),
     borrowed: AtomicBool::new(false),
 };
@@ -31,17 +40,32 @@ struct Memory {
 unsafe impl Send for Memory {}
 unsafe impl Sync for Memory {}
 
+// The bitmap sits at the front of the slab, one bit for each u64 after it
 struct MemoryInner {
-    hint: usize,
+    stats: Stats,
+    map: *mut u64,
     slab: *mut u64,
-    map: [u8; (RESERVE_SLAB_SIZE + 7) / 8]
+    words: usize
 }
 
 impl Memory {
+    #[inline]
+    unsafe fn get(&self) -> *mut MemoryInner {
+        let inner = self.inner.get();
+
+        if (*inner).slab.is_null() {
+            // the slab's size is only known once linked
+            ptr::write(inner, MemoryInner::new(&_reserve_slab as *const _ as *mut u64,
+                                               &_reserve_slab_end as *const _ as *mut u64));
+        }
+
+        inner
+    }
+
     #[inline]
     unsafe fn borrow_mut(&self) -> &mut MemoryInner {
         if !self.borrowed.compare_and_swap(false, true, Ordering::SeqCst) {
-            self.inner.get().as_mut().unwrap()
+            self.get().as_mut().unwrap()
         } else {
             panic!("Attempt to multiply access reserve allocator");
         }
@@ -56,67 +80,144 @@ impl Memory {
 
     #[inline]
     fn belongs(&self, ptr: *mut u8) -> bool {
-        unsafe {self.inner.get().as_ref().unwrap().belongs(ptr)}
+        unsafe {self.get().as_ref().unwrap().belongs(ptr)}
     }
 
     #[inline]
     fn hint(&self) -> usize {
-        unsafe {self.inner.get().as_ref().unwrap().hint()}
+        unsafe {self.get().as_ref().unwrap().hint()}
+    }
+
+    #[inline]
+    fn stats(&self) -> Stats {
+        unsafe {self.get().as_ref().unwrap().stats()}
+    }
+}
+
+#[inline]
+fn word_mask(word: usize, from: usize, to: usize) -> u64 {
+    // bits of this word that fall in from..to
+    let low = cmp::max(from, word << 6) - (word << 6);
+    let high = cmp::min(to, (word + 1) << 6) - (word << 6);
+
+    if high - low == 64 {
+        !0
+    } else {
+        ((1 << (high - low)) - 1) << low
     }
 }
 
 impl MemoryInner {
+    unsafe fn new(begin: *mut u64, end: *mut u64) -> MemoryInner {
+        let total = (end as usize - begin as usize) / U64_BYTES;
+
+        // enough map words to cover everything after them
+        let map_words = (total + 64) / 65;
+
+        ptr::write_bytes(begin, 0, map_words);
+
+        MemoryInner {
+            stats: Stats::new(),
+            map: begin,
+            slab: begin.offset(map_words as isize),
+            words: total - map_words
+        }
+    }
+
     #[inline]
     fn belongs(&self, ptr: *mut u8) -> bool {
-        //trace!("{:?}, {:?}, {}", ptr, self.slab.as_ptr(), self.slab.len());
-        (ptr as usize) >= self.slab as usize && (ptr as usize) < unsafe {self.slab.offset(RESERVE_SLAB_SIZE as isize)} as usize
+        (ptr as usize) >= self.slab as usize && (ptr as usize) < self.slab as usize + self.words * U64_BYTES
     }
 
     #[inline]
     fn hint(&self) -> usize {
-        self.hint
+        U64_BYTES * self.words - self.stats.used
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+        let mut run = 0;
+
+        for position in 0..self.words + 1 {
+            if self.is_allocated(position).unwrap_or(true) {
+                // end of a free run, or end of the slab
+                if run > 0 {
+                    stats.free_blocks += 1;
+                    stats.largest_free = cmp::max(stats.largest_free, run * U64_BYTES);
+                }
+
+                run = 0;
+            } else {
+                run += 1;
+            }
+        }
+
+        stats.free = self.hint();
+
+        stats
     }
 
     #[inline]
     fn is_allocated(&self, position: usize) -> Result<bool, MemoryError> {
-        if position >= RESERVE_SLAB_SIZE {
+        if position >= self.words {
             // return here so we can wrap this in a try below
             return Err(MemoryError::OutOfMemory);
         }
 
-        // each map entry has one bit for each u64 in the slab
-        let bit_position = position & 0x7;
-        let index = position >> 3;
+        // bit is set when that u64 is allocated
+        let entry = unsafe {*self.map.offset((position >> 6) as isize)};
 
-        let entry = self.map[index];
-        let bit = (entry >> bit_position) & 0x1;
+        Ok((entry >> (position & 0x3f)) & 0x1 == 1)
+    }
 
-        // bit is set when that u64 is allocated
-        Ok(bit == 1)
+    #[inline]
+    unsafe fn set_bits(&mut self, base: usize, count: usize, allocated: bool) {
+        for word in base >> 6..(base + count + 63) >> 6 {
+            let mask = word_mask(word, base, base + count);
+            let entry = self.map.offset(word as isize);
+
+            if allocated {
+                *entry |= mask;
+            } else {
+                *entry &= !mask;
+            }
+        }
     }
 
     #[inline]
     unsafe fn set_allocated(&mut self, base: usize, count: usize) {
-        for position in base..base + count {
-            // get the bit offset that we want
-            let bit_position = position & 0x7;
-            let index = position >> 3;
-
-            // set the bit
-            self.map[index] |= 0x1 << bit_position;
-        }
+        self.set_bits(base, count, true);
     }
 
     #[inline]
     unsafe fn set_unallocated(&mut self, base: usize, count: usize) {
-        for position in base..base + count {
-            // get the bit offset that we want
-            let bit_position = position & 0x7;
-            let index = position >> 3;
+        self.set_bits(base, count, false);
+    }
+
+    fn find_allocated(&self, from: usize, to: usize) -> Option<usize> {
+        // first allocated position in from..to
+        for word in from >> 6..(to + 63) >> 6 {
+            let set = unsafe {*self.map.offset(word as isize)} & word_mask(word, from, to);
 
-            // set the bit
-            self.map[index] &= !(0x1 << bit_position);
+            if set != 0 {
+                return Some((word << 6) + set.trailing_zeros() as usize);
+            }
         }
+
+        None
+    }
+
+    fn find_unallocated(&self, from: usize) -> usize {
+        // first free position at or after from, or the end of the slab
+        for word in from >> 6..(self.words + 63) >> 6 {
+            let clear = !unsafe {*self.map.offset(word as isize)} & word_mask(word, from, self.words);
+
+            if clear != 0 {
+                return (word << 6) + clear.trailing_zeros() as usize;
+            }
+        }
+
+        self.words
     }
 
     #[inline]
@@ -124,32 +225,104 @@ impl MemoryInner {
         (ptr as usize - self.slab as usize) >> 3
     }
 
+    #[inline]
+    fn align_position(&self, position: usize, align: usize) -> usize {
+        // align the address, not the position, since the slab is only 8 byte aligned
+        let address = self.slab as usize + position * U64_BYTES;
+
+        (::constants::align(address, cmp::max(align, U64_BYTES)) - self.slab as usize) >> 3
+    }
+
     unsafe fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
         let count = get_count(size);
 
-        let mut base = 0usize;
+        let pointer = try!(self.place(count, align));
 
-        for position in 0..RESERVE_SLAB_SIZE {
-            if try!(self.is_allocated(position)) {
-                // advance the base if we're reading an already allocated slot
+        self.stats.record_allocate(count * U64_BYTES);
 
-                // advance to the next correctly-aligned position
+        Ok(pointer)
+    }
+
+    #[inline]
+    fn free_bits(&self, word: usize) -> u64 {
+        if word << 6 >= self.words {
+            0
+        } else {
+            !unsafe {*self.map.offset(word as isize)} & word_mask(word, 0, self.words)
+        }
+    }
+
+    fn find_short(&self, count: usize, align: usize) -> Option<usize> {
+        // positions that are aligned, the same in every word since step divides 64
+        let step = cmp::max(align / U64_BYTES, 1);
+        let mut aligned = 0u64;
+        let mut bit = self.align_position(0, align) % step;
 
-                // alignment is right-shifted by three because position is
-                // address / 8, also avoid aligning to zero because overflow
-                base = ::constants::align(position, cmp::max(align >> 3, 1));
-            } else if base < position && position - base >= count {
-                // we've found our slot
-                self.set_allocated(base, count);
+        while bit < 64 {
+            aligned |= 1 << bit;
+            bit += step;
+        }
 
-                let pointer = self.slab.offset(base as isize * 8);
+        for word in 0..(self.words + 63) >> 6 {
+            let free = self.free_bits(word);
 
-                return Ok(pointer as *mut u8);
+            if free == 0 {
+                continue;
+            }
+
+            // a run of up to 64 starting in this word ends by the next one
+            let next = self.free_bits(word + 1);
+            let mut starts = free & aligned;
+
+            for shift in 1..count {
+                if starts == 0 {
+                    break;
+                }
+
+                starts &= (free >> shift) | (next << (64 - shift));
+            }
+
+            if starts != 0 {
+                return Some((word << 6) + starts.trailing_zeros() as usize);
             }
         }
 
-        // oom
-        Err(MemoryError::OutOfMemory)
+        None
+    }
+
+    fn find_long(&self, count: usize, align: usize) -> Option<usize> {
+        let mut base = self.align_position(self.find_unallocated(0), align);
+
+        while base + count <= self.words {
+            if let Some(position) = self.find_allocated(base, base + count) {
+                // skip past the allocated run and try again
+                base = self.align_position(self.find_unallocated(position + 1), align);
+            } else {
+                return Some(base);
+            }
+        }
+
+        None
+    }
+
+    unsafe fn place(&mut self, count: usize, align: usize) -> Result<*mut u8, MemoryError> {
+        let found = if count <= 64 && align <= 64 * U64_BYTES {
+            // check every start in a word at once
+            self.find_short(count, align)
+        } else {
+            // long runs, so skipping from one allocated run to the next is quick
+            self.find_long(count, align)
+        };
+
+        if let Some(base) = found {
+            // we've found our slot
+            self.set_allocated(base, count);
+
+            Ok(self.slab.offset(base as isize) as *mut u8)
+        } else {
+            // oom
+            Err(MemoryError::OutOfMemory)
+        }
     }
 
     unsafe fn release(&mut self, ptr: *mut u8, size: usize, _: usize) -> Result<usize, MemoryError> {
@@ -158,6 +331,8 @@ impl MemoryInner {
 
         self.set_unallocated(base, count);
 
+        self.stats.record_release(count * U64_BYTES);
+
         // successfully freed memory
         Ok(size)
     }
@@ -175,18 +350,13 @@ impl MemoryInner {
 
         self.set_unallocated(base + count, old_count - count);
 
+        self.stats.record_shrink((old_count - count) * U64_BYTES);
+
         Ok(())
     }
 
     fn can_grow_inplace(&self, base: usize, old_count: usize, count: usize) -> bool {
-        for position in base + old_count..base + count {
-            if self.is_allocated(position).unwrap_or(true) {
-                // this position is allocated or invalid
-                return false;
-            }
-        }
-
-        true
+        base + count <= self.words && self.find_allocated(base + old_count, base + count).is_none()
     }
 
     unsafe fn grow(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
@@ -204,6 +374,8 @@ impl MemoryInner {
             // just set the new stuff as allocated and done
             self.set_allocated(base + old_count, count - old_count);
 
+            self.stats.record_grow((count - old_count) * U64_BYTES);
+
             Ok(())
         } else {
             // no space to grow this allocation
@@ -213,9 +385,9 @@ impl MemoryInner {
 
     unsafe fn resize(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
         // try to resize in-place
-        if size > old_size && self.grow(ptr, old_size, size, align).is_ok() {
-            return Ok(ptr);
-        } else if self.shrink(ptr, old_size, size, align).is_ok() {
+        if size <= old_size {
+            return self.shrink(ptr, old_size, size, align).map(|_| ptr);
+        } else if self.grow(ptr, old_size, size, align).is_ok() {
             return Ok(ptr);
         }
 
@@ -225,9 +397,12 @@ impl MemoryInner {
 
         self.set_unallocated(base, old_count);
 
-        if let Ok(new_ptr) = self.allocate(size, align) {
+        if let Ok(new_ptr) = self.place(get_count(size), align) {
             ptr::copy(ptr, new_ptr, old_size);
 
+            self.stats.record_shrink(old_count * U64_BYTES);
+            self.stats.record_grow(get_count(size) * U64_BYTES);
+
             Ok(new_ptr)
         } else {
             // roll back
@@ -289,7 +464,208 @@ pub fn hint() -> usize {
     RESERVE.hint()
 }
 
+#[inline]
+pub fn stats() -> Stats {
+    RESERVE.stats()
+}
+
 #[inline]
 pub fn granularity(size: usize, _: usize) -> usize {
     ((size + 7) / 8) * 8
 }
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use constants::*;
+
+    use test::Bencher;
+
+    use harness::{Model, Rng};
+
+    use super::MemoryInner;
+    use super::super::MemoryError;
+
+    // default slab size from the linker script
+    const SLAB_WORDS: usize = 0x1000;
+
+    heap_adapter!(MemoryInner);
+
+    fn with_slab<F: FnOnce(&mut MemoryInner, *mut u8, usize)>(test: F) {
+        let mut buffer: Vec<u64> = vec![0; SLAB_WORDS];
+        let mut inner = unsafe {
+            MemoryInner::new(buffer.as_mut_ptr(), buffer.as_mut_ptr().offset(SLAB_WORDS as isize))
+        };
+
+        let base = inner.slab as *mut u8;
+        let size = inner.words * U64_BYTES;
+
+        test(&mut inner, base, size);
+    }
+
+    // the bit-at-a-time search this replaced, kept to compare against
+    unsafe fn place_linear(inner: &mut MemoryInner, count: usize, align: usize) -> Result<*mut u8, MemoryError> {
+        let mut base = inner.align_position(0, align);
+
+        for position in 0..inner.words {
+            if try!(inner.is_allocated(position)) {
+                base = inner.align_position(position + 1, align);
+            } else if base <= position && position + 1 - base >= count {
+                inner.set_allocated(base, count);
+
+                return Ok(inner.slab.offset(base as isize) as *mut u8);
+            }
+        }
+
+        Err(MemoryError::OutOfMemory)
+    }
+
+    fn fragment(inner: &mut MemoryInner) {
+        unsafe {
+            // fill most of the slab, then free every other word
+            let mut ptrs = vec![];
+
+            for _ in 0..inner.words * 3 / 4 {
+                ptrs.push(inner.allocate(8, 8).unwrap());
+            }
+
+            for (idx, ptr) in ptrs.into_iter().enumerate() {
+                if idx % 2 == 0 {
+                    assert!(inner.release(ptr, 8, 8).is_ok());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_layout() {
+        with_slab(|inner, _, size| {
+            let map_size = ((inner.words + 63) / 64) * U64_BYTES;
+
+            // the map covers the slab, and both fit in the buffer
+            assert!(inner.slab as usize - inner.map as usize >= map_size);
+            assert!(inner.slab as usize - inner.map as usize + size <= SLAB_WORDS * U64_BYTES);
+        });
+    }
+
+    #[test]
+    fn test_adjacent() {
+        with_slab(|inner, base, _| unsafe {
+            let a = inner.allocate(8, 8).unwrap();
+            let b = inner.allocate(8, 8).unwrap();
+
+            assert_eq!(a, base);
+            assert_eq!(b, base.offset(8));
+        });
+    }
+
+    #[test]
+    fn test_fill() {
+        with_slab(|inner, _, size| unsafe {
+            let a = inner.allocate(size, 8).unwrap();
+
+            assert_eq!(inner.allocate(8, 8), Err(MemoryError::OutOfMemory));
+            assert!(inner.release(a, size, 8).is_ok());
+            assert_eq!(inner.hint(), size);
+        });
+    }
+
+    #[test]
+    fn test_across_words() {
+        with_slab(|inner, base, _| unsafe {
+            // leave a gap that straddles a map word
+            let a = inner.allocate(60 * 8, 8).unwrap();
+            let b = inner.allocate(8 * 8, 8).unwrap();
+            let _ = inner.allocate(8, 8).unwrap();
+
+            assert!(inner.release(b, 8 * 8, 8).is_ok());
+
+            assert_eq!(inner.allocate(9 * 8, 8).unwrap(), base.offset(69 * 8));
+            assert_eq!(inner.allocate(8 * 8, 8).unwrap(), b);
+            assert!(inner.release(a, 60 * 8, 8).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_matches_linear() {
+        for seed in 0..4 {
+            with_slab(|inner, _, _| unsafe {
+                fragment(inner);
+
+                let mut rng = Rng::new(seed);
+
+                for _ in 0..0x100 {
+                    let count = 1 + rng.below(80);
+                    let align = 1 << rng.below(11);
+
+                    let found = inner.place(count, align);
+
+                    if let Ok(ptr) = found {
+                        let position = inner.get_position(ptr);
+                        inner.set_unallocated(position, count);
+                    }
+
+                    let expected = place_linear(inner, count, align);
+
+                    if let Ok(ptr) = expected {
+                        let position = inner.get_position(ptr);
+                        inner.set_unallocated(position, count);
+                    }
+
+                    assert_eq!(found, expected);
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_resize_grows() {
+        with_slab(|inner, _, _| unsafe {
+            let a = inner.allocate(8, 8).unwrap();
+            let _ = inner.allocate(8, 8).unwrap();
+
+            *(a as *mut u64) = 0xdeadbeef;
+
+            // a is boxed in, so growing it has to move it
+            let moved = inner.resize(a, 8, 16, 8).unwrap();
+
+            assert!(moved != a);
+            assert_eq!(*(moved as *mut u64), 0xdeadbeef);
+        });
+    }
+
+    #[test]
+    fn test_random() {
+        for seed in 0..16 {
+            with_slab(|inner, base, size| unsafe {
+                Model::new(inner, base, size, seed).run(0x1000);
+            });
+        }
+    }
+
+    fn bench_place<F>(bench: &mut Bencher, mut place: F)
+        where F: FnMut(&mut MemoryInner, usize, usize) -> Result<*mut u8, MemoryError> {
+        with_slab(|inner, _, _| {
+            fragment(inner);
+
+            bench.iter(|| unsafe {
+                // has to go past the fragmented part
+                let ptr = place(inner, 4, 8).unwrap();
+                let position = inner.get_position(ptr);
+
+                inner.set_unallocated(position, 4);
+            });
+        });
+    }
+
+    #[bench]
+    fn bench_place_words(bench: &mut Bencher) {
+        bench_place(bench, |inner, count, align| unsafe { inner.place(count, align) });
+    }
+
+    #[bench]
+    fn bench_place_linear(bench: &mut Bencher) {
+        bench_place(bench, |inner, count, align| unsafe { place_linear(inner, count, align) });
+    }
+}
@@ -7,10 +7,13 @@ use spin::Mutex;
 
 use constants;
 
-use super::MemoryError;
+use super::{MemoryError, Stats};
 
 static MEMORY: Mutex<Manager> = Mutex::new(Manager::new());
 
+// every block boundary is a multiple of this, so any leftover piece can hold a Block
+const GRANULE: usize = 32;
+
 #[derive(Debug, Clone, Copy)]
 struct Block {
     base: *mut u8,
@@ -21,7 +24,8 @@ struct Block {
 
 struct Manager {
     hint: usize,
-    free: *mut Block
+    free: *mut Block,
+    stats: Stats
 }
 
 // Manager is an internally-managed singleton
@@ -33,7 +37,8 @@ impl Manager {
     const fn new() -> Manager {
         Manager {
             hint: 0,
-            free: ptr::null_mut()
+            free: ptr::null_mut(),
+            stats: Stats::new()
         }
     }
 
@@ -67,16 +72,6 @@ impl Manager {
     }
 
     unsafe fn insert_between(&mut self, before: *mut Block, after: *mut Block, new: *mut Block) {
-        if self.free.is_null() {
-            self.free = new;
-
-            let free = self.free.as_mut().unwrap();
-            free.last = ptr::null_mut();
-            free.next = ptr::null_mut();
-
-            return;
-        }
-
         debug_assert!(!new.is_null());
 
         if let Some(before) = before.as_mut() {
@@ -95,75 +90,123 @@ impl Manager {
         }
     }
 
+    unsafe fn unlink(&mut self, block: *mut Block) {
+        let block = block.as_mut().unwrap();
+
+        if let Some(last) = block.last.as_mut() {
+            last.next = block.next;
+        } else {
+            self.free = block.next;
+        }
+
+        if let Some(next) = block.next.as_mut() {
+            next.last = block.last;
+        }
+    }
+
+    unsafe fn create_block(base: *mut u8, end: *mut u8) -> *mut Block {
+        let block = base as *mut Block;
+
+        ptr::write(block, Block {
+            base: base,
+            end: end,
+            next: ptr::null_mut(),
+            last: ptr::null_mut()
+        });
+
+        block
+    }
+
     unsafe fn register(&mut self, ptr: *mut u8, size: usize) -> Result<usize, MemoryError> {
-        // find the blocks around the given area
-        let (before, after) = self.find_around(ptr);
+        // only use whole granules
+        let base = constants::align(ptr as usize, GRANULE) as *mut u8;
+        let end = constants::align_back(ptr as usize + size, GRANULE) as *mut u8;
+
+        if end as usize <= base as usize {
+            return Err(MemoryError::TinyBlock);
+        }
 
-        // TODO check_invariants
+        // find the blocks around the given area
+        let (before, after) = self.find_around(base);
 
-        let new_block = (ptr as *mut Block).as_mut().unwrap();
+        if let Some(before) = before.as_ref() {
+            if before.end as usize > base as usize {
+                return Err(MemoryError::Overlap);
+            }
+        }
 
-        new_block.base = ptr;
-        new_block.end = ptr.offset(size as isize);
+        if let Some(after) = after.as_ref() {
+            if (after.base as usize) < end as usize {
+                return Err(MemoryError::Overlap);
+            }
+        }
 
-        self.insert_between(before, after, new_block);
+        // coalesce with the neighbours we touch
+        let merge_before = before.as_ref().map(|block| block.end == base).unwrap_or(false);
+        let merge_after = after.as_ref().map(|block| block.base == end).unwrap_or(false);
+
+        if merge_before && merge_after {
+            before.as_mut().unwrap().end = after.as_ref().unwrap().end;
+            self.unlink(after);
+        } else if merge_before {
+            before.as_mut().unwrap().end = end;
+        } else if merge_after {
+            let after_end = after.as_ref().unwrap().end;
+            let after_next = after.as_ref().unwrap().next;
+
+            self.unlink(after);
+            self.insert_between(before, after_next, Manager::create_block(base, after_end));
+        } else {
+            self.insert_between(before, after, Manager::create_block(base, end));
+        }
 
-        // TODO coalesce
+        self.hint += end as usize - base as usize;
 
-        self.hint += size;
+        self.check_invariants();
 
-        Ok(size)
+        Ok(end as usize - base as usize)
     }
 
     unsafe fn forget(&mut self, ptr: *mut u8, size: usize) -> Result<usize, MemoryError> {
-        let (before, _) = self.find_around(ptr);
+        let end = ptr.offset(size as isize);
 
-        let (last, after) = self.find_around(ptr.offset(size as isize));
+        // the region has to lie entirely within one free block
+        let (block, after) = self.find_around(ptr);
 
-        if last.is_null() {
-            // do nothing
+        if block.is_null() || (block.as_ref().unwrap().end as usize) < end as usize {
             return Err(MemoryError::NoPlace);
         }
 
-        if (last.as_ref().unwrap().end as usize) < ptr as usize + size {
-            // no block overlaps our region
-            let before = before.as_mut().unwrap();
-            let after = after.as_mut().unwrap();
-
-            before.end = ptr;
-            before.next = after;
-            after.last = before;
-        } else {
-            // save values before they might get clobbered
-            let before = before.as_mut().unwrap();
-
-            let maybe_last = before.last;
-            let end = last.as_ref().unwrap().end;
-
-            // truncate before and last and hook them up
-            let new_block = (ptr.offset(size as isize) as *mut Block).as_mut().unwrap();
+        // save values before they might get clobbered
+        let block_base = block.as_ref().unwrap().base;
+        let block_end = block.as_ref().unwrap().end;
+        let block_last = block.as_ref().unwrap().last;
 
-            new_block.base = ptr.offset(size as isize);
-            new_block.end = end;
+        if block_base == ptr {
+            self.unlink(block);
 
-            if before as *mut Block as usize != ptr as usize {
-                before.end = ptr;
+            if block_end != end {
+                // keep the piece after the region
+                self.insert_between(block_last, after, Manager::create_block(end, block_end));
+            }
+        } else {
+            // truncate block to the piece before the region
+            block.as_mut().unwrap().end = ptr;
 
-                self.insert_between(before, after, new_block);
-            } else {
-                self.insert_between(maybe_last, after, new_block);
+            if block_end != end {
+                // keep the piece after the region
+                self.insert_between(block, after, Manager::create_block(end, block_end));
             }
         }
 
-        // TODO maybe improve this
         self.hint -= size;
 
+        self.check_invariants();
+
         Ok(size)
     }
 
-    unsafe fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
-        let size = granularity(size, align);
-
+    unsafe fn find_fit(&self, size: usize, align: usize) -> Option<*mut u8> {
         let mut pointer = self.free;
 
         while !pointer.is_null() {
@@ -171,24 +214,37 @@ impl Manager {
             let block_end = pointer.as_ref().unwrap().end as usize;
 
             if block_end > aligned_base && block_end - aligned_base >= size {
-                self.hint -= size;
-                return self.forget(constants::align(pointer as usize, align) as *mut u8, size)
-                    .map(|_| constants::align(pointer as usize, align) as *mut u8)
+                return Some(aligned_base as *mut u8);
             }
 
             pointer = pointer.as_ref().unwrap().next;
         }
 
-        Err(MemoryError::OutOfMemory)
+        None
+    }
+
+    unsafe fn allocate(&mut self, size: usize, align: usize) -> Result<*mut u8, MemoryError> {
+        let size = granularity(size, align);
+        let align = cmp::max(align, GRANULE);
+
+        if let Some(ptr) = self.find_fit(size, align) {
+            try!(self.forget(ptr, size));
+
+            self.stats.record_allocate(size);
+
+            Ok(ptr)
+        } else {
+            Err(MemoryError::OutOfMemory)
+        }
     }
 
     unsafe fn release(&mut self, ptr: *mut u8, size: usize, align: usize) -> Result<usize, MemoryError> {
         let size = granularity(size, align);
         let registered_size = try!(self.register(ptr, size));
-        trace!("{}", registered_size);
 
         if registered_size == size {
-            self.hint += size;
+            self.stats.record_release(size);
+
             Ok(size)
         } else {
             Err(MemoryError::Overlap)
@@ -199,37 +255,45 @@ impl Manager {
         let size = granularity(size, align);
         let old_size = granularity(old_size, align);
 
-        let (before, _) = self.find_around(ptr.offset(old_size as isize));
+        if size <= old_size {
+            return Ok(());
+        }
+
+        // there has to be a free block right after this allocation
+        let (after, _) = self.find_around(ptr.offset(old_size as isize));
 
-        if before as usize != ptr as usize {
+        if after as usize != ptr as usize + old_size {
             return Err(MemoryError::OutOfSpace)
         }
 
-        let before = before.as_mut().unwrap();
-
-        if before.end < ptr.offset(size as isize) {
+        if (after.as_ref().unwrap().end as usize) < ptr as usize + size {
             return Err(MemoryError::OutOfSpace)
         }
 
-        self.forget(ptr.offset(old_size as isize), size - old_size).map(|_| ())
+        try!(self.forget(ptr.offset(old_size as isize), size - old_size));
+
+        self.stats.record_grow(size - old_size);
+
+        Ok(())
     }
 
-    unsafe fn shrink(&mut self, ptr: *mut u8, old_size: usize, mut size: usize, align: usize) -> Result<(), MemoryError> {
+    unsafe fn shrink(&mut self, ptr: *mut u8, old_size: usize, size: usize, align: usize) -> Result<(), MemoryError> {
         // adjust size
-        size = granularity(size, align);
-        
-        if size >= granularity(old_size, align) {
+        let size = granularity(size, align);
+        let old_size = granularity(old_size, align);
+
+        if size >= old_size {
             return Ok(());
         }
 
-        let difference = size - old_size;
-        let registered_size = try!(self.register((ptr as *mut u8).offset(size as isize) as *mut u8, difference));
-
-        trace!("f: {}, {}", difference, registered_size);
+        let difference = old_size - size;
+        let registered_size = try!(self.register(ptr.offset(size as isize), difference));
 
         if registered_size != difference {
             return Err(MemoryError::Overlap);
         } else {
+            self.stats.record_shrink(difference);
+
             return Ok(());
         }
     }
@@ -239,63 +303,128 @@ impl Manager {
 
         if (ptr as usize) & (align - 1) == 0 {
             // pointer is already aligned
-            trace!("Trying inplace");
             if granularity(size, align) > granularity(old_size, align) {
-                trace!("Growing");
                 if self.grow(ptr, old_size, size, align).is_ok() {
                     return Ok(ptr);
                 }
             } else if granularity(size, align) < granularity(old_size, align) {
-                trace!("Shrinking");
                 if self.shrink(ptr, old_size, size, align).is_ok() {
                     return Ok(ptr);
                 }
             } else {
                 // pointer is aligned and the right size, do nothing
-                trace!("Doing nothing");
                 return Ok(ptr);
             }
         }
 
+        let old_size = granularity(old_size, align);
+        let size = granularity(size, align);
+        let copy_size = cmp::min(old_size, size);
+
         // keep data that might be clobbered by release
-        let diff_size: usize = cmp::min(mem::size_of::<Block>(), size);
+        let diff_size: usize = cmp::min(mem::size_of::<Block>(), copy_size);
         let mut store: Block = mem::zeroed();
-        trace!("Copying: 0x{:x}", diff_size);
         ptr::copy(ptr as *mut u8, (&mut store as *mut _ as *mut u8), diff_size);
 
-        trace!("0x{:x}", diff_size);
-
-        if let Err(e) = self.release(ptr, old_size, align) {
-            error!("Failed to free pointer on resize: {}", e);
-            return Err(e);
+        match self.register(ptr, old_size) {
+            Ok(registered_size) if registered_size == old_size => {},
+            Ok(_) => {
+                error!("Failed to free pointer on resize: {}", MemoryError::Overlap);
+                return Err(MemoryError::Overlap);
+            },
+            Err(e) => {
+                error!("Failed to free pointer on resize: {}", e);
+                return Err(e);
+            }
         }
 
-        if let Ok(new_ptr) = self.allocate(size, align) {
-            trace!("{:?}, {:?}, 0x{:x}", ptr, new_ptr, old_size);
+        let new_ptr = match self.find_fit(size, cmp::max(align, GRANULE)) {
+            Some(new_ptr) => new_ptr,
+            None => {
+                // roll back
+                assert!(self.forget(ptr, old_size).is_ok());
+                ptr::copy((&mut store as *mut _ as *mut u8), ptr, diff_size);
+
+                return Err(MemoryError::OutOfMemory);
+            }
+        };
+
+        // the block after the new allocation might be written over our old data
+        let tail = new_ptr as usize + size;
+        let tail_offset = tail.wrapping_sub(ptr as usize);
+        let tail_size = if tail_offset < copy_size {
+            cmp::min(mem::size_of::<Block>(), copy_size - tail_offset)
+        } else {
+            0
+        };
+        let mut tail_store: Block = mem::zeroed();
+        ptr::copy(tail as *mut u8, (&mut tail_store as *mut _ as *mut u8), tail_size);
 
-            // copy the data from the old pointer
-            ptr::copy(ptr as *mut u8, new_ptr as *mut u8, old_size);
+        assert!(self.forget(new_ptr, size).is_ok());
 
-            trace!("{:?}, 0x{:x}", (&mut store as *mut _ as *mut u8), diff_size);
+        // copy the data from the old pointer
+        ptr::copy(ptr as *mut u8, new_ptr as *mut u8, copy_size);
 
-            // some bytes at the beginning might have been clobbered
-            // copy data that might have been clobbered
-            ptr::copy((&mut store as *mut _ as *mut u8), new_ptr as *mut u8, diff_size);
+        // some bytes might have been clobbered, put them back
+        ptr::copy((&mut store as *mut _ as *mut u8), new_ptr as *mut u8, diff_size);
+        ptr::copy((&mut tail_store as *mut _ as *mut u8), new_ptr.offset(tail_offset as isize), tail_size);
 
-            // succeeded!
-            Ok(new_ptr)
-        } else {
-            // roll back
-            if old_size > size {
-                // this should really rarely happen
-                assert!(self.register(ptr.offset(size as isize), old_size - size).is_ok());
-            } else {
-                assert!(self.forget(ptr.offset(old_size as isize), size - old_size).is_ok());
+        self.stats.record_shrink(old_size);
+        self.stats.record_grow(size);
+
+        // succeeded!
+        Ok(new_ptr)
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+        let mut pointer = self.free;
+
+        while let Some(block) = unsafe { pointer.as_ref() } {
+            let size = block.end as usize - block.base as usize;
+
+            stats.free_blocks += 1;
+            stats.largest_free = cmp::max(stats.largest_free, size);
+
+            pointer = block.next;
+        }
+
+        stats.free = self.hint;
+
+        stats
+    }
+
+    #[cfg(debug_assertions)]
+    unsafe fn check_invariants(&self) {
+        let mut pointer = self.free;
+        let mut last: *mut Block = ptr::null_mut();
+        let mut total = 0;
+
+        while let Some(block) = pointer.as_ref() {
+            assert!(block.base == pointer as *mut u8, "Block at {:?} had base {:?}", pointer, block.base);
+            assert!(block.end as usize > block.base as usize, "Block at {:?} was empty", pointer);
+            assert!(block.last == last, "Block at {:?} had the wrong back link", pointer);
+            assert!(constants::is_aligned(block.base as usize, GRANULE)
+                    && constants::is_aligned(block.end as usize, GRANULE),
+                    "Block at {:?} was not aligned", pointer);
+
+            if let Some(last) = last.as_ref() {
+                assert!((last.end as usize) < block.base as usize,
+                        "Block at {:?} overlapped or was not merged with the block before it", pointer);
             }
-            
-            // failed
-            Err(MemoryError::OutOfMemory)
+
+            total += block.end as usize - block.base as usize;
+            last = pointer;
+            pointer = block.next;
         }
+
+        assert!(total == self.hint, "Free list held 0x{:x} bytes, but hint was 0x{:x}", total, self.hint);
+    }
+
+    #[cfg(not(debug_assertions))]
+    #[inline]
+    unsafe fn check_invariants(&self) {
+        // only checked in debug builds
     }
 }
 
@@ -304,6 +433,16 @@ pub fn hint() -> usize {
     MEMORY.lock().hint()
 }
 
+#[inline]
+pub fn stats() -> Stats {
+    MEMORY.lock().stats()
+}
+
+#[inline]
+pub fn try_stats() -> Option<Stats> {
+    MEMORY.try_lock().map(|memory| memory.stats())
+}
+
 #[inline]
 pub unsafe fn register(ptr: *mut u8, size: usize) -> Result<usize, MemoryError> {
     MEMORY.lock().register(ptr, size)
@@ -341,9 +480,97 @@ pub unsafe fn resize(ptr: *mut u8, old_size: usize, size: usize, align: usize) -
 
 #[inline]
 pub fn granularity(size: usize, _: usize) -> usize {
-    if size < 32 {
-        32
-    } else {
-        size
+    // whole granules, never smaller than one
+    constants::align(cmp::max(size, GRANULE), GRANULE)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use harness::Model;
+
+    use super::{Manager, GRANULE};
+    use super::super::MemoryError;
+
+    const HEAP_SIZE: usize = 0x10000;
+
+    heap_adapter!(Manager);
+
+    fn with_heap<F: FnOnce(&mut Manager, *mut u8, usize)>(test: F) {
+        let mut buffer: Vec<u8> = vec![0; HEAP_SIZE + GRANULE];
+        let mut manager = Manager::new();
+
+        let base = ::constants::align(buffer.as_mut_ptr() as usize, GRANULE) as *mut u8;
+
+        unsafe {
+            assert_eq!(manager.register(base, HEAP_SIZE), Ok(HEAP_SIZE));
+        }
+
+        test(&mut manager, base, HEAP_SIZE);
+    }
+
+    #[test]
+    fn test_coalesce() {
+        with_heap(|manager, _, _| unsafe {
+            let a = manager.allocate(0x100, 1).unwrap();
+            let b = manager.allocate(0x100, 1).unwrap();
+            let c = manager.allocate(0x100, 1).unwrap();
+
+            // release out of order so both neighbours get merged
+            assert!(manager.release(a, 0x100, 1).is_ok());
+            assert!(manager.release(c, 0x100, 1).is_ok());
+            assert!(manager.release(b, 0x100, 1).is_ok());
+
+            let stats = manager.stats();
+
+            assert_eq!(stats.free_blocks, 1);
+            assert_eq!(stats.largest_free, HEAP_SIZE);
+            assert_eq!(stats.used, 0);
+        });
+    }
+
+    #[test]
+    fn test_double_release() {
+        with_heap(|manager, _, _| unsafe {
+            let a = manager.allocate(0x100, 1).unwrap();
+            let _ = manager.allocate(0x100, 1).unwrap();
+
+            assert!(manager.release(a, 0x100, 1).is_ok());
+            assert_eq!(manager.release(a, 0x100, 1), Err(MemoryError::Overlap));
+        });
+    }
+
+    #[test]
+    fn test_resize_overlapping() {
+        with_heap(|manager, _, _| unsafe {
+            let a = manager.allocate(0x40, 1).unwrap();
+            let b = manager.allocate(0x80, 1).unwrap();
+            let _ = manager.allocate(0x40, 1).unwrap();
+
+            for offset in 0..0x80 {
+                *b.offset(offset) = offset as u8;
+            }
+
+            // b can't grow in place, so it moves down over its old self
+            assert!(manager.release(a, 0x40, 1).is_ok());
+
+            let moved = manager.resize(b, 0x80, 0xa0, 1).unwrap();
+
+            assert_eq!(moved, a);
+
+            for offset in 0..0x80 {
+                assert_eq!(*moved.offset(offset), offset as u8);
+            }
+        });
+    }
+
+    #[test]
+    fn test_random() {
+        for seed in 0..16 {
+            with_heap(|manager, base, size| unsafe {
+                Model::new(manager, base, size, seed).run(0x1000);
+            });
+        }
     }
 }
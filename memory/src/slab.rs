@@ -0,0 +1,318 @@
+use std::ptr;
+use std::cmp;
+
+use spin::Mutex;
+
+use super::{MemoryError, Stats};
+
+static SLABS: Mutex<Manager> = Mutex::new(Manager::new());
+
+/// Size of the pages that slabs are cut from
+pub const PAGE_SIZE: usize = 0x1000;
+
+// size classes go from 16 bytes up to 2 kilobytes
+const MIN_SHIFT: usize = 4;
+const MAX_SHIFT: usize = 11;
+const CLASSES: usize = MAX_SHIFT - MIN_SHIFT + 1;
+
+// header kept in every free object
+struct Object {
+    next: *mut Object
+}
+
+// One free list per size class
+//
+// Objects are naturally aligned to their size within page-aligned pages, so
+// any request with align no larger than its class is satisfied. Pages stay
+// with the class they were first cut for.
+struct Manager {
+    hint: usize,
+    free: [*mut Object; CLASSES],
+    stats: Stats
+}
+
+// Manager is an internally-managed singleton
+unsafe impl Sync for Manager {}
+unsafe impl Send for Manager {}
+
+impl Manager {
+    #[inline]
+    const fn new() -> Manager {
+        Manager {
+            hint: 0,
+            free: [ptr::null_mut(); CLASSES],
+            stats: Stats::new()
+        }
+    }
+
+    #[inline]
+    fn hint(&self) -> usize {
+        self.hint
+    }
+
+    unsafe fn allocate(&mut self, class: usize) -> Result<*mut u8, MemoryError> {
+        let object = self.pop(class);
+
+        if !object.is_null() {
+            self.stats.record_allocate(class_size(class));
+
+            Ok(object as *mut u8)
+        } else {
+            Err(MemoryError::OutOfMemory)
+        }
+    }
+
+    unsafe fn release(&mut self, ptr: *mut u8, class: usize) {
+        self.stats.record_release(class_size(class));
+
+        self.push(ptr, class);
+    }
+
+    unsafe fn pop(&mut self, class: usize) -> *mut Object {
+        let object = self.free[class];
+
+        if let Some(object_ref) = object.as_ref() {
+            self.free[class] = object_ref.next;
+            self.hint -= class_size(class);
+        }
+
+        object
+    }
+
+    unsafe fn push(&mut self, ptr: *mut u8, class: usize) {
+        let object = ptr as *mut Object;
+
+        ptr::write(object, Object {
+            next: self.free[class]
+        });
+
+        self.free[class] = object;
+        self.hint += class_size(class);
+    }
+
+    unsafe fn refill(&mut self, page: *mut u8, class: usize) {
+        let size = class_size(class);
+
+        // release back to front so objects get handed out in address order
+        for idx in (0..PAGE_SIZE / size).rev() {
+            self.push(page.offset((idx * size) as isize), class);
+        }
+    }
+
+    fn stats(&self) -> Stats {
+        let mut stats = self.stats;
+
+        for class in 0..CLASSES {
+            let mut object = self.free[class];
+
+            while let Some(object_ref) = unsafe { object.as_ref() } {
+                stats.free_blocks += 1;
+                stats.largest_free = class_size(class);
+
+                object = object_ref.next;
+            }
+        }
+
+        stats.free = self.hint;
+
+        stats
+    }
+}
+
+/// The size class serving this request, if it is small enough
+#[inline]
+pub fn class(size: usize, align: usize) -> Option<usize> {
+    let size = cmp::max(cmp::max(size, align), 1 << MIN_SHIFT);
+
+    if size > 1 << MAX_SHIFT {
+        None
+    } else {
+        Some(size.next_power_of_two().trailing_zeros() as usize - MIN_SHIFT)
+    }
+}
+
+#[inline]
+pub fn class_size(class: usize) -> usize {
+    1 << (class + MIN_SHIFT)
+}
+
+#[inline]
+pub fn hint() -> usize {
+    SLABS.lock().hint()
+}
+
+#[inline]
+pub fn stats() -> Stats {
+    SLABS.lock().stats()
+}
+
+#[inline]
+pub fn try_stats() -> Option<Stats> {
+    SLABS.try_lock().map(|slabs| slabs.stats())
+}
+
+#[inline]
+pub unsafe fn allocate(class: usize) -> Result<*mut u8, MemoryError> {
+    SLABS.lock().allocate(class)
+}
+
+#[inline]
+pub unsafe fn release(ptr: *mut u8, class: usize) {
+    SLABS.lock().release(ptr, class)
+}
+
+/// Cut a fresh page-aligned page into objects of the given class
+#[inline]
+pub unsafe fn refill(page: *mut u8, class: usize) {
+    SLABS.lock().refill(page, class)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::vec::Vec;
+
+    use constants;
+
+    use harness::{Model, Global, with_global};
+
+    use super::{Manager, PAGE_SIZE, class, class_size};
+    use super::super::MemoryError;
+
+    fn with_page<F: FnOnce(&mut Manager, *mut u8)>(test: F) {
+        let mut buffer: Vec<u8> = vec![0; 3 * PAGE_SIZE];
+        let mut manager = Manager::new();
+
+        let page = constants::align(buffer.as_mut_ptr() as usize, PAGE_SIZE) as *mut u8;
+
+        test(&mut manager, page);
+    }
+
+    #[test]
+    fn test_class() {
+        assert_eq!(class(1, 1), Some(0));
+        assert_eq!(class(16, 1), Some(0));
+        assert_eq!(class(17, 1), Some(1));
+        assert_eq!(class(0x800, 1), Some(7));
+        assert_eq!(class(0x801, 1), None);
+
+        // alignment larger than the size picks a class of the alignment
+        assert_eq!(class(8, 0x40), Some(2));
+        assert_eq!(class(0x10, 0x800), Some(7));
+        assert_eq!(class(0x10, 0x1000), None);
+
+        assert_eq!(class_size(0), 16);
+        assert_eq!(class_size(7), 0x800);
+    }
+
+    #[test]
+    fn test_refill() {
+        with_page(|manager, page| unsafe {
+            let class = class(0x100, 1).unwrap();
+            let count = PAGE_SIZE / 0x100;
+
+            assert_eq!(manager.allocate(class), Err(MemoryError::OutOfMemory));
+
+            manager.refill(page, class);
+
+            assert_eq!(manager.hint(), PAGE_SIZE);
+
+            // objects come out in address order until the page runs out
+            for idx in 0..count {
+                assert_eq!(manager.allocate(class), Ok(page.offset((idx * 0x100) as isize)));
+            }
+
+            assert_eq!(manager.allocate(class), Err(MemoryError::OutOfMemory));
+            assert_eq!(manager.hint(), 0);
+
+            // other classes never see the page
+            assert_eq!(manager.allocate(class - 1), Err(MemoryError::OutOfMemory));
+
+            let next = page.offset(PAGE_SIZE as isize);
+
+            manager.refill(next, class);
+
+            assert_eq!(manager.allocate(class), Ok(next));
+
+            manager.release(page, class);
+
+            assert_eq!(manager.allocate(class), Ok(page));
+
+            let stats = manager.stats();
+
+            assert_eq!(stats.used, (count + 1) * 0x100);
+            assert_eq!(stats.free, (count - 1) * 0x100);
+            assert_eq!(stats.free_blocks, count - 1);
+        });
+    }
+
+    // the debug heap reports the requested size instead
+    #[test]
+    #[cfg(not(feature = "debug_heap"))]
+    fn test_granularity() {
+        with_global(|_, _| {
+            for &(size, align) in [(1, 1), (16, 1), (17, 8), (0x100, 0x100), (8, 0x40), (0x800, 1)].iter() {
+                assert_eq!(::granularity(size, align), class_size(class(size, align).unwrap()));
+            }
+
+            // past the largest class the simple heap decides
+            assert_eq!(::granularity(0x801, 1), ::simple::granularity(0x801, 1));
+        });
+    }
+
+    // keeps using the size from before the first resize, which the debug heap rejects
+    #[test]
+    #[cfg(not(feature = "debug_heap"))]
+    fn test_resize() {
+        with_global(|base, size| unsafe {
+            let end = base.offset(size as isize);
+
+            let a = ::allocate(0x10, 8).unwrap();
+
+            for offset in 0..0x10 {
+                *a.offset(offset) = offset as u8;
+            }
+
+            // within a class it stays put
+            assert_eq!(::resize(a, 0x10, 0xc, 8), Ok(a));
+
+            // up a class it moves
+            let b = ::resize(a, 0x10, 0x30, 8).unwrap();
+
+            assert!(b != a);
+
+            // past the largest class it ends up in simple
+            let c = ::resize(b, 0x30, 0x1000, 8).unwrap();
+
+            assert!(c >= base && c < end);
+            assert!(class(0x1000, 8).is_none());
+
+            for offset in 0x10..0x1000 {
+                *c.offset(offset) = 0xff;
+            }
+
+            assert_eq!(::grow(c, 0x1000, 0x1100, 8), Ok(()));
+
+            // and back down into a slab
+            let d = ::resize(c, 0x1100, 0x20, 8).unwrap();
+
+            assert!(d != c);
+
+            for offset in 0..0x10 {
+                assert_eq!(*d.offset(offset), offset as u8);
+            }
+
+            assert_eq!(::shrink(d, 0x20, 0x10, 8), Err(MemoryError::OutOfSpace));
+            assert!(::release(d, 0x20, 8).is_ok());
+        });
+    }
+
+    #[test]
+    fn test_random() {
+        for seed in 0..16 {
+            with_global(|base, size| unsafe {
+                // sizes on both sides of the largest class
+                Model::new(&mut Global, base, size, seed).limits(0x1000, 0x100).run(0x1000);
+            });
+        }
+    }
+}
@@ -13,11 +13,13 @@ use constants::*;
 
 use segment::Segment;
 use layout::Layout;
-use table::{Info, Table, Base, Level};
+use table::{Info, Table, Base, Level, huge_pages};
 
 pub struct Builder<'a> {
     base: &'a mut Base,
-    root: Shared<Table>
+    root: Shared<Table>,
+    level: Level,
+    huge_pages: bool
 }
 
 struct RelativeBase {
@@ -62,6 +64,10 @@ impl Base for RelativeBase {
         Shared::new(virtual_address)
     }
 
+    unsafe fn release_table(&mut self, _: Shared<Table>) {
+        // the tables end up in one buffer, so they only go away all at once
+    }
+
     fn clear(&mut self) {
         self.used = 0;
         self.tables.clear();
@@ -110,9 +116,16 @@ impl<'a> Builder<'a> {
     }
 
     pub unsafe fn at(base: &mut Base, root: Shared<Table>) -> Builder {
+        Builder::at_level(base, root, Level::root())
+    }
+
+    /// Build into a root table on the given level, regardless of the paging mode
+    pub unsafe fn at_level(base: &mut Base, root: Shared<Table>, level: Level) -> Builder {
         Builder {
             base: base,
-            root: root
+            root: root,
+            level: level,
+            huge_pages: huge_pages()
         }
     }
 
@@ -120,14 +133,16 @@ impl<'a> Builder<'a> {
     fn create_page(segment: &Segment, level: Level, subframe: u64) -> Info {
         Info {
             page: true,
-            write: segment.write(),
+            // copy-on-write pages fault on the first write
+            write: segment.write() && !segment.copy_on_write(),
             execute: segment.execute(),
             user: segment.user(),
             global: segment.global(),
-            write_through: false,
-            cache_disable: false,
-            attribute_table: false,
-            protection_key: 0,
+            write_through: segment.cache().write_through(),
+            cache_disable: segment.cache().cache_disable(),
+            attribute_table: segment.cache().attribute_table(),
+            protection_key: segment.protection_key(),
+            copy_on_write: segment.copy_on_write(),
             level: level,
             address: segment.get_physical_subframe(subframe)
         }
@@ -171,6 +186,7 @@ impl<'a> Builder<'a> {
                 cache_disable: false,
                 attribute_table: false,
                 protection_key: 0,
+                copy_on_write: false,
                 level: level,
                 address: self.base.to_physical(*new_table as u64)
                     .expect("Failed to translate table address to physical address")
@@ -196,37 +212,30 @@ impl<'a> Builder<'a> {
 
     unsafe fn place_page(&mut self, info: Info, subframe: u64) {
         //trace!("Placing page at subframe 0x{:x}", subframe);
-        let pml4e_idx = subframe >> 39 & 0x1ff;
-        let pdpte_idx = subframe >> 30 & 0x1ff;
-        let pde_idx = subframe >> 21 & 0x1ff;
-        let pte_idx = subframe >> 12 & 0x1ff;
-
-        let root_table = self.root;
-
-        let pml4e_table = self.get_or_create(root_table, pml4e_idx as usize, Level::PML4E);
+        let mut table = self.root;
+        let mut level = self.level;
 
-        if info.level == Level::PDPTE {
-            Builder::write_page(pml4e_table, info, pdpte_idx as usize);
-            return;
+        while level != info.level {
+            table = self.get_or_create(table, level.get_index(subframe), level);
+            level = level.get_next().expect("Walked past the last level");
         }
 
-        let pdpte_table = self.get_or_create(pml4e_table, pdpte_idx as usize, Level::PDPTE);
+        let idx = level.get_index(subframe);
 
-        if info.level == Level::PDE {
-            Builder::write_page(pdpte_table, info, pde_idx as usize);
-            return;
-        }
-
-        let pde_table = self.get_or_create(pdpte_table, pde_idx as usize, Level::PDE);
-
-        debug_assert!(info.level == Level::PTE, "Pages cannot be PML4E in size");
-
-        Builder::write_page(pde_table, info, pte_idx as usize);
+        Builder::write_page(table, info, idx);
     }
 
     pub unsafe fn build(mut self, layout: &mut Layout) -> u64 {
         for segment in layout.segments() {
-            if Builder::is_segment_aligned(segment, PageSize::Huge) {
+            if !segment.allocate() {
+                // mapped on first touch instead
+                continue;
+            }
+
+            // frames are shared and copied one page at a time
+            if segment.copy_on_write() {
+                self.generate_pages(segment, PageSize::Page);
+            } else if self.huge_pages && Builder::is_segment_aligned(segment, PageSize::Huge) {
                 self.generate_pages(segment, PageSize::Huge);
             } else if Builder::is_segment_aligned(segment, PageSize::Big) {
                 self.generate_pages(segment, PageSize::Big);
@@ -535,4 +544,75 @@ mod tests {
         let frame_addr = next_entry & (((1 << 41) - 1) << 12);
         assert!(frame_addr == 0x23b000, "Frame was not at the right address: 0x{:x}", frame_addr);
     }
+
+    #[test]
+    fn test_layout_lazy() {
+        before();
+
+        let mut layout = Layout::new();
+        layout.insert(Segment::new(0x200000, 0x200000, 0x1000, false, false, false, false));
+        layout.insert(Segment::lazy(0x400000, 0x200000, true, false, false, false));
+
+        assert!(layout.segment(0x400000).map(|segment| !segment.allocate()).unwrap_or(false),
+                "Lazy segment was not found");
+        assert!(layout.to_physical(0x401000).is_none(), "Lazy segment had a physical address");
+        assert!(layout.to_physical(0x200010) == Some(0x200010), "Segment had the wrong physical address");
+
+        let (addr, mut buffer) = super::build_layout_relative(&mut layout, 0x180000);
+        let ptr = buffer.as_mut_ptr();
+        let len = buffer.len();
+        let cap = buffer.capacity();
+
+        // forget the buffer now
+        mem::forget(buffer);
+
+        // construct an object
+        let tables: Vec<u64> = unsafe {Vec::from_raw_parts(ptr as *mut u64, len / 8, cap / 8)};
+        let head_idx = (addr - 0x180000) / 0x8;
+
+        // 0th entry
+        let next_entry = tables[head_idx as usize + 0];
+        assert!(next_entry & 0x1 == 0x1, "No 0th entry");
+        let next_addr = next_entry & (((1 << 41) - 1) << 12);
+        let next_idx = (next_addr - 0x180000) / 0x8;
+
+        // 0th entry
+        let next_entry = tables[next_idx as usize + 0];
+        assert!(next_entry & 0x1 == 0x1, "No 0th entry");
+        let next_addr = next_entry & (((1 << 41) - 1) << 12);
+        let next_idx = (next_addr - 0x180000) / 0x8;
+
+        // the mapped segment is there, the lazy one is not
+        assert!(tables[next_idx as usize + 1] & 0x1 == 0x1, "No 1st entry");
+        assert!(tables[next_idx as usize + 2] & 0x1 == 0, "Lazy segment was mapped");
+    }
+
+    #[test]
+    fn test_layout_five_level() {
+        use harness::TestBase;
+        use mapper::Mapper;
+        use table::{Base, Level};
+
+        let mut layout = Layout::new();
+        // only reachable through a PML5
+        layout.insert(Segment::new(0x200000, 0x10000000200000, 0x200000, true, false, false, false));
+        layout.insert(Segment::new(0x400000, 0xff00000000000000, 0x1000, false, false, true, false));
+
+        let mut base = TestBase::new();
+
+        unsafe {
+            let root = base.new_table();
+            Builder::at_level(&mut base, root, Level::PML5E).build(&mut layout);
+
+            let mapper = Mapper::at_level(&mut base, root, Level::PML5E);
+
+            assert_eq!(mapper.translate(0x10000000201000), Some(0x201000));
+            assert_eq!(mapper.translate(0xff00000000000010), Some(0x400010));
+            assert_eq!(mapper.translate(0x200000), None);
+
+            // the same tables read as four levels lose the top index
+            let mapper = Mapper::at_level(&mut base, root, Level::PML4E);
+            assert_eq!(mapper.translate(0x201000), None);
+        }
+    }
 }
@@ -0,0 +1,102 @@
+/// Memory type of a mapping
+///
+/// Each type picks one of the entries boot programs into the PAT, see `PAT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cache {
+    /// Normal memory
+    WriteBack,
+    /// Reads are cached, writes go straight to memory
+    WriteThrough,
+    /// Device MMIO, every access goes to the bus in order
+    Uncached,
+    /// Framebuffers, writes are buffered and combined but never read from cache
+    WriteCombining,
+    /// Reads are cached, writes go to memory and invalidate the line
+    WriteProtect
+}
+
+/// Value for the PAT MSR, indexed by the PAT, PCD and PWT bits of an entry
+///
+/// The first four entries are the power-on defaults, so tables built before
+/// the MSR is written keep their meaning.
+pub const PAT: u64 =
+    0x06 << 0 |  // write-back
+    0x04 << 8 |  // write-through
+    0x07 << 16 | // uncached, unless MTRRs say write-combining
+    0x00 << 24 | // uncached
+    0x01 << 32 | // write-combining
+    0x05 << 40 | // write-protect
+    0x07 << 48 | // uncached, unless MTRRs say write-combining
+    0x00 << 56;  // uncached
+
+impl Cache {
+    /// Index of this type in the PAT
+    #[inline]
+    pub fn get_index(self) -> u8 {
+        match self {
+            Cache::WriteBack => 0,
+            Cache::WriteThrough => 1,
+            Cache::Uncached => 3,
+            Cache::WriteCombining => 4,
+            Cache::WriteProtect => 5
+        }
+    }
+
+    /// The type behind a PAT index
+    #[inline]
+    pub fn from_index(index: u8) -> Cache {
+        match index & 0x7 {
+            0 => Cache::WriteBack,
+            1 => Cache::WriteThrough,
+            4 => Cache::WriteCombining,
+            5 => Cache::WriteProtect,
+            _ => Cache::Uncached
+        }
+    }
+
+    #[inline]
+    pub fn write_through(self) -> bool {
+        self.get_index() & 1 << 0 != 0
+    }
+
+    #[inline]
+    pub fn cache_disable(self) -> bool {
+        self.get_index() & 1 << 1 != 0
+    }
+
+    #[inline]
+    pub fn attribute_table(self) -> bool {
+        self.get_index() & 1 << 2 != 0
+    }
+
+    /// The type an entry with these bits has
+    #[inline]
+    pub fn from_bits(write_through: bool, cache_disable: bool, attribute_table: bool) -> Cache {
+        Cache::from_index((write_through as u8) | (cache_disable as u8) << 1 | (attribute_table as u8) << 2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cache, PAT};
+
+    #[test]
+    fn test_round_trip() {
+        for &cache in [Cache::WriteBack, Cache::WriteThrough, Cache::Uncached,
+                       Cache::WriteCombining, Cache::WriteProtect].iter() {
+            assert_eq!(Cache::from_bits(cache.write_through(), cache.cache_disable(), cache.attribute_table()),
+                       cache);
+        }
+    }
+
+    #[test]
+    fn test_pat() {
+        // memory type encodings from the SDM
+        let types = [(Cache::WriteBack, 0x06), (Cache::WriteThrough, 0x04), (Cache::Uncached, 0x00),
+                     (Cache::WriteCombining, 0x01), (Cache::WriteProtect, 0x05)];
+
+        for &(cache, memory_type) in types.iter() {
+            assert_eq!(PAT >> (cache.get_index() as u64 * 8) & 0xff, memory_type);
+        }
+    }
+}
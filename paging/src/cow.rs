@@ -0,0 +1,171 @@
+use collections::BTreeMap;
+
+use constants::*;
+
+use segment::Segment;
+
+/// How many mappings share each copy-on-write frame
+///
+/// A frame drops out once its last mapping writes to it, since whoever
+/// writes last gets to keep the frame instead of copying it.
+pub struct Shares {
+    counts: BTreeMap<u64, u64>
+}
+
+impl Shares {
+    pub fn new() -> Shares {
+        Shares {
+            counts: BTreeMap::new()
+        }
+    }
+
+    /// Record one more mapping of frame
+    pub fn share(&mut self, frame: u64) {
+        *self.counts.entry(frame).or_insert(0) += 1;
+    }
+
+    /// Record a mapping of every frame behind a copy-on-write segment
+    pub fn share_segment(&mut self, segment: &Segment) {
+        if !segment.copy_on_write() || !segment.allocate() {
+            return;
+        }
+
+        let mut offset = 0;
+
+        while offset < segment.size() {
+            self.share(segment.physical_base() + offset);
+            offset += 0x1000;
+        }
+    }
+
+    /// Drop one mapping of frame, returning how many others still map it
+    pub fn release(&mut self, frame: u64) -> u64 {
+        let remaining = match self.counts.get_mut(&frame) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return 0
+        };
+
+        if remaining == 0 {
+            self.counts.remove(&frame);
+        }
+
+        remaining
+    }
+
+    /// How many mappings share frame
+    pub fn count(&self, frame: u64) -> u64 {
+        self.counts.get(&align_back(frame, 0x1000)).cloned().unwrap_or(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::Shared;
+
+    use table::{Table, Base};
+    use harness::TestBase;
+    use segment::Segment;
+    use layout::Layout;
+    use builder::Builder;
+    use mapper::{Mapper, MapError};
+    use super::Shares;
+
+    fn shared_layout() -> (Layout, Segment) {
+        let mut segment = Segment::new(0x400000, 0x200000, 0x2000, true, true, false, false);
+        segment.set_copy_on_write(true);
+
+        let mut layout = Layout::new();
+        layout.insert(segment.clone());
+
+        (layout, segment)
+    }
+
+    // what the page fault handler does about a write to virt
+    unsafe fn write(mapper: &mut Mapper, shares: &mut Shares, virt: u64, copy: u64) -> u64 {
+        let frame = mapper.shared_frame(virt).expect("Page was not shared");
+
+        if shares.release(frame) == 0 {
+            mapper.unshare(virt, frame).unwrap();
+            frame
+        } else {
+            mapper.unshare(virt, copy).unwrap();
+            copy
+        }
+    }
+
+    #[test]
+    fn test_share_and_diverge() {
+        let mut base = TestBase::new();
+        let mut shares = Shares::new();
+
+        let (first, second): (Shared<Table>, Shared<Table>) = unsafe {
+            (base.new_table(), base.new_table())
+        };
+
+        for &root in [first, second].iter() {
+            let (mut layout, segment) = shared_layout();
+
+            unsafe {
+                Builder::at(&mut base, root).build(&mut layout);
+            }
+
+            shares.share_segment(&segment);
+        }
+
+        assert_eq!(shares.count(0x400000), 2);
+        assert_eq!(shares.count(0x401000), 2);
+
+        unsafe {
+            for &root in [first, second].iter() {
+                let mapper = Mapper::at(&mut base, root);
+
+                assert_eq!(mapper.translate(0x200000), Some(0x400000));
+                assert_eq!(mapper.shared_frame(0x200000), Some(0x400000));
+            }
+
+            // the first write moves the page to a copy
+            assert_eq!(write(&mut Mapper::at(&mut base, first), &mut shares, 0x200010, 0x800000), 0x800000);
+
+            {
+                let mapper = Mapper::at(&mut base, first);
+                assert_eq!(mapper.translate(0x200010), Some(0x800010));
+                assert_eq!(mapper.shared_frame(0x200000), None);
+                assert_eq!(mapper.shared_frame(0x201000), Some(0x401000));
+            }
+
+            {
+                let mapper = Mapper::at(&mut base, second);
+                assert_eq!(mapper.translate(0x200000), Some(0x400000));
+                assert_eq!(mapper.shared_frame(0x200000), Some(0x400000));
+            }
+
+            assert_eq!(shares.count(0x400000), 1);
+
+            // nobody else maps the frame now, so it's kept
+            assert_eq!(write(&mut Mapper::at(&mut base, second), &mut shares, 0x200000, 0x900000), 0x400000);
+
+            let mut mapper = Mapper::at(&mut base, second);
+            assert_eq!(mapper.translate(0x200000), Some(0x400000));
+            assert_eq!(mapper.shared_frame(0x200000), None);
+            assert_eq!(mapper.unshare(0x200000, 0x400000), Err(MapError::NotShared(0x200000)));
+        }
+
+        assert_eq!(shares.count(0x400000), 0);
+        assert_eq!(shares.count(0x401000), 2);
+    }
+
+    #[test]
+    fn test_read_only_ignored() {
+        let mut segment = Segment::new(0x400000, 0x200000, 0x1000, false, false, false, false);
+        segment.set_copy_on_write(true);
+
+        let mut shares = Shares::new();
+        shares.share_segment(&segment);
+
+        assert!(!segment.copy_on_write());
+        assert_eq!(shares.count(0x400000), 0);
+    }
+}
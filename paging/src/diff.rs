@@ -0,0 +1,375 @@
+use collections::Vec;
+
+use constants::*;
+
+use layout::Layout;
+use mapper::{Mapper, Flags, MapError};
+
+/// One edit that takes tables built from one layout towards another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Map {
+        virtual_base: u64,
+        physical_base: u64,
+        size: u64,
+        flags: Flags
+    },
+    Unmap {
+        virtual_base: u64,
+        size: u64
+    },
+    /// Unmap the pages the fault handler backed, leaving the rest alone
+    Clear {
+        virtual_base: u64,
+        size: u64
+    },
+    Protect {
+        virtual_base: u64,
+        size: u64,
+        flags: Flags
+    }
+}
+
+impl Change {
+    // grow this change by other if it's the same edit and picks up where this one ends
+    fn extend(&mut self, other: &Change) -> bool {
+        let grown = match (*self, *other) {
+            (Change::Map { virtual_base, physical_base, size, flags },
+             Change::Map { virtual_base: next, physical_base: next_physical, size: next_size, flags: next_flags })
+                if virtual_base + size == next && physical_base + size == next_physical && flags == next_flags =>
+                size + next_size,
+            (Change::Unmap { virtual_base, size }, Change::Unmap { virtual_base: next, size: next_size })
+                if virtual_base + size == next =>
+                size + next_size,
+            (Change::Clear { virtual_base, size }, Change::Clear { virtual_base: next, size: next_size })
+                if virtual_base + size == next =>
+                size + next_size,
+            (Change::Protect { virtual_base, size, flags },
+             Change::Protect { virtual_base: next, size: next_size, flags: next_flags })
+                if virtual_base + size == next && flags == next_flags =>
+                size + next_size,
+            _ => return false
+        };
+
+        match *self {
+            Change::Map { ref mut size, .. } |
+            Change::Unmap { ref mut size, .. } |
+            Change::Clear { ref mut size, .. } |
+            Change::Protect { ref mut size, .. } => *size = grown
+        }
+
+        true
+    }
+}
+
+// what backs a page of a layout
+#[derive(Clone, Copy)]
+enum Backing {
+    // the builder mapped this frame
+    Mapped(u64, Flags),
+    // the fault handler may or may not have mapped a frame yet
+    Lazy(Flags)
+}
+
+fn backing(layout: &Layout, addr: u64) -> Option<Backing> {
+    layout.segment(addr).map(|segment| if segment.allocate() {
+        Backing::Mapped(segment.get_physical_subframe(addr), Flags::from(segment))
+    } else {
+        Backing::Lazy(Flags::from(segment))
+    })
+}
+
+fn push(changes: &mut Vec<Change>, change: Change) {
+    let extended = match changes.last_mut() {
+        Some(last) => last.extend(&change),
+        None => false
+    };
+
+    if !extended {
+        changes.push(change);
+    }
+}
+
+impl Layout {
+    /// The changes that turn tables built from this layout into ones built from other
+    ///
+    /// Unmaps come first, then protects, then maps, so a range that moved to
+    /// another frame is free again by the time it's mapped. Lazy ranges that
+    /// don't stay lazy with the same flags are cleared, so the pages the fault
+    /// handler already backed go with them.
+    pub fn diff(&self, other: &Layout) -> Vec<Change> {
+        let mut bounds = vec![];
+
+        for segment in self.segments().chain(other.segments()) {
+            if segment.size() > 0 {
+                bounds.push(align_back(segment.virtual_base(), 0x1000));
+                bounds.push(align(segment.virtual_base() + segment.size(), 0x1000));
+            }
+        }
+
+        bounds.sort();
+        bounds.dedup();
+
+        let mut unmaps = vec![];
+        let mut protects = vec![];
+        let mut maps = vec![];
+
+        for pair in bounds.windows(2) {
+            let (base, end) = (pair[0], pair[1]);
+            let virtual_base = canonicalize_to(base, LA57_CANONICAL_BITS);
+            let size = end - base;
+
+            match (backing(self, base), backing(other, base)) {
+                (None, None) | (None, Some(Backing::Lazy(_))) => {},
+                (Some(Backing::Lazy(from_flags)), Some(Backing::Lazy(flags))) if from_flags == flags => {},
+                (Some(Backing::Lazy(_)), to) => {
+                    push(&mut unmaps, Change::Clear {
+                        virtual_base: virtual_base,
+                        size: size
+                    });
+
+                    if let Some(Backing::Mapped(physical_base, flags)) = to {
+                        push(&mut maps, Change::Map {
+                            virtual_base: virtual_base,
+                            physical_base: physical_base,
+                            size: size,
+                            flags: flags
+                        });
+                    }
+                },
+                (Some(Backing::Mapped(..)), None) | (Some(Backing::Mapped(..)), Some(Backing::Lazy(_))) =>
+                    push(&mut unmaps, Change::Unmap {
+                        virtual_base: virtual_base,
+                        size: size
+                    }),
+                (None, Some(Backing::Mapped(physical_base, flags))) => push(&mut maps, Change::Map {
+                    virtual_base: virtual_base,
+                    physical_base: physical_base,
+                    size: size,
+                    flags: flags
+                }),
+                (Some(Backing::Mapped(from_physical, from_flags)), Some(Backing::Mapped(physical_base, flags))) => {
+                    if from_physical != physical_base {
+                        push(&mut unmaps, Change::Unmap {
+                            virtual_base: virtual_base,
+                            size: size
+                        });
+
+                        push(&mut maps, Change::Map {
+                            virtual_base: virtual_base,
+                            physical_base: physical_base,
+                            size: size,
+                            flags: flags
+                        });
+                    } else if from_flags != flags {
+                        push(&mut protects, Change::Protect {
+                            virtual_base: virtual_base,
+                            size: size,
+                            flags: flags
+                        });
+                    }
+                }
+            }
+        }
+
+        unmaps.extend(protects);
+        unmaps.extend(maps);
+
+        unmaps
+    }
+}
+
+impl<'a> Mapper<'a> {
+    /// Make a series of changes, flushing only the pages they touch
+    ///
+    /// Released gets the frames clears unmap, see `Mapper::clear`. Stops at
+    /// the first change that fails, leaving the ones before it made.
+    pub unsafe fn apply<F: FnMut(u64)>(&mut self, changes: &[Change], mut released: F) -> Result<(), MapError> {
+        for change in changes {
+            match *change {
+                Change::Map { virtual_base, physical_base, size, flags } =>
+                    try!(self.map(virtual_base, physical_base, size, flags)),
+                Change::Unmap { virtual_base, size } =>
+                    try!(self.unmap(virtual_base, size)),
+                Change::Clear { virtual_base, size } =>
+                    try!(self.clear(virtual_base, size, &mut released)),
+                Change::Protect { virtual_base, size, flags } =>
+                    try!(self.protect(virtual_base, size, flags))
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::Shared;
+
+    use table::{Table, Base};
+    use harness::TestBase;
+    use segment::Segment;
+    use layout::Layout;
+    use builder::Builder;
+    use mapper::{Mapper, Flags};
+    use walker::Walker;
+    use super::Change;
+
+    fn layout(segments: &[Segment]) -> Layout {
+        let mut layout = Layout::new();
+
+        for segment in segments {
+            assert!(layout.insert(segment.clone()));
+        }
+
+        layout
+    }
+
+    // every mapped page with its frame and flags
+    fn pages(base: &TestBase, root: Shared<Table>) -> Vec<(u64, u64, bool, bool)> {
+        let mut pages = vec![];
+
+        unsafe {
+            Walker::at(base, root).mappings(|mapping| {
+                let mut offset = 0;
+
+                while offset < mapping.size {
+                    pages.push((mapping.virtual_base + offset, mapping.physical_base + offset,
+                                mapping.write, mapping.execute));
+                    offset += 0x1000;
+                }
+            });
+        }
+
+        pages
+    }
+
+    fn build(base: &mut TestBase, layout: &mut Layout) -> Shared<Table> {
+        unsafe {
+            let root = base.new_table();
+            Builder::at(base, root).build(layout);
+
+            root
+        }
+    }
+
+    #[test]
+    fn test_diff_same() {
+        let segments = [Segment::new(0x200000, 0x200000, 0x3000, true, false, false, false),
+                        Segment::new(0x400000, 0xffffffff80000000, 0x200000, false, false, true, true)];
+
+        assert_eq!(layout(&segments).diff(&layout(&segments)), vec![]);
+    }
+
+    #[test]
+    fn test_diff_changes() {
+        let from = layout(&[Segment::new(0x200000, 0x200000, 0x3000, true, false, false, false),
+                            Segment::new(0x400000, 0x400000, 0x2000, true, false, false, false),
+                            Segment::new(0x600000, 0x600000, 0x1000, true, false, false, false)]);
+
+        let to = layout(&[// grows by a page, and the last page turns read-only
+                          Segment::new(0x200000, 0x200000, 0x2000, true, false, false, false),
+                          Segment::new(0x202000, 0x202000, 0x2000, false, false, false, false),
+                          // moves to other frames
+                          Segment::new(0x800000, 0x400000, 0x2000, true, false, false, false),
+                          // the segment at 0x600000 is gone
+                          Segment::new(0x900000, 0xffffffff80000000, 0x1000, false, false, true, true)]);
+
+        let data = Flags::from(&Segment::new(0, 0, 0, true, false, false, false));
+        let read_only = Flags::from(&Segment::new(0, 0, 0, false, false, false, false));
+        let code = Flags::from(&Segment::new(0, 0, 0, false, false, true, true));
+
+        assert_eq!(from.diff(&to), vec![
+            Change::Unmap { virtual_base: 0x400000, size: 0x2000 },
+            Change::Unmap { virtual_base: 0x600000, size: 0x1000 },
+            Change::Protect { virtual_base: 0x202000, size: 0x1000, flags: read_only },
+            Change::Map { virtual_base: 0x203000, physical_base: 0x203000, size: 0x1000, flags: read_only },
+            Change::Map { virtual_base: 0x400000, physical_base: 0x800000, size: 0x2000, flags: data },
+            Change::Map { virtual_base: 0xffffffff80000000, physical_base: 0x900000, size: 0x1000, flags: code }
+        ]);
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut from = layout(&[Segment::new(0x200000, 0x200000, 0x400000, true, false, false, false),
+                                Segment::new(0x800000, 0xffffffff80000000, 0x200000, false, false, true, true)]);
+
+        let mut to = layout(&[Segment::new(0x200000, 0x200000, 0x1ff000, true, false, false, false),
+                              Segment::new(0x3ff000, 0x3ff000, 0x1000, false, false, false, false),
+                              Segment::new(0xa00000, 0x400000, 0x200000, true, false, false, false),
+                              Segment::new(0x800000, 0xffffffff80000000, 0x200000, false, false, true, true)]);
+
+        let mut base = TestBase::new();
+
+        let updated = build(&mut base, &mut from);
+        let fresh = build(&mut base, &mut to);
+
+        let changes = from.diff(&to);
+
+        unsafe {
+            Mapper::at(&mut base, updated).apply(&changes, |frame| panic!("Cleared frame 0x{:x}", frame)).unwrap();
+        }
+
+        assert_eq!(pages(&base, updated), pages(&base, fresh));
+    }
+
+    #[test]
+    fn test_diff_lazy() {
+        let from = layout(&[Segment::lazy(0x200000, 0x2000, true, false, false, false),
+                            Segment::lazy(0x400000, 0x2000, true, false, false, false),
+                            Segment::lazy(0x600000, 0x1000, true, false, false, false),
+                            Segment::new(0x800000, 0x800000, 0x1000, true, false, false, false)]);
+
+        let to = layout(&[// the lazy segment at 0x200000 is gone
+                          // backed up front now
+                          Segment::new(0x900000, 0x400000, 0x2000, true, false, false, false),
+                          // stays as it was
+                          Segment::lazy(0x600000, 0x1000, true, false, false, false),
+                          // turns lazy
+                          Segment::lazy(0x800000, 0x1000, true, false, false, false)]);
+
+        let data = Flags::from(&Segment::new(0, 0, 0, true, false, false, false));
+
+        assert_eq!(from.diff(&to), vec![
+            Change::Clear { virtual_base: 0x200000, size: 0x2000 },
+            Change::Clear { virtual_base: 0x400000, size: 0x2000 },
+            Change::Unmap { virtual_base: 0x800000, size: 0x1000 },
+            Change::Map { virtual_base: 0x400000, physical_base: 0x900000, size: 0x2000, flags: data }
+        ]);
+    }
+
+    #[test]
+    fn test_apply_lazy() {
+        let mut from = layout(&[Segment::lazy(0x200000, 0x2000, true, false, false, false),
+                                Segment::lazy(0x400000, 0x200000, true, false, false, false)]);
+
+        let mut to = layout(&[Segment::new(0xa00000, 0x400000, 0x200000, true, false, false, false)]);
+
+        let mut base = TestBase::new();
+
+        let updated = build(&mut base, &mut from);
+        let fresh = build(&mut base, &mut to);
+
+        let data = Flags::from(&Segment::new(0, 0, 0, true, false, false, false));
+
+        unsafe {
+            let mut mapper = Mapper::at(&mut base, updated);
+
+            // pages the fault handler backed
+            mapper.map(0x201000, 0x300000, 0x1000, data).unwrap();
+            mapper.map(0x400000, 0x301000, 0x1000, data).unwrap();
+            mapper.map(0x5ff000, 0x302000, 0x1000, data).unwrap();
+        }
+
+        let changes = from.diff(&to);
+        let mut released = vec![];
+
+        unsafe {
+            Mapper::at(&mut base, updated).apply(&changes, |frame| released.push(frame)).unwrap();
+        }
+
+        // exactly the frames the fault handler put in
+        assert_eq!(released, vec![0x300000, 0x301000, 0x302000]);
+        assert_eq!(pages(&base, updated), pages(&base, fresh));
+    }
+}
@@ -0,0 +1,63 @@
+use std::ptr::Shared;
+
+use table::{Table, Base};
+
+const TABLES_BEGIN: u64 = 0x180000;
+
+/// Boxed tables, pretending to sit one after another from TABLES_BEGIN
+///
+/// Released tables are kept around, so addresses stay the same, and recorded.
+pub struct TestBase {
+    tables: Vec<Box<Table>>,
+    released: Vec<u64>
+}
+
+impl TestBase {
+    pub fn new() -> TestBase {
+        TestBase {
+            tables: vec![],
+            released: vec![]
+        }
+    }
+
+    /// Physical addresses of the released tables, in order
+    pub fn released(&self) -> &[u64] {
+        &self.released
+    }
+}
+
+impl Base for TestBase {
+    fn to_physical(&self, address: u64) -> Option<u64> {
+        self.tables.iter()
+            .position(|table| &**table as *const Table as u64 == address)
+            .map(|idx| TABLES_BEGIN + idx as u64 * 0x1000)
+    }
+
+    fn to_virtual(&self, address: u64) -> Option<u64> {
+        if address < TABLES_BEGIN {
+            return None;
+        }
+
+        self.tables.get(((address - TABLES_BEGIN) / 0x1000) as usize)
+            .map(|table| &**table as *const Table as u64)
+    }
+
+    unsafe fn new_table(&mut self) -> Shared<Table> {
+        self.tables.push(Box::new(Table::new()));
+
+        Shared::new(&mut **self.tables.last_mut().unwrap() as *mut Table)
+    }
+
+    unsafe fn release_table(&mut self, table: Shared<Table>) {
+        let physical = self.to_physical(*table as u64).expect("Released a table that was never made");
+
+        assert!(!self.released.contains(&physical), "Released table 0x{:x} twice", physical);
+
+        self.released.push(physical);
+    }
+
+    fn clear(&mut self) {
+        self.released.clear();
+        self.tables.clear();
+    }
+}
@@ -69,15 +69,21 @@ impl Layout {
         }
     }
 
-    pub fn to_physical(&self, addr: u64) -> Option<u64> {
-        let dummy = Segment::dummy(addr);
+    /// The segment covering the page addr is in
+    pub fn segment(&self, addr: u64) -> Option<&Segment> {
+        // a zero-sized dummy on a page boundary overlaps nothing, so probe the whole page
+        let dummy = Segment::dummy_range(align_back(addr, 0x1000), 0x1000);
 
-        if let Some(segment) = self.map.get(&dummy) {
-            // address has a mapping
-            Some((addr & ((1 << CANONICAL_BITS) - 1)) - segment.virtual_base() + segment.physical_base())
-        } else {
-            // no mapping
-            None
+        self.map.get(&dummy)
+    }
+
+    pub fn to_physical(&self, addr: u64) -> Option<u64> {
+        match self.segment(addr) {
+            // lazy segments have no physical memory yet
+            Some(segment) if segment.allocate() => {
+                Some((addr & ((1 << LA57_CANONICAL_BITS) - 1)) - segment.virtual_base() + segment.physical_base())
+            },
+            _ => None
         }
     }
 
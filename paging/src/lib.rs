@@ -4,6 +4,7 @@
 #![feature(collections)]
 #![feature(alloc)]
 #![feature(inclusive_range_syntax)]
+#![feature(asm)]
 #![cfg_attr(not(test), no_std)]
 #[cfg(not(test))]
 extern crate core as std;
@@ -18,8 +19,15 @@ extern crate kernel_std;
 
 pub use layout::Layout;
 pub use segment::{Segment, raw_segment_size};
-pub use table::{Entry, Table, Base, Level, Info};
+pub use table::{Entry, Table, Base, Level, Info, set_five_level, five_level,
+                 set_huge_pages, huge_pages};
 pub use builder::{Builder, build_layout_relative};
+pub use mapper::{Mapper, Flags, MapError};
+pub use walker::{Walker, Mapping};
+pub use cow::Shares;
+pub use cache::{Cache, PAT};
+pub use diff::Change;
+pub use validate::Violation;
 
 use std::cmp::{Ord, PartialOrd, Ordering};
 
@@ -27,6 +35,16 @@ mod table;
 mod segment;
 mod layout;
 mod builder;
+mod mapper;
+mod walker;
+mod cow;
+mod cache;
+mod diff;
+mod validate;
+
+// Host-side page tables
+#[cfg(test)]
+mod harness;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum FrameSize {
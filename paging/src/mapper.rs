@@ -0,0 +1,816 @@
+use super::PageSize;
+
+use std::ptr::Shared;
+use std::ops::Range;
+
+use constants::*;
+
+use segment::Segment;
+use cache::Cache;
+use table::{Info, Entry, Table, Base, Level, huge_pages};
+use walker::active_root;
+
+/// Permissions of a runtime mapping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags {
+    pub write: bool,
+    pub user: bool,
+    pub execute: bool,
+    pub global: bool,
+    /// Map writable pages read-only until the first write, see `Mapper::unshare`
+    pub copy_on_write: bool,
+    pub cache: Cache,
+    /// Only restricts user pages, see `Segment::protection_key`
+    pub protection_key: u8
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// Address or size was not page-aligned
+    Unaligned,
+    /// Something is already mapped at this address
+    Mapped(u64),
+    /// Nothing is mapped at this address
+    Unmapped(u64),
+    /// The page at this address is not copy-on-write
+    NotShared(u64)
+}
+
+/// Edits an existing page table tree in place
+pub struct Mapper<'a> {
+    base: &'a mut Base,
+    root: Shared<Table>,
+    level: Level,
+    live: bool,
+    huge_pages: bool
+}
+
+enum Walk {
+    // the entry mapping the address
+    Page(Shared<Table>, usize, Level),
+    // the walk stopped at a non-present entry on this level
+    Missing(Level)
+}
+
+impl Flags {
+    /// Writable, non-executable, kernel-only and write-back cached
+    pub const fn kernel_data() -> Flags {
+        Flags {
+            write: true,
+            user: false,
+            execute: false,
+            global: false,
+            copy_on_write: false,
+            cache: Cache::WriteBack,
+            protection_key: 0
+        }
+    }
+}
+
+impl<'a> From<&'a Segment> for Flags {
+    fn from(segment: &'a Segment) -> Flags {
+        Flags {
+            write: segment.write(),
+            user: segment.user(),
+            execute: segment.execute(),
+            global: segment.global(),
+            copy_on_write: segment.copy_on_write(),
+            cache: segment.cache(),
+            protection_key: segment.protection_key()
+        }
+    }
+}
+
+impl<'a> Mapper<'a> {
+    /// Edit tables that are not loaded, so no TLB entries get flushed
+    pub unsafe fn at(base: &mut Base, root: Shared<Table>) -> Mapper {
+        Mapper::at_level(base, root, Level::root())
+    }
+
+    /// Edit tables rooted on the given level, regardless of the paging mode
+    pub unsafe fn at_level(base: &mut Base, root: Shared<Table>, level: Level) -> Mapper {
+        Mapper {
+            base: base,
+            root: root,
+            level: level,
+            live: false,
+            huge_pages: huge_pages()
+        }
+    }
+
+    /// Edit the tables CR3 points at
+    pub unsafe fn active(base: &mut Base) -> Mapper {
+        let root = active_root(base);
+
+        Mapper {
+            base: base,
+            root: root,
+            level: Level::root(),
+            live: true,
+            huge_pages: huge_pages()
+        }
+    }
+
+    /// Map size bytes at virt to phys, using the largest pages that fit
+    ///
+    /// Copy-on-write ranges always use 4K pages. Nothing may be mapped in the
+    /// range yet. Nothing is changed on error.
+    pub unsafe fn map(&mut self, virt: u64, phys: u64, size: u64, flags: Flags) -> Result<(), MapError> {
+        try!(Mapper::check_aligned(virt, phys, size));
+        try!(self.check(virt, size, false));
+
+        let mut offset = 0;
+
+        while offset < size {
+            let address = virt.wrapping_add(offset);
+            let physical = phys + offset;
+            let mut page = if flags.copy_on_write {
+                PageSize::Page
+            } else {
+                self.page_size(address, physical, size - offset)
+            };
+
+            loop {
+                let table = try!(self.descend(address, page.get_level()));
+                let idx = page.get_level().get_index(address);
+
+                if !table.as_mut().unwrap().read(idx).present() {
+                    let info = Mapper::page_info(flags, page.get_level(), physical);
+                    table.as_mut().unwrap().write(info.into(), idx);
+                    break;
+                }
+
+                // an empty table is left over here, fill it with smaller pages
+                page = try!(page.get_next().ok_or(MapError::Mapped(address)));
+            }
+
+            offset += page.get_size();
+        }
+
+        // the new pages were not present before, so there's nothing to flush
+        Ok(())
+    }
+
+    /// Unmap size bytes at virt, splitting large pages at the edges
+    ///
+    /// Everything in the range has to be mapped. Nothing is changed on error.
+    pub unsafe fn unmap(&mut self, virt: u64, size: u64) -> Result<(), MapError> {
+        try!(Mapper::check_aligned(virt, 0, size));
+        try!(self.check(virt, size, true));
+
+        let mut offset = 0;
+
+        while offset < size {
+            let address = virt.wrapping_add(offset);
+            let (table, idx, level) = try!(self.fit(address, size - offset));
+
+            table.as_mut().unwrap().write(Entry::empty(), idx);
+            self.flush(address);
+
+            offset += level.get_size();
+        }
+
+        Ok(())
+    }
+
+    /// Unmap whatever is mapped in size bytes at virt, skipping pages that aren't
+    ///
+    /// Released gets the frame of every 4K page unmapped, so the caller can
+    /// free it. Stops at the first page that fails to unmap, leaving the ones
+    /// before it unmapped.
+    pub unsafe fn clear<F: FnMut(u64)>(&mut self, virt: u64, size: u64, mut released: F) -> Result<(), MapError> {
+        try!(Mapper::check_aligned(virt, 0, size));
+
+        let mut offset = 0;
+
+        while offset < size {
+            let address = virt.wrapping_add(offset);
+
+            offset += match self.walk(address) {
+                Walk::Page(table, idx, level) => {
+                    let entry = table.as_mut().unwrap().read(idx);
+                    let frame = entry.address(level) + (address & (level.get_size() - 1));
+
+                    try!(self.unmap(address, PageSize::Page.get_size()));
+                    released(frame);
+
+                    PageSize::Page.get_size()
+                },
+                // skip to the end of the missing entry
+                Walk::Missing(level) => level.get_size() - (address & (level.get_size() - 1))
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Give every table below the given root entries back to the base, and clear the entries
+    ///
+    /// Whatever those tables mapped is left to the caller. Only for tables that
+    /// are not loaded, since nothing gets flushed.
+    pub unsafe fn release_tables(&mut self, entries: Range<usize>) {
+        assert!(!self.live, "Tried to release the live page tables");
+
+        for idx in entries {
+            let entry = self.root.as_mut().unwrap().read(idx);
+
+            if entry.present() && !entry.is_page(self.level) {
+                let table = self.table(entry, self.level);
+                let level = self.level.get_next().expect("Page table entry was not a page");
+
+                self.release_below(table, level);
+            }
+
+            self.root.as_mut().unwrap().write(Entry::empty(), idx);
+        }
+    }
+
+    /// Change the permissions of size bytes at virt, splitting large pages at the edges
+    ///
+    /// Everything in the range has to be mapped. Nothing is changed on error.
+    pub unsafe fn protect(&mut self, virt: u64, size: u64, flags: Flags) -> Result<(), MapError> {
+        try!(Mapper::check_aligned(virt, 0, size));
+        try!(self.check(virt, size, true));
+
+        let mut offset = 0;
+
+        while offset < size {
+            let address = virt.wrapping_add(offset);
+            let (table, idx, level) = try!(self.fit(address, size - offset));
+
+            let mut info = table.as_mut().unwrap().read(idx).info(level);
+            info.write = flags.write && !flags.copy_on_write;
+            info.user = flags.user;
+            info.execute = flags.execute;
+            info.global = flags.global;
+            info.copy_on_write = flags.write && flags.copy_on_write;
+            info.write_through = flags.cache.write_through();
+            info.cache_disable = flags.cache.cache_disable();
+            info.attribute_table = flags.cache.attribute_table();
+            info.protection_key = flags.protection_key;
+
+            table.as_mut().unwrap().write(info.into(), idx);
+            self.flush(address);
+
+            offset += level.get_size();
+        }
+
+        Ok(())
+    }
+
+    /// The physical address virt maps to
+    pub unsafe fn translate(&self, virt: u64) -> Option<u64> {
+        match self.walk(virt) {
+            Walk::Page(table, idx, level) => {
+                let entry = table.as_mut().unwrap().read(idx);
+
+                Some(entry.address(level) + (virt & (level.get_size() - 1)))
+            },
+            Walk::Missing(_) => None
+        }
+    }
+
+    /// The frame behind the copy-on-write page holding virt
+    pub unsafe fn shared_frame(&self, virt: u64) -> Option<u64> {
+        match self.walk(virt) {
+            Walk::Page(table, idx, level) => {
+                let entry = table.as_mut().unwrap().read(idx);
+
+                if entry.copy_on_write() {
+                    Some(entry.address(level))
+                } else {
+                    None
+                }
+            },
+            Walk::Missing(_) => None
+        }
+    }
+
+    /// Point the copy-on-write page holding virt at frame and make it writable
+    ///
+    /// Frame can be the one the page already maps, if nothing else shares it.
+    /// Copying the contents over is up to the caller.
+    pub unsafe fn unshare(&mut self, virt: u64, frame: u64) -> Result<(), MapError> {
+        try!(Mapper::check_aligned(0, frame, 0));
+
+        let page = align_back(virt, PageSize::Page.get_size());
+
+        match self.walk(page) {
+            Walk::Page(table, idx, level) => {
+                let mut info = table.as_mut().unwrap().read(idx).info(level);
+
+                if !info.copy_on_write {
+                    return Err(MapError::NotShared(page));
+                }
+
+                info.write = true;
+                info.copy_on_write = false;
+                info.address = frame;
+
+                table.as_mut().unwrap().write(info.into(), idx);
+                self.flush(page);
+
+                Ok(())
+            },
+            Walk::Missing(_) => Err(MapError::Unmapped(page))
+        }
+    }
+
+    #[inline]
+    fn check_aligned(virt: u64, phys: u64, size: u64) -> Result<(), MapError> {
+        let page = PageSize::Page.get_size();
+
+        if is_aligned(virt, page) && is_aligned(phys, page) && is_aligned(size, page) {
+            Ok(())
+        } else {
+            Err(MapError::Unaligned)
+        }
+    }
+
+    #[inline]
+    fn page_size(&self, virt: u64, phys: u64, remaining: u64) -> PageSize {
+        for &page in [PageSize::Huge, PageSize::Big].iter() {
+            if page == PageSize::Huge && !self.huge_pages {
+                continue;
+            }
+
+            if is_aligned(virt, page.get_size()) && is_aligned(phys, page.get_size())
+                && remaining >= page.get_size() {
+                return page;
+            }
+        }
+
+        PageSize::Page
+    }
+
+    #[inline]
+    fn page_info(flags: Flags, level: Level, address: u64) -> Info {
+        Info {
+            page: true,
+            write: flags.write && !flags.copy_on_write,
+            execute: flags.execute,
+            user: flags.user,
+            global: flags.global,
+            write_through: flags.cache.write_through(),
+            cache_disable: flags.cache.cache_disable(),
+            attribute_table: flags.cache.attribute_table(),
+            protection_key: flags.protection_key,
+            copy_on_write: flags.write && flags.copy_on_write,
+            level: level,
+            address: address
+        }
+    }
+
+    #[inline]
+    unsafe fn flush(&self, address: u64) {
+        if self.live {
+            asm!("invlpg [$0]" :: "r"(address as usize) : "memory" : "intel", "volatile");
+        }
+    }
+
+    #[inline]
+    unsafe fn table(&self, entry: Entry, level: Level) -> Shared<Table> {
+        Shared::new(self.base.to_virtual(entry.address(level))
+                    .expect("Failed to translate physical address to table address")
+                    as *mut Table)
+    }
+
+    // point an entry on level at a new table
+    unsafe fn link(&mut self, table: Shared<Table>, idx: usize, level: Level, new_table: Shared<Table>) {
+        let info = Info {
+            page: false,
+            write: true,
+            execute: true,
+            user: true,
+            global: false,
+            write_through: false,
+            cache_disable: false,
+            attribute_table: false,
+            protection_key: 0,
+            copy_on_write: false,
+            level: level,
+            address: self.base.to_physical(*new_table as u64)
+                .expect("Failed to translate table address to physical address")
+        };
+
+        table.as_mut().unwrap().write(info.into(), idx);
+    }
+
+    unsafe fn walk(&self, virt: u64) -> Walk {
+        let mut table = self.root;
+        let mut level = self.level;
+
+        loop {
+            let idx = level.get_index(virt);
+            let entry = table.as_mut().unwrap().read(idx);
+
+            if !entry.present() {
+                return Walk::Missing(level);
+            } else if entry.is_page(level) {
+                return Walk::Page(table, idx, level);
+            }
+
+            table = self.table(entry, level);
+            level = level.get_next().expect("Page table entry was not a page");
+        }
+    }
+
+    // whether size bytes at virt are all mapped, or all unmapped
+    unsafe fn check(&self, virt: u64, size: u64, mapped: bool) -> Result<(), MapError> {
+        let mut offset = 0;
+
+        while offset < size {
+            let address = virt.wrapping_add(offset);
+
+            let level = match self.walk(address) {
+                Walk::Page(_, _, level) => if mapped {
+                    level
+                } else {
+                    return Err(MapError::Mapped(address));
+                },
+                Walk::Missing(level) => if mapped {
+                    return Err(MapError::Unmapped(address));
+                } else {
+                    level
+                }
+            };
+
+            offset += level.get_size() - (address & (level.get_size() - 1));
+        }
+
+        Ok(())
+    }
+
+    // the table holding entries on level for virt, creating tables on the way
+    unsafe fn descend(&mut self, virt: u64, level: Level) -> Result<Shared<Table>, MapError> {
+        let mut table = self.root;
+        let mut current = self.level;
+
+        while current != level {
+            let idx = current.get_index(virt);
+            let entry = table.as_mut().unwrap().read(idx);
+
+            table = if !entry.present() {
+                let new_table = self.base.new_table();
+                self.link(table, idx, current, new_table);
+                new_table
+            } else if entry.is_page(current) {
+                return Err(MapError::Mapped(virt));
+            } else {
+                self.table(entry, current)
+            };
+
+            current = current.get_next().expect("Walked past the last level");
+        }
+
+        Ok(table)
+    }
+
+    // the entry mapping virt, split until its page fits in remaining
+    unsafe fn fit(&mut self, virt: u64, remaining: u64) -> Result<(Shared<Table>, usize, Level), MapError> {
+        loop {
+            match self.walk(virt) {
+                Walk::Page(table, idx, level) => {
+                    if is_aligned(virt, level.get_size()) && remaining >= level.get_size() {
+                        return Ok((table, idx, level));
+                    }
+
+                    self.split(table, idx, level);
+                },
+                Walk::Missing(_) => return Err(MapError::Unmapped(virt))
+            }
+        }
+    }
+
+    // release table holding entries on level, and every table below it
+    unsafe fn release_below(&mut self, table: Shared<Table>, level: Level) {
+        if let Some(next) = level.get_next() {
+            for idx in 0..0x200 {
+                let entry = table.as_mut().unwrap().read(idx);
+
+                if entry.present() && !entry.is_page(level) {
+                    let below = self.table(entry, level);
+
+                    self.release_below(below, next);
+                }
+            }
+        }
+
+        self.base.release_table(table);
+    }
+
+    // replace a large page with a table of smaller pages mapping the same range
+    unsafe fn split(&mut self, table: Shared<Table>, idx: usize, level: Level) {
+        let mut info = table.as_mut().unwrap().read(idx).info(level);
+        let next = level.get_next().expect("Tried to split a 4K page");
+        let address = info.address;
+        let new_table = self.base.new_table();
+
+        info.level = next;
+
+        for sub in 0..0x200 {
+            info.address = address + sub * next.get_size();
+            new_table.as_mut().unwrap().write(info.clone().into(), sub as usize);
+        }
+
+        // translations stay the same, callers flush whatever they change next
+        self.link(table, idx, level, new_table);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use table::{Entry, Base, Level};
+    use harness::TestBase;
+    use cache::Cache;
+    use super::{Mapper, Flags, MapError, Walk};
+
+    const DATA: Flags = Flags::kernel_data();
+
+    const CODE: Flags = Flags {
+        write: false,
+        user: false,
+        execute: true,
+        global: false,
+        copy_on_write: false,
+        cache: Cache::WriteBack,
+        protection_key: 0
+    };
+
+    fn with_mapper<F: FnOnce(&mut Mapper)>(f: F) {
+        let mut base = TestBase::new();
+
+        unsafe {
+            let root = base.new_table();
+            f(&mut Mapper::at(&mut base, root));
+        }
+    }
+
+    fn lookup(mapper: &Mapper, virt: u64) -> Option<(Entry, Level)> {
+        unsafe {
+            match mapper.walk(virt) {
+                Walk::Page(table, idx, level) => Some((table.as_mut().unwrap().read(idx), level)),
+                Walk::Missing(_) => None
+            }
+        }
+    }
+
+    #[test]
+    fn test_map_translate() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x3000, DATA), Ok(()));
+
+            assert_eq!(mapper.translate(0x200000), Some(0x400000));
+            assert_eq!(mapper.translate(0x201234), Some(0x401234));
+            assert_eq!(mapper.translate(0x203000), None);
+            assert_eq!(mapper.translate(0x1ff000), None);
+
+            let (entry, level) = lookup(mapper, 0x202000).unwrap();
+            assert_eq!(level, Level::PTE);
+            assert!(entry.write() && !entry.execute() && !entry.user());
+        });
+    }
+
+    #[test]
+    fn test_map_large() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x40000000, 0x80000000, 0x40201000, DATA), Ok(()));
+
+            assert_eq!(lookup(mapper, 0x40000000).unwrap().1, Level::PDPTE);
+            assert_eq!(lookup(mapper, 0x80000000).unwrap().1, Level::PDE);
+            assert_eq!(lookup(mapper, 0x80200000).unwrap().1, Level::PTE);
+
+            assert_eq!(mapper.translate(0x7fffffff), Some(0xbfffffff));
+            assert_eq!(mapper.translate(0x801fffff), Some(0xc01fffff));
+            assert_eq!(mapper.translate(0x80200000), Some(0xc0200000));
+            assert_eq!(mapper.translate(0x80201000), None);
+        });
+    }
+
+    #[test]
+    fn test_map_without_huge_pages() {
+        with_mapper(|mapper| unsafe {
+            // CPUs without 1G pages get 2M pages instead
+            mapper.huge_pages = false;
+
+            assert_eq!(mapper.map(0x40000000, 0x80000000, 0x40000000, DATA), Ok(()));
+
+            assert_eq!(lookup(mapper, 0x40000000).unwrap().1, Level::PDE);
+            assert_eq!(lookup(mapper, 0x7fe00000).unwrap().1, Level::PDE);
+
+            assert_eq!(mapper.translate(0x7fffffff), Some(0xbfffffff));
+        });
+    }
+
+    #[test]
+    fn test_map_high() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0xffffffff80000000, 0x200000, 0x1000, CODE), Ok(()));
+
+            assert_eq!(mapper.translate(0xffffffff80000010), Some(0x200010));
+            assert_eq!(mapper.translate(0x80000000), None);
+        });
+    }
+
+    #[test]
+    fn test_map_twice() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x2000, DATA), Ok(()));
+
+            assert_eq!(mapper.map(0x200000, 0x800000, 0x1000, DATA), Err(MapError::Mapped(0x200000)));
+            assert_eq!(mapper.map(0x1fe000, 0x800000, 0x3000, DATA), Err(MapError::Mapped(0x200000)));
+
+            // nothing was left behind by the failed map
+            assert_eq!(mapper.translate(0x1fe000), None);
+            assert_eq!(mapper.translate(0x200000), Some(0x400000));
+        });
+    }
+
+    #[test]
+    fn test_unaligned() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x200010, 0x400000, 0x1000, DATA), Err(MapError::Unaligned));
+            assert_eq!(mapper.map(0x200000, 0x400010, 0x1000, DATA), Err(MapError::Unaligned));
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x10, DATA), Err(MapError::Unaligned));
+            assert_eq!(mapper.unmap(0x200000, 0x10), Err(MapError::Unaligned));
+        });
+    }
+
+    #[test]
+    fn test_unmap_splits() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x200000, DATA), Ok(()));
+            assert_eq!(lookup(mapper, 0x200000).unwrap().1, Level::PDE);
+
+            assert_eq!(mapper.unmap(0x201000, 0x1000), Ok(()));
+
+            assert_eq!(mapper.translate(0x201000), None);
+            assert_eq!(mapper.translate(0x200000), Some(0x400000));
+            assert_eq!(mapper.translate(0x202000), Some(0x402000));
+            assert_eq!(mapper.translate(0x3ff000), Some(0x5ff000));
+            assert_eq!(lookup(mapper, 0x200000).unwrap().1, Level::PTE);
+
+            assert_eq!(mapper.unmap(0x200000, 0x2000), Err(MapError::Unmapped(0x201000)));
+            assert_eq!(mapper.translate(0x200000), Some(0x400000));
+        });
+    }
+
+    #[test]
+    fn test_map_over_empty_table() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x1000, DATA), Ok(()));
+            assert_eq!(mapper.unmap(0x200000, 0x1000), Ok(()));
+
+            // the page table for this range is still there, so 4K pages are used
+            assert_eq!(mapper.map(0x200000, 0x600000, 0x200000, DATA), Ok(()));
+            assert_eq!(lookup(mapper, 0x200000).unwrap().1, Level::PTE);
+            assert_eq!(mapper.translate(0x3ff000), Some(0x7ff000));
+        });
+    }
+
+    #[test]
+    fn test_protect() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x3000, DATA), Ok(()));
+            assert_eq!(mapper.protect(0x201000, 0x1000, CODE), Ok(()));
+
+            let (entry, _) = lookup(mapper, 0x201000).unwrap();
+            assert!(!entry.write() && entry.execute());
+
+            let (entry, _) = lookup(mapper, 0x202000).unwrap();
+            assert!(entry.write() && !entry.execute());
+
+            assert_eq!(mapper.translate(0x201000), Some(0x401000));
+            assert_eq!(mapper.protect(0x202000, 0x2000, CODE), Err(MapError::Unmapped(0x203000)));
+        });
+    }
+
+    #[test]
+    fn test_copy_on_write() {
+        with_mapper(|mapper| unsafe {
+            let shared = Flags {
+                copy_on_write: true,
+                ..DATA
+            };
+
+            assert_eq!(mapper.map(0x200000, 0x400000, 0x200000, shared), Ok(()));
+
+            let (entry, level) = lookup(mapper, 0x201000).unwrap();
+            assert_eq!(level, Level::PTE);
+            assert!(!entry.write() && entry.copy_on_write());
+            assert_eq!(mapper.shared_frame(0x201234), Some(0x401000));
+
+            assert_eq!(mapper.unshare(0x201234, 0x800000), Ok(()));
+
+            let (entry, _) = lookup(mapper, 0x201000).unwrap();
+            assert!(entry.write() && !entry.copy_on_write());
+            assert_eq!(mapper.translate(0x201234), Some(0x800234));
+            assert_eq!(mapper.shared_frame(0x202000), Some(0x402000));
+
+            assert_eq!(mapper.unshare(0x201000, 0x800000), Err(MapError::NotShared(0x201000)));
+            assert_eq!(mapper.unshare(0x400000, 0x800000), Err(MapError::Unmapped(0x400000)));
+        });
+    }
+
+    #[test]
+    fn test_cache() {
+        with_mapper(|mapper| unsafe {
+            let framebuffer = Flags {
+                cache: Cache::WriteCombining,
+                ..DATA
+            };
+
+            assert_eq!(mapper.map(0x200000, 0xfd000000, 0x200000, framebuffer), Ok(()));
+
+            let (entry, level) = lookup(mapper, 0x200000).unwrap();
+            assert_eq!(level, Level::PDE);
+            assert_eq!(entry.cache(level), Cache::WriteCombining);
+            assert_eq!(mapper.translate(0x200000), Some(0xfd000000));
+
+            // the PAT bit moves when the page is split
+            assert_eq!(mapper.protect(0x200000, 0x1000, Flags { cache: Cache::Uncached, ..DATA }), Ok(()));
+
+            let (entry, level) = lookup(mapper, 0x200000).unwrap();
+            assert_eq!((level, entry.cache(level)), (Level::PTE, Cache::Uncached));
+
+            let (entry, level) = lookup(mapper, 0x201000).unwrap();
+            assert_eq!((level, entry.cache(level)), (Level::PTE, Cache::WriteCombining));
+            assert_eq!(mapper.translate(0x201000), Some(0xfd001000));
+        });
+    }
+
+    #[test]
+    fn test_protection_key() {
+        with_mapper(|mapper| unsafe {
+            let tagged = Flags {
+                user: true,
+                protection_key: 5,
+                ..DATA
+            };
+
+            assert_eq!(mapper.map(0x40000000, 0x40000000, 0x40000000, tagged), Ok(()));
+
+            let (entry, level) = lookup(mapper, 0x40000000).unwrap();
+            assert_eq!((level, entry.protection_key(level)), (Level::PDPTE, 5));
+
+            assert_eq!(mapper.protect(0x40000000, 0x1000, Flags { protection_key: 9, ..tagged }), Ok(()));
+
+            let (entry, level) = lookup(mapper, 0x40000000).unwrap();
+            assert_eq!((level, entry.protection_key(level)), (Level::PTE, 9));
+
+            let (entry, level) = lookup(mapper, 0x40200000).unwrap();
+            assert_eq!((level, entry.protection_key(level)), (Level::PDE, 5));
+            assert!(entry.user() && entry.write() && !entry.execute());
+        });
+    }
+
+    #[test]
+    fn test_clear() {
+        with_mapper(|mapper| unsafe {
+            assert_eq!(mapper.map(0x201000, 0x500000, 0x1000, DATA), Ok(()));
+            assert_eq!(mapper.map(0x400000, 0x800000, 0x200000, DATA), Ok(()));
+
+            let mut released = vec![];
+
+            // holes are skipped, and the 2M page is split around the cleared range
+            assert_eq!(mapper.clear(0x200000, 0x202000, |frame| released.push(frame)), Ok(()));
+
+            assert_eq!(released, vec![0x500000, 0x800000, 0x801000]);
+            assert_eq!(mapper.translate(0x201000), None);
+            assert_eq!(mapper.translate(0x401000), None);
+            assert_eq!(mapper.translate(0x402000), Some(0x802000));
+        });
+    }
+
+    #[test]
+    fn test_release_tables() {
+        let mut base = TestBase::new();
+
+        unsafe {
+            let root = base.new_table();
+
+            {
+                let mut mapper = Mapper::at(&mut base, root);
+
+                // a table on every level under the first root entry
+                assert_eq!(mapper.map(0x200000, 0x400000, 0x1000, DATA), Ok(()));
+                // a 2M page under the second
+                assert_eq!(mapper.map(0x8000000000, 0x400000, 0x200000, DATA), Ok(()));
+                // and the third stays
+                assert_eq!(mapper.map(0x10000000000, 0x400000, 0x1000, DATA), Ok(()));
+
+                mapper.release_tables(0..2);
+
+                assert_eq!(mapper.translate(0x200000), None);
+                assert_eq!(mapper.translate(0x8000000000), None);
+                assert_eq!(mapper.translate(0x10000000000), Some(0x400000));
+
+                // the cleared entries get new tables
+                assert_eq!(mapper.map(0x200000, 0x400000, 0x1000, DATA), Ok(()));
+            }
+
+            // tables below their parents, the root never
+            assert_eq!(base.released(), &[0x183000, 0x182000, 0x181000, 0x185000, 0x184000]);
+        }
+    }
+}
@@ -11,7 +11,12 @@ use std::fmt;
 
 use constants::*;
 
+use cache::Cache;
+
 /// Uniform linear address transformation
+///
+/// Virtual addresses are kept without their sign extension, wide enough for
+/// five-level tables. Four-level tables ignore the extra bits.
 #[derive(Clone)]
 pub struct Segment {
     physical_base: u64,
@@ -21,7 +26,11 @@ pub struct Segment {
     write: bool,
     user: bool,
     execute: bool,
-    global: bool
+    global: bool,
+    copy_on_write: bool,
+    cache: Cache,
+    protection_key: u8,
+    canonical: bool
 }
 
 #[repr(packed)]
@@ -30,7 +39,7 @@ struct RawSegment {
     physical_base: u64,
     virtual_base: u64,
     size: u64,
-    flags: u8
+    flags: u16
 }
 
 pub fn raw_segment_size() -> usize {
@@ -39,7 +48,7 @@ pub fn raw_segment_size() -> usize {
 
 impl Debug for Segment {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "Segment {{ physical_base: 0x{:x}, virtual_base: 0x{:x} size: 0x{:x}, allocate: {:?}, write: {:?}, user: {:?}, execute: {:?}, global: {:?} }}", self.physical_base, self.virtual_base, self.size, self.allocate, self.write, self.user, self.execute, self.global)
+        write!(fmt, "Segment {{ physical_base: 0x{:x}, virtual_base: 0x{:x} size: 0x{:x}, allocate: {:?}, write: {:?}, user: {:?}, execute: {:?}, global: {:?}, copy_on_write: {:?}, cache: {:?}, protection_key: {}, canonical: {:?} }}", self.physical_base, self.virtual_base, self.size, self.allocate, self.write, self.user, self.execute, self.global, self.copy_on_write, self.cache, self.protection_key, self.canonical)
     }
 }
 
@@ -52,7 +61,7 @@ impl Ord for Segment {
             <= align_back(other.virtual_base, PageSize::Page as u64) ||
             align_back(self.virtual_base, PageSize::Page as u64)
             >= align(other.virtual_base + other.size, PageSize::Page as u64) {
-                self.physical_base.cmp(&other.virtual_base)
+                self.virtual_base.cmp(&other.virtual_base)
             } else {
                 Ordering::Equal
             }
@@ -74,6 +83,11 @@ impl PartialEq for Segment {
 impl Eq for Segment {}
 
 
+// canonical for the widest tables we can build
+fn is_canonical(virtual_base: u64) -> bool {
+    canonicalize_to(virtual_base & ((1 << LA57_CANONICAL_BITS) - 1), LA57_CANONICAL_BITS) == virtual_base
+}
+
 impl Segment {
     pub fn new(physical_base: u64, virtual_base: u64, size: u64,
                write: bool, user: bool, execute: bool, global: bool) -> Segment {
@@ -82,26 +96,55 @@ impl Segment {
 
         Segment {
             physical_base: physical_base,
-            virtual_base: virtual_base & ((1 << CANONICAL_BITS) - 1),
+            virtual_base: virtual_base & ((1 << LA57_CANONICAL_BITS) - 1),
             allocate: true,
             size: size,
             write: write,
             user: user,
             execute: execute,
-            global: global
+            global: global,
+            copy_on_write: false,
+            cache: Cache::WriteBack,
+            protection_key: 0,
+            canonical: is_canonical(virtual_base)
+        }
+    }
+
+    /// A segment backed by zeroed frames on first touch, rather than up front
+    pub fn lazy(virtual_base: u64, size: u64,
+                write: bool, user: bool, execute: bool, global: bool) -> Segment {
+        debug_assert!(is_aligned(virtual_base, 0x1000), "Virtual base was not aligned");
+
+        Segment {
+            physical_base: 0,
+            virtual_base: virtual_base & ((1 << LA57_CANONICAL_BITS) - 1),
+            allocate: false,
+            size: size,
+            write: write,
+            user: user,
+            execute: execute,
+            global: global,
+            copy_on_write: false,
+            cache: Cache::WriteBack,
+            protection_key: 0,
+            canonical: is_canonical(virtual_base)
         }
     }
 
     pub fn dummy_range(virtual_address: u64, size: u64) -> Segment {
         Segment {
             physical_base: 0,
-            virtual_base: virtual_address & ((1 << CANONICAL_BITS) - 1),
+            virtual_base: virtual_address & ((1 << LA57_CANONICAL_BITS) - 1),
             allocate: false,
             size: size,
             write: false,
             user: false,
             execute: false,
-            global: false
+            global: false,
+            copy_on_write: false,
+            cache: Cache::WriteBack,
+            protection_key: 0,
+            canonical: is_canonical(virtual_address)
         }
     }
 
@@ -121,6 +164,11 @@ impl Segment {
         self.size
     }
 
+    /// Whether the segment is backed when the tables are built
+    pub fn allocate(&self) -> bool {
+        self.allocate
+    }
+
     pub fn write(&self) -> bool {
         self.write
     }
@@ -137,6 +185,48 @@ impl Segment {
         self.global
     }
 
+    /// Whether the frames are shared read-only until written to
+    ///
+    /// Only writable segments take this into account. A write gives the page
+    /// a private copy of its frame, or the frame itself once nobody else maps it.
+    pub fn copy_on_write(&self) -> bool {
+        self.copy_on_write && self.write
+    }
+
+    pub fn set_copy_on_write(&mut self, copy_on_write: bool) {
+        self.copy_on_write = copy_on_write;
+    }
+
+    /// Memory type of the mapping, write-back unless set otherwise
+    pub fn cache(&self) -> Cache {
+        self.cache
+    }
+
+    pub fn set_cache(&mut self, cache: Cache) {
+        self.cache = cache;
+    }
+
+    /// Key the pages are tagged with, which PKRU can revoke access to
+    ///
+    /// Keys only restrict user pages. Key 0 is the one everything else has.
+    pub fn protection_key(&self) -> u8 {
+        self.protection_key
+    }
+
+    pub fn set_protection_key(&mut self, key: u8) {
+        assert!(key < 16, "Protection key {} out of range", key);
+
+        self.protection_key = key;
+    }
+
+    /// Whether the virtual base was sign-extended when the segment was made
+    ///
+    /// The extension is dropped from `virtual_base`, so this is the only
+    /// trace of a base that wasn't canonical even with five-level tables.
+    pub fn canonical(&self) -> bool {
+        self.canonical
+    }
+
     pub fn from_raw(raw: &[u8]) -> Segment {
         assert!(raw.len() == mem::size_of::<RawSegment>());
 
@@ -148,12 +238,16 @@ impl Segment {
         Segment {
             physical_base: data.physical_base,
             virtual_base: data.virtual_base,
-            allocate: true,
+            allocate: (data.flags & 1 << 4) == 0,
             size: data.size,
             write: (data.flags & 1 << 0) == 1 << 0,
             user: (data.flags & 1 << 1) == 1 << 1,
             execute: (data.flags & 1 << 2) == 1 << 2,
-            global: (data.flags & 1 << 3) == 1 << 3
+            global: (data.flags & 1 << 3) == 1 << 3,
+            copy_on_write: (data.flags & 1 << 5) == 1 << 5,
+            cache: Cache::from_index((data.flags >> 8) as u8),
+            protection_key: (data.flags >> 12 & 0xf) as u8,
+            canonical: (data.flags & 1 << 6) == 0
         }
     }
 
@@ -177,6 +271,21 @@ impl Segment {
             flags |= 1 << 3;
         }
 
+        if !self.allocate {
+            flags |= 1 << 4;
+        }
+
+        if self.copy_on_write {
+            flags |= 1 << 5;
+        }
+
+        if !self.canonical {
+            flags |= 1 << 6;
+        }
+
+        flags |= (self.cache.get_index() as u16) << 8;
+        flags |= (self.protection_key as u16) << 12;
+
         let data = RawSegment {
             physical_base: self.physical_base,
             virtual_base: self.virtual_base,
@@ -208,6 +317,9 @@ impl Segment {
         other.write == self.write &&
             other.user == self.user &&
             other.execute == self.execute &&
-            other.global == self.global
+            other.global == self.global &&
+            other.copy_on_write == self.copy_on_write &&
+            other.cache == self.cache &&
+            other.protection_key == self.protection_key
     }
 }
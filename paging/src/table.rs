@@ -1,12 +1,22 @@
 use std::fmt::{Debug, Formatter};
 use std::ptr::Shared;
+use std::sync::atomic::{Ordering, AtomicBool};
 
 use std::fmt;
 
 use constants::*;
 
+use cache::Cache;
+
+// whether tables are rooted at a PML5, see set_five_level
+static FIVE_LEVEL: AtomicBool = AtomicBool::new(false);
+
+// whether 1G pages can be used, see set_huge_pages
+static HUGE_PAGES: AtomicBool = AtomicBool::new(true);
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum Level {
+    PML5E,
     PML4E,
     PDPTE,
     PDE,
@@ -35,6 +45,7 @@ pub struct Info {
     pub cache_disable: bool,
     pub attribute_table: bool,
     pub protection_key: u8,
+    pub copy_on_write: bool,
     pub level: Level,
     pub address: u64
 }
@@ -43,9 +54,88 @@ pub trait Base {
     fn to_physical(&self, address: u64) -> Option<u64>;
     fn to_virtual(&self, address: u64) -> Option<u64>;
     unsafe fn new_table(&mut self) -> Shared<Table>;
+    /// Take back a table nothing points at anymore
+    unsafe fn release_table(&mut self, table: Shared<Table>);
     fn clear(&mut self);
 }
 
+/// Root tables at a PML5 from now on, for CPUs with LA57 enabled
+///
+/// Builders, mappers and walkers made after this use five levels, unless
+/// they are given a root level explicitly.
+pub fn set_five_level(five_level: bool) {
+    FIVE_LEVEL.store(five_level, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn five_level() -> bool {
+    FIVE_LEVEL.load(Ordering::Relaxed)
+}
+
+/// Whether the CPU can map 1G pages, which it has to report in CPUID
+///
+/// Builders and mappers made after this fall back to 2M pages without them.
+pub fn set_huge_pages(huge_pages: bool) {
+    HUGE_PAGES.store(huge_pages, Ordering::Relaxed);
+}
+
+#[inline]
+pub fn huge_pages() -> bool {
+    HUGE_PAGES.load(Ordering::Relaxed)
+}
+
+impl Level {
+    /// The level of root tables
+    #[inline]
+    pub fn root() -> Level {
+        if five_level() {
+            Level::PML5E
+        } else {
+            Level::PML4E
+        }
+    }
+
+    /// Width of the virtual addresses a tree rooted at this level translates
+    #[inline]
+    pub fn canonical_bits(self) -> usize {
+        self.get_shift() as usize + 9
+    }
+
+    /// Bits of the address below this level's index
+    #[inline]
+    pub fn get_shift(self) -> u64 {
+        match self {
+            Level::PML5E => 48,
+            Level::PML4E => 39,
+            Level::PDPTE => 30,
+            Level::PDE => 21,
+            Level::PTE => 12
+        }
+    }
+
+    /// Size of the range one entry at this level covers
+    #[inline]
+    pub fn get_size(self) -> u64 {
+        1 << self.get_shift()
+    }
+
+    #[inline]
+    pub fn get_index(self, address: u64) -> usize {
+        (address >> self.get_shift() & 0x1ff) as usize
+    }
+
+    #[inline]
+    pub fn get_next(self) -> Option<Level> {
+        match self {
+            Level::PML5E => Some(Level::PML4E),
+            Level::PML4E => Some(Level::PDPTE),
+            Level::PDPTE => Some(Level::PDE),
+            Level::PDE => Some(Level::PTE),
+            Level::PTE => None
+        }
+    }
+}
+
 impl Clone for Table {
     fn clone(&self) -> Table {
         let mut new = Table::new();
@@ -85,6 +175,10 @@ impl From<Info> for Entry {
             entry |= 1 << 8;
         }
 
+        if info.copy_on_write {
+            entry |= 1 << 9;
+        }
+
         if info.level == Level::PTE {
             if info.attribute_table {
                 entry |= 1 << 7;
@@ -155,6 +249,10 @@ impl Debug for Entry {
             if self.dirty() {
                 try!(write!(fmt, ", dirty"));
             }
+
+            if self.copy_on_write() {
+                try!(write!(fmt, ", copy-on-write"));
+            }
         } else {
             try!(write!(fmt, "not present"));
         }
@@ -164,6 +262,31 @@ impl Debug for Entry {
 }
 
 impl Entry {
+    #[inline]
+    pub fn empty() -> Entry {
+        Entry {
+            entry: 0
+        }
+    }
+
+    /// Decode a present entry back into the info it was written from
+    pub fn info(&self, level: Level) -> Info {
+        Info {
+            page: self.is_page(level),
+            write: self.write(),
+            execute: self.execute(),
+            user: self.user(),
+            global: self.global(level),
+            write_through: self.write_through(),
+            cache_disable: self.cache_disable(),
+            attribute_table: self.attribute_table(level),
+            protection_key: self.protection_key(level),
+            copy_on_write: self.copy_on_write(),
+            level: level,
+            address: self.address(level)
+        }
+    }
+
     pub fn address(&self, level: Level) -> u64 {
         if self.is_page(level) && level != Level::PTE {
             canonicalize((self.entry & PAGE_ADDR_MASK & !(1 << 12)) as u64)
@@ -198,10 +321,10 @@ impl Entry {
 
     pub fn is_page(&self, level: Level) -> bool {
         match level {
-            Level::PML4E => false,
+            Level::PML5E | Level::PML4E => false,
             Level::PTE => true,
             _ => {
-                self.entry & 1 << 7 == 1
+                self.entry & 1 << 7 != 0
             }
         }
     }
@@ -215,13 +338,18 @@ impl Entry {
     }
 
     pub fn global(&self, level: Level) -> bool {
-        if level == Level::PML4E {
+        if level == Level::PML5E || level == Level::PML4E {
             false
         } else {
             self.entry & 1 << 8 != 0
         }
     }
 
+    /// Memory type, assuming the PAT boot programs
+    pub fn cache(&self, level: Level) -> Cache {
+        Cache::from_bits(self.write_through(), self.cache_disable(), self.attribute_table(level))
+    }
+
     pub fn attribute_table(&self, level: Level) -> bool {
         if level == Level::PTE {
             self.entry & 1 << 7 != 0
@@ -232,9 +360,14 @@ impl Entry {
         }
     }
 
+    /// Software bit marking a read-only page that becomes writable once copied
+    pub fn copy_on_write(&self) -> bool {
+        self.entry & 1 << 9 != 0
+    }
+
     pub fn protection_key(&self, level: Level) -> u8 {
         if self.is_page(level) {
-            (self.entry >> 59) as u8
+            (self.entry >> 59 & 0xf) as u8
         } else {
             0
         }
@@ -0,0 +1,203 @@
+use std::fmt::{Display, Formatter};
+use std::fmt;
+
+use collections::Vec;
+
+use constants::*;
+
+use segment::Segment;
+use layout::Layout;
+use table::Level;
+
+/// Something about a layout that tables shouldn't be built from
+///
+/// Segments are named by their virtual base, sign-extended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Violation {
+    /// A segment is both writable and executable
+    WriteExecute(u64),
+    /// Two segments map the same frames with different permissions
+    Alias(u64, u64),
+    /// A user segment reaches into the kernel's half of the address space
+    UserKernel(u64),
+    /// A segment is not in either canonical half of the address space
+    NonCanonical(u64)
+}
+
+impl Display for Violation {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        match *self {
+            Violation::WriteExecute(base) =>
+                write!(fmt, "segment at 0x{:x} is writable and executable", base),
+            Violation::Alias(base, other) =>
+                write!(fmt, "segments at 0x{:x} and 0x{:x} map the same frames with different permissions",
+                       base, other),
+            Violation::UserKernel(base) =>
+                write!(fmt, "user segment at 0x{:x} reaches into the kernel half", base),
+            Violation::NonCanonical(base) =>
+                write!(fmt, "segment at 0x{:x} is not canonical", base)
+        }
+    }
+}
+
+fn name(segment: &Segment) -> u64 {
+    canonicalize_to(segment.virtual_base(), LA57_CANONICAL_BITS)
+}
+
+// whether two mappings of the same frames disagree on how they may be used
+fn conflict(segment: &Segment, other: &Segment) -> bool {
+    segment.write() != other.write() ||
+        segment.execute() != other.execute() ||
+        segment.user() != other.user() ||
+        segment.copy_on_write() != other.copy_on_write() ||
+        segment.cache() != other.cache()
+}
+
+impl Layout {
+    /// Check the layout against the tables the running CPU would use
+    pub fn validate(&self) -> Result<(), Vec<Violation>> {
+        self.validate_at_level(Level::root())
+    }
+
+    /// Check the layout against tables rooted at level
+    ///
+    /// Every violation is reported, not just the first.
+    pub fn validate_at_level(&self, level: Level) -> Result<(), Vec<Violation>> {
+        // segments keep virtual addresses 57 bits wide
+        let half = 1 << (level.canonical_bits() - 1);
+        let top = 1 << LA57_CANONICAL_BITS;
+
+        let mut violations = vec![];
+
+        for segment in self.segments() {
+            let base = segment.virtual_base();
+            let end = base + segment.size();
+
+            if segment.write() && segment.execute() {
+                violations.push(Violation::WriteExecute(name(segment)));
+            }
+
+            let lower = end <= half;
+            let upper = base >= top - half && end <= top;
+
+            if !segment.canonical() || !(lower || upper) {
+                violations.push(Violation::NonCanonical(name(segment)));
+            } else if segment.user() && upper {
+                violations.push(Violation::UserKernel(name(segment)));
+            }
+        }
+
+        // lazy segments have no frames yet
+        let mut mapped: Vec<&Segment> = self.segments().filter(|segment| segment.allocate()).collect();
+        mapped.sort_by_key(|segment| segment.physical_base());
+
+        for (idx, segment) in mapped.iter().enumerate() {
+            let end = align(segment.physical_base() + segment.size(), 0x1000);
+
+            for other in mapped[idx + 1..].iter() {
+                if other.physical_base() >= end {
+                    break;
+                }
+
+                if conflict(segment, other) {
+                    violations.push(Violation::Alias(name(segment), name(other)));
+                }
+            }
+        }
+
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use segment::Segment;
+    use layout::Layout;
+    use table::Level;
+    use cache::Cache;
+    use super::Violation;
+
+    fn layout(segments: &[Segment]) -> Layout {
+        let mut layout = Layout::new();
+
+        for segment in segments {
+            assert!(layout.insert(segment.clone()));
+        }
+
+        layout
+    }
+
+    #[test]
+    fn test_valid() {
+        // read-only aliases of the same frames are fine
+        let layout = layout(&[Segment::new(0x200000, 0x200000, 0x2000, false, false, true, false),
+                              Segment::new(0x200000, 0xffffffff80000000, 0x2000, false, false, true, true),
+                              Segment::new(0x400000, 0xffffffff80200000, 0x1000, true, false, false, true),
+                              Segment::lazy(0x400000, 0x4000, true, true, false, false),
+                              Segment::new(0x500000, 0x7ffffffff000, 0x1000, true, true, false, false)]);
+
+        assert_eq!(layout.validate_at_level(Level::PML4E), Ok(()));
+        assert_eq!(layout.validate_at_level(Level::PML5E), Ok(()));
+    }
+
+    #[test]
+    fn test_write_execute() {
+        let layout = layout(&[Segment::new(0x200000, 0xffffffff80000000, 0x1000, true, false, true, true)]);
+
+        assert_eq!(layout.validate_at_level(Level::PML4E),
+                   Err(vec![Violation::WriteExecute(0xffffffff80000000)]));
+    }
+
+    #[test]
+    fn test_alias() {
+        let mut uncached = Segment::new(0x201000, 0xffffffff80201000, 0x1000, true, false, false, true);
+        uncached.set_cache(Cache::Uncached);
+
+        let layout = layout(&[Segment::new(0x200000, 0x200000, 0x3000, true, false, false, false),
+                              // same permissions as the identity map
+                              Segment::new(0x200000, 0xffffffff80000000, 0x1000, true, false, false, true),
+                              uncached,
+                              // the last page of the identity map, read-only
+                              Segment::new(0x202000, 0xffffffff80202000, 0x1000, false, false, false, true),
+                              // past the identity map
+                              Segment::new(0x203000, 0xffffffff80203000, 0x1000, false, false, true, true)]);
+
+        assert_eq!(layout.validate_at_level(Level::PML4E),
+                   Err(vec![Violation::Alias(0x200000, 0xffffffff80201000),
+                            Violation::Alias(0x200000, 0xffffffff80202000)]));
+    }
+
+    #[test]
+    fn test_user_kernel() {
+        let layout = layout(&[Segment::new(0x200000, 0xffffffff80000000, 0x1000, false, true, false, false),
+                              Segment::lazy(0xffff800000000000, 0x1000, true, true, false, false)]);
+
+        assert_eq!(layout.validate_at_level(Level::PML4E),
+                   Err(vec![Violation::UserKernel(0xffff800000000000),
+                            Violation::UserKernel(0xffffffff80000000)]));
+    }
+
+    #[test]
+    fn test_non_canonical() {
+        // runs off the end of the lower half
+        let lower = layout(&[Segment::new(0x200000, 0x7ffffffff000, 0x2000, true, false, false, false)]);
+
+        assert_eq!(lower.validate_at_level(Level::PML4E), Err(vec![Violation::NonCanonical(0x7ffffffff000)]));
+        assert_eq!(lower.validate_at_level(Level::PML5E), Ok(()));
+
+        // only canonical with five-level tables
+        let wide = layout(&[Segment::new(0x200000, 0xff00000000000000, 0x1000, true, false, false, true)]);
+
+        assert_eq!(wide.validate_at_level(Level::PML4E), Err(vec![Violation::NonCanonical(0xff00000000000000)]));
+        assert_eq!(wide.validate_at_level(Level::PML5E), Ok(()));
+
+        // never canonical, though it looks like the above once truncated
+        let truncated = layout(&[Segment::new(0x200000, 0x0100000000000000, 0x1000, true, false, false, false)]);
+
+        assert_eq!(truncated.validate_at_level(Level::PML5E), Err(vec![Violation::NonCanonical(0xff00000000000000)]));
+    }
+}
@@ -0,0 +1,421 @@
+use super::PageSize;
+
+use std::fmt::{Display, Formatter};
+use std::ptr::Shared;
+
+use std::fmt;
+
+use constants::*;
+
+use table::{Entry, Table, Base, Level};
+use cache::Cache;
+
+/// A run of pages that are contiguous in both address spaces and share flags
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mapping {
+    pub virtual_base: u64,
+    pub physical_base: u64,
+    pub size: u64,
+    pub page: PageSize,
+    pub write: bool,
+    pub user: bool,
+    pub execute: bool,
+    pub global: bool,
+    pub cache: Cache,
+    pub protection_key: u8
+}
+
+/// Reads a page table tree without changing it
+pub struct Walker<'a> {
+    base: &'a Base,
+    root: Shared<Table>,
+    level: Level
+}
+
+impl Display for Mapping {
+    fn fmt(&self, fmt: &mut Formatter) -> fmt::Result {
+        let page = match self.page {
+            PageSize::Huge => "1G",
+            PageSize::Big => "2M",
+            PageSize::Page => "4K"
+        };
+
+        try!(write!(fmt, "0x{:016x}-0x{:016x} -> 0x{:x} {} r{}{} {}",
+                    self.virtual_base, self.virtual_base.wrapping_add(self.size),
+                    self.physical_base, page,
+                    if self.write { "w" } else { "-" },
+                    if self.execute { "x" } else { "-" },
+                    if self.user { "user" } else { "kernel" }));
+
+        if self.global {
+            try!(write!(fmt, " global"));
+        }
+
+        match self.cache {
+            Cache::WriteBack => {},
+            Cache::WriteThrough => try!(write!(fmt, " write-through")),
+            Cache::Uncached => try!(write!(fmt, " uncached")),
+            Cache::WriteCombining => try!(write!(fmt, " write-combining")),
+            Cache::WriteProtect => try!(write!(fmt, " write-protect"))
+        }
+
+        if self.protection_key != 0 {
+            try!(write!(fmt, " key {}", self.protection_key));
+        }
+
+        Ok(())
+    }
+}
+
+impl Mapping {
+    fn new(virt: u64, entry: Entry, level: Level) -> Mapping {
+        Mapping {
+            virtual_base: virt,
+            physical_base: entry.address(level),
+            size: level.get_size(),
+            page: match level {
+                Level::PDPTE => PageSize::Huge,
+                Level::PDE => PageSize::Big,
+                _ => PageSize::Page
+            },
+            write: entry.write(),
+            user: entry.user(),
+            execute: entry.execute(),
+            global: entry.global(level),
+            cache: entry.cache(level),
+            protection_key: entry.protection_key(level)
+        }
+    }
+
+    // grow this mapping by other if it picks up right where this one ends
+    fn extend(&mut self, other: &Mapping) -> bool {
+        if self.virtual_base.wrapping_add(self.size) == other.virtual_base
+            && self.physical_base + self.size == other.physical_base
+            && self.page == other.page && self.write == other.write && self.user == other.user
+            && self.execute == other.execute && self.global == other.global
+            && self.cache == other.cache && self.protection_key == other.protection_key {
+            self.size += other.size;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// The root table CR3 points at
+pub unsafe fn active_root(base: &Base) -> Shared<Table> {
+    try_active_root(base).expect("Root page table had no virtual mapping")
+}
+
+/// The root table CR3 points at, if base can reach it
+pub unsafe fn try_active_root(base: &Base) -> Option<Shared<Table>> {
+    let cr3: usize;
+
+    asm!("mov $0, cr3" : "=r"(cr3) ::: "intel");
+
+    base.to_virtual(cr3 as u64 & PAGE_ADDR_MASK).map(|root| Shared::new(root as *mut Table))
+}
+
+impl<'a> Walker<'a> {
+    pub unsafe fn at(base: &Base, root: Shared<Table>) -> Walker {
+        Walker::at_level(base, root, Level::root())
+    }
+
+    /// Read tables rooted on the given level, regardless of the paging mode
+    pub unsafe fn at_level(base: &Base, root: Shared<Table>, level: Level) -> Walker {
+        Walker {
+            base: base,
+            root: root,
+            level: level
+        }
+    }
+
+    /// Read the tables CR3 points at, if base can reach them
+    pub unsafe fn active(base: &Base) -> Option<Walker> {
+        try_active_root(base).map(|root| Walker::at(base, root))
+    }
+
+    /// Call f once for every contiguous mapping, in virtual address order
+    ///
+    /// Tables base can't reach are skipped, along with everything under them.
+    pub unsafe fn mappings<F: FnMut(&Mapping)>(&self, mut f: F) {
+        let mut current = None;
+
+        self.visit(self.root, self.level, 0, &mut current, &mut f);
+
+        if let Some(mapping) = current {
+            f(&mapping);
+        }
+    }
+
+    /// Call f with every entry the CPU reads to translate virt
+    ///
+    /// Stops early with the physical address of a table base can't reach.
+    pub unsafe fn path<F: FnMut(Level, usize, Entry)>(&self, virt: u64, mut f: F) -> Result<(), u64> {
+        let mut table = self.root;
+        let mut level = self.level;
+
+        loop {
+            let idx = level.get_index(virt);
+            let entry = table.as_mut().unwrap().read(idx);
+
+            f(level, idx, entry);
+
+            if !entry.present() || entry.is_page(level) {
+                return Ok(());
+            }
+
+            table = match self.table(entry, level) {
+                Some(table) => table,
+                None => return Err(entry.address(level))
+            };
+
+            level = level.get_next().expect("Page table entry was not a page");
+        }
+    }
+
+    // walks run from fault handlers, so a bad entry must not panic
+    #[inline]
+    unsafe fn table(&self, entry: Entry, level: Level) -> Option<Shared<Table>> {
+        self.base.to_virtual(entry.address(level)).map(|table| Shared::new(table as *mut Table))
+    }
+
+    unsafe fn visit<F: FnMut(&Mapping)>(&self, table: Shared<Table>, level: Level, prefix: u64,
+                                        current: &mut Option<Mapping>, f: &mut F) {
+        for idx in 0..0x200 {
+            let entry = table.as_mut().unwrap().read(idx);
+
+            if !entry.present() {
+                continue;
+            }
+
+            let virt = canonicalize_to(prefix | (idx as u64) << level.get_shift(), self.level.canonical_bits());
+
+            if !entry.is_page(level) {
+                let next = level.get_next().expect("Page table entry was not a page");
+
+                if let Some(table) = self.table(entry, level) {
+                    self.visit(table, next, virt, current, f);
+                }
+
+                continue;
+            }
+
+            let mapping = Mapping::new(virt, entry, level);
+
+            let extended = match current.as_mut() {
+                Some(current) => current.extend(&mapping),
+                None => false
+            };
+
+            if !extended {
+                if let Some(ref done) = *current {
+                    f(done);
+                }
+
+                *current = Some(mapping);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ptr::Shared;
+
+    use table::{Table, Base, Level};
+    use harness::TestBase;
+    use mapper::{Mapper, Flags};
+    use cache::Cache;
+    use super::{Walker, Mapping};
+    use super::super::PageSize;
+
+    const DATA: Flags = Flags {
+        write: true,
+        user: false,
+        execute: false,
+        global: false,
+        copy_on_write: false,
+        cache: Cache::WriteBack,
+        protection_key: 0
+    };
+
+    const CODE: Flags = Flags {
+        write: false,
+        user: false,
+        execute: true,
+        global: true,
+        copy_on_write: false,
+        cache: Cache::WriteBack,
+        protection_key: 0
+    };
+
+    fn mappings(base: &TestBase, root: Shared<Table>) -> Vec<Mapping> {
+        let mut mappings = vec![];
+
+        unsafe {
+            Walker::at(base, root).mappings(|mapping| mappings.push(*mapping));
+        }
+
+        mappings
+    }
+
+    #[test]
+    fn test_mappings_merge() {
+        let mut base = TestBase::new();
+
+        let root = unsafe {
+            let root = base.new_table();
+            let mut mapper = Mapper::at(&mut base, root);
+
+            // contiguous on both sides, mapped in two steps
+            mapper.map(0x200000, 0x400000, 0x2000, DATA).unwrap();
+            mapper.map(0x202000, 0x402000, 0x1000, DATA).unwrap();
+
+            // contiguous virtually but not physically
+            mapper.map(0x203000, 0x900000, 0x1000, DATA).unwrap();
+
+            // different flags
+            mapper.map(0x204000, 0x901000, 0x1000, CODE).unwrap();
+
+            root
+        };
+
+        let mappings = mappings(&base, root);
+
+        assert_eq!(mappings.len(), 3);
+        assert_eq!((mappings[0].virtual_base, mappings[0].physical_base, mappings[0].size),
+                   (0x200000, 0x400000, 0x3000));
+        assert_eq!((mappings[1].virtual_base, mappings[1].physical_base, mappings[1].size),
+                   (0x203000, 0x900000, 0x1000));
+        assert!(mappings[2].execute && mappings[2].global && !mappings[2].write);
+    }
+
+    #[test]
+    fn test_mappings_pages() {
+        let mut base = TestBase::new();
+
+        let root = unsafe {
+            let root = base.new_table();
+            let mut mapper = Mapper::at(&mut base, root);
+
+            mapper.map(0x40000000, 0x40000000, 0x40201000, DATA).unwrap();
+            mapper.map(0xffffffff80000000, 0x200000, 0x200000, CODE).unwrap();
+
+            root
+        };
+
+        let mappings = mappings(&base, root);
+
+        assert_eq!(mappings.len(), 4);
+        assert_eq!((mappings[0].page, mappings[0].size), (PageSize::Huge, 0x40000000));
+        assert_eq!((mappings[1].page, mappings[1].size), (PageSize::Big, 0x200000));
+        assert_eq!((mappings[2].page, mappings[2].size), (PageSize::Page, 0x1000));
+        assert_eq!((mappings[3].virtual_base, mappings[3].page), (0xffffffff80000000, PageSize::Big));
+
+        assert_eq!(format!("{}", mappings[3]),
+                   "0xffffffff80000000-0xffffffff80200000 -> 0x200000 2M r-x kernel global");
+    }
+
+    #[test]
+    fn test_unreachable_table() {
+        let mut base = TestBase::new();
+
+        let root = unsafe {
+            let root = base.new_table();
+            let mut mapper = Mapper::at(&mut base, root);
+
+            mapper.map(0x201000, 0x400000, 0x1000, DATA).unwrap();
+            mapper.map(0x40000000, 0x800000, 0x1000, DATA).unwrap();
+
+            root
+        };
+
+        // point the table for the first gigabyte somewhere the base can't reach
+        unsafe {
+            let entry = (**root).read(0);
+            let table = base.to_virtual(entry.address(Level::PML4E)).unwrap() as *mut Table;
+            let mut info = (*table).read(0).info(Level::PDPTE);
+
+            info.address = 0x1000;
+            (*table).write(info.into(), 0);
+        }
+
+        let mappings = mappings(&base, root);
+
+        assert_eq!(mappings.len(), 1);
+        assert_eq!(mappings[0].virtual_base, 0x40000000);
+
+        let mut path = vec![];
+        let result = unsafe {
+            Walker::at(&base, root).path(0x201000, |level, idx, _| path.push((level, idx)))
+        };
+
+        assert_eq!(result, Err(0x1000));
+        assert_eq!(path, vec![(Level::PML4E, 0), (Level::PDPTE, 0)]);
+    }
+
+    #[test]
+    fn test_path() {
+        let mut base = TestBase::new();
+
+        let root = unsafe {
+            let root = base.new_table();
+            Mapper::at(&mut base, root).map(0x201000, 0x400000, 0x1000, DATA).unwrap();
+
+            root
+        };
+
+        let mut path = vec![];
+
+        unsafe {
+            Walker::at(&base, root).path(0x201000, |level, idx, entry| path.push((level, idx, entry.present()))).unwrap();
+        }
+
+        assert_eq!(path, vec![(Level::PML4E, 0, true), (Level::PDPTE, 0, true),
+                              (Level::PDE, 1, true), (Level::PTE, 1, true)]);
+
+        path.clear();
+
+        unsafe {
+            Walker::at(&base, root).path(0x40000000, |level, idx, entry| path.push((level, idx, entry.present()))).unwrap();
+        }
+
+        assert_eq!(path, vec![(Level::PML4E, 0, true), (Level::PDPTE, 1, false)]);
+    }
+
+    #[test]
+    fn test_five_level() {
+        let mut base = TestBase::new();
+
+        let root = unsafe {
+            let root = base.new_table();
+            let mut mapper = Mapper::at_level(&mut base, root, Level::PML5E);
+
+            mapper.map(0x1000000000000, 0x400000, 0x1000, DATA).unwrap();
+            mapper.map(0xff00000000000000, 0x200000, 0x200000, CODE).unwrap();
+
+            root
+        };
+
+        let mut path = vec![];
+
+        unsafe {
+            Walker::at_level(&base, root, Level::PML5E)
+                .path(0x1000000000000, |level, idx, entry| path.push((level, idx, entry.present()))).unwrap();
+        }
+
+        assert_eq!(path, vec![(Level::PML5E, 1, true), (Level::PML4E, 0, true), (Level::PDPTE, 0, true),
+                              (Level::PDE, 0, true), (Level::PTE, 0, true)]);
+
+        let mut mappings = vec![];
+
+        unsafe {
+            Walker::at_level(&base, root, Level::PML5E).mappings(|mapping| mappings.push(*mapping));
+        }
+
+        assert_eq!(mappings.len(), 2);
+        assert_eq!(mappings[0].virtual_base, 0x1000000000000);
+        assert_eq!((mappings[1].virtual_base, mappings[1].page), (0xff00000000000000, PageSize::Big));
+    }
+}